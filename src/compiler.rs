@@ -1,15 +1,16 @@
 use crate::chunk::Chunk;
 use crate::codegen::Codegen;
 use crate::dprintln;
-use crate::error::{LoxError, Result};
+use crate::error::{LoxError, ProgramLimit, Result};
 use crate::gc::Heap;
-use crate::object::{LoxObj, ObjClosure, ObjString};
+use crate::object::{LoxObj, ObjFunction};
 use crate::opcodes::OpCode;
 use crate::scanner::Scanner;
 use crate::token::{Token, TokenType};
 use crate::value::{Value, ValueHandle};
 use crate::vm::INIT_STRING;
-use std::iter::Peekable;
+use std::collections::HashSet;
+use std::fmt;
 use std::mem;
 use std::str::Chars;
 
@@ -17,6 +18,8 @@ struct Local {
     name: String,
     depth: isize,
     is_captured: bool,
+    // Declared with `const`, so it can't be assigned
+    is_const: bool,
 }
 
 #[derive(PartialEq, Clone, Copy, Debug)]
@@ -33,6 +36,43 @@ struct Upvalue {
     index: u8,
 }
 
+// How deeply statements and expressions may nest, since the parser recurses
+// on the Rust stack for each level
+const MAX_NESTING: usize = 200;
+
+/// How much debug information the compiler keeps in the bytecode.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum DebugInfo {
+    /// Source lines for every instruction and function names.
+    Full,
+    /// Source lines, but functions are left unnamed.
+    LinesOnly,
+    /// Neither, so runtime errors can't tell which line they came from.
+    None,
+}
+
+/// Caps on how much bytecode a program may compile to, so hostile or broken
+/// input fails with `LoxError::ProgramTooLarge` instead of exhausting memory.
+#[derive(Clone, Copy, Debug)]
+pub struct ProgramLimits {
+    /// Bytes of code in any single function (the script included).
+    pub max_code_bytes: usize,
+    /// Constants across every function.
+    pub max_constants: usize,
+    /// Functions and methods declared, not counting the script.
+    pub max_functions: usize,
+}
+
+impl Default for ProgramLimits {
+    fn default() -> Self {
+        Self {
+            max_code_bytes: 1 << 24,
+            max_constants: 1 << 20,
+            max_functions: 1 << 16,
+        }
+    }
+}
+
 enum UpvaluesKind {
     Current,
     Past(usize),
@@ -42,9 +82,82 @@ struct ClassCompiler {
     has_superclass: bool,
 }
 
+/// What a loop being compiled does with globals, to suggest hoisting the ones
+/// it only reads.
+#[derive(Default)]
+struct LoopGlobals {
+    // Globals read, each with the line and column of its first read
+    reads: Vec<(String, usize, usize)>,
+    assigned: HashSet<String>,
+    // A call could assign any global, so this rules out every suggestion
+    has_call: bool,
+}
+
+/// A loop being compiled, for `break` to jump out of and `continue` to
+/// restart.
+struct LoopContext {
+    // Scope depth around the loop's body. Locals deeper than it are discarded
+    // when jumping out.
+    scope_depth: isize,
+    // Where `continue` jumps back to: the increment clause of a `for` loop
+    // that has one, otherwise the condition
+    start: usize,
+    // Jumps to patch to just past the loop
+    breaks: Vec<usize>,
+    // Slot of a `for` loop's variable whose per-iteration copy is in the slot
+    // above it, and must be written back before the increment runs
+    loop_variable: Option<u8>,
+}
+
+/// Advice about the compiled program. Warnings never stop compilation.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Warning {
+    /// A global read inside a loop that neither assigns it nor calls anything,
+    /// so it could be copied into a local before the loop.
+    LoopInvariantGlobal {
+        name: String,
+        line: usize,
+        column: usize,
+    },
+}
+
+impl Warning {
+    /// The line and column the warning is about.
+    pub fn position(&self) -> (usize, usize) {
+        match self {
+            Warning::LoopInvariantGlobal { line, column, .. } => (*line, *column),
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::LoopInvariantGlobal { name, .. } => write!(
+                f,
+                "global `{}` doesn't change in this loop; copy it into a local before the loop",
+                name
+            ),
+        }
+    }
+}
+
+/// A position in the token stream to backtrack to with `Compiler::restore`.
+#[derive(Clone, Copy)]
+struct Checkpoint {
+    next_token: usize,
+    line: usize,
+}
+
 pub struct Compiler<'a> {
-    scanner: Peekable<Scanner<'a>>,
-    pub function: ObjClosure,
+    scanner: Scanner<'a>,
+    // Every token scanned so far, so the parser can look ahead and backtrack
+    tokens: Vec<Token>,
+    // Index in `tokens` of the next token to consume
+    next_token: usize,
+    // The scanner's error, returned once the tokens before it are consumed
+    scan_error: Option<LoxError>,
+    pub function: ObjFunction,
     fun_type: FunctionType,
     locals: Vec<Local>,
     scope_depth: isize,
@@ -54,59 +167,150 @@ pub struct Compiler<'a> {
     classes: Vec<ClassCompiler>,
     locals_stack: Vec<Vec<Local>>,
     upvalues_stack: Vec<Vec<Upvalue>>,
+    fresh_loop_bindings: bool,
+    debug_info: DebugInfo,
+    top_level_return: bool,
+    limits: ProgramLimits,
+    // Enclosing loops of the function being compiled, innermost last
+    loops: Vec<LoopGlobals>,
+    // Loops of the function being compiled that `break` and `continue` apply
+    // to, innermost last
+    loop_contexts: Vec<LoopContext>,
+    warnings: Vec<Warning>,
+    // Names of the globals declared with `const`
+    const_globals: HashSet<String>,
+    // Depth of the statements and expressions being parsed
+    nesting: usize,
+    // Totals across the whole program, checked against `limits`
+    constant_count: usize,
+    function_count: usize,
 }
 
 impl<'a> Compiler<'a> {
     pub fn new(source: Chars<'a>, heap: Heap<LoxObj>) -> Self {
         // NOTE: I don't think we need to worry about GC here. Still, be mindful.
-        let function = ObjClosure {
+        let function = ObjFunction {
             arity: 0,
             chunk: Chunk::default(),
             name: None,
-            upvalues: vec![],
             upvalue_count: 0,
+            is_generator: false,
             is_marked: false,
         };
 
-        let mut locals = Vec::with_capacity(std::u8::MAX as usize + 1);
+        let mut locals = Vec::with_capacity(u8::MAX as usize + 1);
 
         locals.push(Local {
             name: String::from(""),
             depth: 0,
             is_captured: false,
+            is_const: false,
         });
 
         Self {
-            scanner: Scanner::new(source).peekable(),
+            scanner: Scanner::new(source),
+            tokens: vec![],
+            next_token: 0,
+            scan_error: None,
             function,
             fun_type: FunctionType::Script,
             locals,
             scope_depth: 0,
-            line: 0,
+            line: 1,
             heap,
             upvalues: Vec::with_capacity(u8::MAX as usize),
             classes: vec![],
             locals_stack: vec![],
             upvalues_stack: vec![],
+            fresh_loop_bindings: false,
+            debug_info: DebugInfo::Full,
+            top_level_return: false,
+            limits: ProgramLimits::default(),
+            loops: vec![],
+            loop_contexts: vec![],
+            warnings: vec![],
+            const_globals: HashSet::new(),
+            nesting: 0,
+            constant_count: 0,
+            function_count: 0,
         }
     }
 
+    /// When enabled, a variable declared in a `for` initializer is copied into a
+    /// fresh local for every iteration, so closures created in the body capture
+    /// that iteration's value instead of sharing one variable.
+    pub fn set_fresh_loop_bindings(&mut self, enabled: bool) {
+        self.fresh_loop_bindings = enabled;
+    }
+
+    /// Sets how much debug information to emit. Defaults to `DebugInfo::Full`.
+    pub fn set_debug_info(&mut self, debug_info: DebugInfo) {
+        self.debug_info = debug_info;
+    }
+
+    /// When enabled, `return` is allowed outside functions and ends the script,
+    /// handing a value back to the host through `Vm::result` or
+    /// `Vm::eval_isolated`.
+    pub fn set_top_level_return(&mut self, allowed: bool) {
+        self.top_level_return = allowed;
+    }
+
+    /// Replaces the default `ProgramLimits`.
+    pub fn set_limits(&mut self, limits: ProgramLimits) {
+        self.limits = limits;
+    }
+
+    /// Names of the globals already defined with `const`, e.g. by earlier
+    /// evals on the same VM, which the program can't assign or redeclare.
+    pub fn set_const_globals(&mut self, names: HashSet<String>) {
+        self.const_globals = names;
+    }
+
+    /// Warnings about the source compiled so far.
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
     pub fn compile(&mut self) -> Result<()> {
         while self.peek().is_some() {
             self.declaration()?;
         }
 
-        Ok(())
+        // `peek` sees the end of the source at a scan error too
+        match self.scan_error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
     }
 
     pub fn declaration(&mut self) -> Result<()> {
         dprintln!("declaration");
-        match self.peek() {
-            Some(TokenType::Var) => self.var_declaration(),
-            Some(TokenType::Fun) => self.fun_declaration(),
-            Some(TokenType::Class) => self.class_declaration(),
-            _ => self.statement(),
+        self.check_code_size()?;
+
+        self.nested(|this| match this.peek() {
+            Some(TokenType::Var) => this.var_declaration(),
+            Some(TokenType::Const) => this.const_declaration(),
+            Some(TokenType::Fun) => this.fun_declaration(),
+            Some(TokenType::Class) => this.class_declaration(),
+            _ => this.statement(),
+        })
+    }
+
+    /// Runs `parse` one level deeper, failing instead of overflowing the stack
+    /// on absurdly nested source.
+    fn nested<F>(&mut self, parse: F) -> Result<()>
+    where
+        F: FnOnce(&mut Self) -> Result<()>,
+    {
+        if self.nesting == MAX_NESTING {
+            return Err(LoxError::CompileError("too deeply nested"));
         }
+
+        self.nesting += 1;
+        let result = parse(self);
+        self.nesting -= 1;
+
+        result
     }
 
     fn class_declaration(&mut self) -> Result<()> {
@@ -121,7 +325,7 @@ impl<'a> Compiler<'a> {
 
                 let value = Value::Obj(handle);
 
-                let named_constant = self.chunk().add_constant(value)?;
+                let named_constant = self.add_constant(value)?;
 
                 self.emit_bytes(OpCode::Class as u8, named_constant);
 
@@ -142,7 +346,7 @@ impl<'a> Compiler<'a> {
 
                     self.variable(false)?;
 
-                    if &id == &name {
+                    if id == name {
                         return Err(LoxError::CompileError("class cannot inherit from itself"));
                     }
 
@@ -187,30 +391,82 @@ impl<'a> Compiler<'a> {
 
     fn method(&mut self) -> Result<()> {
         match self.advance()? {
-            Some(TokenType::Ident(id)) => {
-                let fun_type = if &id == INIT_STRING {
-                    FunctionType::Initializer
-                } else {
-                    FunctionType::Method
-                };
-
-                // TODO: refactor this into its own function
-                let handle = self.make_string(id.clone());
-                let value = Value::Obj(handle);
-                let named_constant = self.chunk().add_constant(value)?;
-
-                self.function(id, fun_type)?;
-
-                self.emit_bytes(OpCode::Method as u8, named_constant);
+            Some(TokenType::Ident(id)) if self.peek() == Some(&TokenType::Equal) => self.field(id),
+            // `private` is only special in front of a method name
+            Some(TokenType::Ident(id))
+                if id == "private" && matches!(self.peek(), Some(TokenType::Ident(_))) =>
+            {
+                let id = self.expect_ident()?;
+
+                if id == INIT_STRING {
+                    return Err(LoxError::CompileError("init cannot be private"));
+                }
 
-                Ok(())
+                self.method_body(id, OpCode::PrivateMethod)
             }
+            Some(TokenType::Ident(id)) => self.method_body(id, OpCode::Method),
             token => Err(LoxError::UnexpectedToken(token)),
         }
     }
 
+    /// Compiles the parameters and body of method `id`, then adds it to the
+    /// class with `opcode` (`Method` or `PrivateMethod`).
+    fn method_body(&mut self, id: String, opcode: OpCode) -> Result<()> {
+        let fun_type = if id == INIT_STRING {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+
+        // TODO: refactor this into its own function
+        let handle = self.make_string(id.clone());
+        let value = Value::Obj(handle);
+        let named_constant = self.add_constant(value)?;
+
+        self.function(id, fun_type)?;
+
+        self.emit_bytes(opcode as u8, named_constant);
+
+        Ok(())
+    }
+
+    /// Compiles a field declaration `name = expr;` in a class body. The default
+    /// is compiled into a method-like closure returning its value, which the VM
+    /// runs on every new instance before `init`.
+    fn field(&mut self, id: String) -> Result<()> {
+        self.expect(TokenType::Equal)?;
+
+        let handle = self.make_string(id.clone());
+        let value = Value::Obj(handle);
+        let named_constant = self.add_constant(value)?;
+
+        let closure_obj = self.with_function_ctx(id, FunctionType::Method, &mut |this| {
+            this.begin_scope();
+
+            this.expression()?;
+            this.expect(TokenType::Semicolon)?;
+
+            this.emit_byte(OpCode::Return as u8);
+
+            Ok(())
+        })?;
+
+        self.emit_function(closure_obj)?;
+
+        self.emit_bytes(OpCode::Field as u8, named_constant);
+
+        Ok(())
+    }
+
     fn fun_declaration(&mut self) -> Result<()> {
         dprintln!("fun_declaration");
+
+        // `fun (` starts an anonymous function instead, e.g. one called right
+        // away
+        if self.peek_nth(1) == Some(&TokenType::LParen) {
+            return self.statement();
+        }
+
         self.expect(TokenType::Fun)?;
 
         let (global, name) = self.parse_function_name()?;
@@ -226,9 +482,17 @@ impl<'a> Compiler<'a> {
         Ok(())
     }
 
+    /// An anonymous function expression, `fun (params) { body }`, which
+    /// leaves its closure on the stack.
+    fn lambda(&mut self) -> Result<()> {
+        self.expect(TokenType::Fun)?;
+
+        self.function(String::from("lambda"), FunctionType::Function)
+    }
+
     fn function(&mut self, name: String, fun_type: FunctionType) -> Result<()> {
         dprintln!("function");
-        let mut closure_obj = self.with_function_ctx(name, fun_type, &mut |this| {
+        let closure_obj = self.with_function_ctx(name, fun_type, &mut |this| {
             this.begin_scope();
 
             this.parse_parameters()?;
@@ -236,9 +500,15 @@ impl<'a> Compiler<'a> {
             this.block()
         })?;
 
-        closure_obj.upvalue_count = self.upvalues.len();
+        self.emit_function(closure_obj)
+    }
+
+    /// Emits the `Closure` instruction for a just-compiled function along with
+    /// its upvalue operands.
+    fn emit_function(&mut self, mut function: ObjFunction) -> Result<()> {
+        function.upvalue_count = self.upvalues.len();
 
-        let handle = self.heap.insert(LoxObj::Closure(Box::from(closure_obj)));
+        let handle = self.heap.insert(LoxObj::Function(Box::from(function)));
         let value = Value::Obj(handle);
         self.emit_closure(value)?;
 
@@ -293,6 +563,57 @@ impl<'a> Compiler<'a> {
         // where the variable name (its handle) will be stored
         let const_idx = self.parse_variable()?;
 
+        self.var_initializer(const_idx)
+    }
+
+    /// `const name = value;`, a variable that can't be assigned afterwards.
+    fn const_declaration(&mut self) -> Result<()> {
+        self.expect(TokenType::Const)?;
+
+        let name = match self.peek() {
+            Some(TokenType::Ident(id)) => id.clone(),
+            _ => return Err(LoxError::UnexpectedToken(self.advance()?)),
+        };
+
+        let const_idx = self.parse_variable()?;
+
+        if self.scope_depth > 0 {
+            let len = self.locals.len();
+            self.locals[len - 1].is_const = true;
+        } else {
+            self.const_globals.insert(name);
+        }
+
+        if self.peek() != Some(&TokenType::Equal) {
+            return Err(LoxError::CompileError("const without initializer"));
+        }
+
+        self.initializer()?;
+
+        // The VM remembers const globals, so functions compiled before the
+        // declaration can't assign them either
+        if self.scope_depth > 0 {
+            self.mark_initialized();
+        } else {
+            self.emit_bytes(OpCode::DefineConst as u8, const_idx);
+        }
+
+        Ok(())
+    }
+
+    /// Compiles an optional `= value` and the closing `;` of a variable
+    /// declaration whose name has been parsed, then defines the variable.
+    fn var_initializer(&mut self, const_idx: u8) -> Result<()> {
+        self.initializer()?;
+
+        self.define_variable(const_idx);
+
+        Ok(())
+    }
+
+    /// Compiles an optional `= value`, or pushes nil without one, and the
+    /// closing `;`.
+    fn initializer(&mut self) -> Result<()> {
         match self.peek() {
             Some(TokenType::Equal) => {
                 self.advance()?;
@@ -303,8 +624,6 @@ impl<'a> Compiler<'a> {
 
         self.expect(TokenType::Semicolon)?;
 
-        self.define_variable(const_idx);
-
         Ok(())
     }
 
@@ -320,7 +639,7 @@ impl<'a> Compiler<'a> {
 
                     let value = Value::Obj(handle);
 
-                    Ok((self.chunk().add_constant(value)?, id))
+                    Ok((self.add_constant(value)?, id))
                 }
             }
             token => Err(LoxError::UnexpectedToken(token)),
@@ -340,7 +659,7 @@ impl<'a> Compiler<'a> {
 
                     let value = Value::Obj(handle);
 
-                    self.chunk().add_constant(value)
+                    self.add_constant(value)
                 }
             }
             token => Err(LoxError::UnexpectedToken(token)),
@@ -350,6 +669,10 @@ impl<'a> Compiler<'a> {
     fn declare_variable(&mut self, name: String) -> Result<()> {
         // variable is global
         if self.scope_depth == 0 {
+            if self.const_globals.contains(&name) {
+                return Err(LoxError::CompileError("cannot redeclare const"));
+            }
+
             return Ok(());
         }
 
@@ -376,6 +699,7 @@ impl<'a> Compiler<'a> {
             name,
             depth: -1,
             is_captured: false,
+            is_const: false,
         });
 
         Ok(())
@@ -402,27 +726,85 @@ impl<'a> Compiler<'a> {
 
     fn statement(&mut self) -> Result<()> {
         dprintln!("statement");
-        match self.peek() {
-            Some(TokenType::Print) => self.print_statement(),
+        self.nested(|this| match this.peek() {
+            Some(TokenType::Print) => this.print_statement(),
+            Some(TokenType::Assert) => this.assert_statement(),
             Some(TokenType::LBrace) => {
-                self.begin_scope();
-                self.block()?;
-                self.end_scope();
+                if this.at_map_literal()? {
+                    return this.expr_statement();
+                }
+
+                this.begin_scope();
+                this.block()?;
+                this.end_scope();
                 Ok(())
             }
-            Some(TokenType::If) => self.if_statement(),
-            Some(TokenType::While) => self.while_statement(),
-            Some(TokenType::Return) => self.return_statement(),
-            _ => self.expr_statement(),
-        }
+            Some(TokenType::If) => this.if_statement(),
+            Some(TokenType::Switch) => this.switch_statement(),
+            Some(TokenType::While) => this.while_statement(),
+            Some(TokenType::For) => this.for_statement(),
+            Some(TokenType::Break) => this.break_statement(),
+            Some(TokenType::Continue) => this.continue_statement(),
+            Some(TokenType::Return) => this.return_statement(),
+            Some(TokenType::Yield) => this.yield_statement(),
+            _ => this.expr_statement(),
+        })
+    }
+
+    /// Whether the `{` starting a statement opens a map literal rather than a
+    /// block, judging by a simple key followed by `:`, as in `{"k": v}`.
+    fn at_map_literal(&mut self) -> Result<bool> {
+        let checkpoint = self.checkpoint();
+
+        self.expect(TokenType::LBrace)?;
+
+        let is_map = match self.advance()? {
+            Some(TokenType::Str(_)) | Some(TokenType::Num(_)) | Some(TokenType::Ident(_)) => {
+                self.peek() == Some(&TokenType::Colon)
+            }
+            _ => false,
+        };
+
+        self.restore(checkpoint);
+
+        Ok(is_map)
     }
 
     fn print_statement(&mut self) -> Result<()> {
         self.expect(TokenType::Print)?;
+        let line = self.line;
+
+        self.expression()?;
+        self.expect(TokenType::Semicolon)?;
+
+        self.emit_byte_on_line(OpCode::Print as u8, line);
+        Ok(())
+    }
+
+    /// `assert condition;` fails with the condition's source text if it's
+    /// falsey, e.g. `assertion failed: x == 5`.
+    fn assert_statement(&mut self) -> Result<()> {
+        self.expect(TokenType::Assert)?;
+        let line = self.line;
+
+        self.scan_ahead(0);
+        let start = self.tokens.get(self.next_token).map(|token| token.start);
+
         self.expression()?;
+
+        let end = self.tokens[self.next_token - 1].end;
+        let text = start
+            .and_then(|start| self.scanner.text(start, end))
+            .unwrap_or_default()
+            .to_owned();
+
         self.expect(TokenType::Semicolon)?;
 
-        self.emit_byte(OpCode::Print as u8);
+        let handle = self.make_string(text);
+        let idx = self.add_constant(Value::Obj(handle))?;
+
+        self.emit_byte_on_line(OpCode::Assert as u8, line);
+        self.emit_byte_on_line(idx, line);
         Ok(())
     }
 
@@ -471,6 +853,18 @@ impl<'a> Compiler<'a> {
         self.expect(TokenType::If)?;
 
         self.expect(TokenType::LParen)?;
+
+        if let Some(condition) = self.constant_condition()? {
+            self.branch(condition)?;
+
+            if let Some(TokenType::Else) = self.peek() {
+                self.advance()?;
+                self.branch(!condition)?;
+            }
+
+            return Ok(());
+        }
+
         self.expression()?;
         self.expect(TokenType::RParen)?;
 
@@ -492,10 +886,143 @@ impl<'a> Compiler<'a> {
         self.patch_jump(else_jump)
     }
 
+    /// `switch (subject) { case v: ... default: ... }`. The subject is
+    /// evaluated once and compared with each case's value in turn, as `==`
+    /// would, so a value of another type just doesn't match. Only the
+    /// first matching case runs (there's no fall-through), or the default if
+    /// none matches. So `break` and `continue` in a case apply to the
+    /// enclosing loop.
+    fn switch_statement(&mut self) -> Result<()> {
+        self.expect(TokenType::Switch)?;
+        self.expect(TokenType::LParen)?;
+
+        self.begin_scope();
+
+        self.expression()?;
+        self.expect(TokenType::RParen)?;
+
+        // The subject lives in a hidden local no identifier can refer to
+        self.add_local("switch subject".to_owned())?;
+        self.mark_initialized();
+        let subject_slot = (self.locals.len() - 1) as u8;
+
+        self.expect(TokenType::LBrace)?;
+
+        let mut end_jumps = vec![];
+
+        while let Some(TokenType::Case) = self.peek() {
+            self.advance()?;
+            let line = self.line;
+
+            self.emit_bytes(OpCode::GetLocal as u8, subject_slot);
+            self.expression()?;
+            self.expect(TokenType::Colon)?;
+            self.emit_byte_on_line(OpCode::Equal as u8, line);
+
+            let next_jump = self.emit_jump(OpCode::JumpIfFalse as u8);
+            self.emit_byte(OpCode::Pop as u8);
+
+            self.case_body()?;
+
+            end_jumps.push(self.emit_jump(OpCode::Jump as u8));
+
+            self.patch_jump(next_jump)?;
+            self.emit_byte(OpCode::Pop as u8);
+        }
+
+        if let Some(TokenType::Default) = self.peek() {
+            self.advance()?;
+            self.expect(TokenType::Colon)?;
+
+            self.case_body()?;
+
+            if let Some(TokenType::Case) | Some(TokenType::Default) = self.peek() {
+                return Err(LoxError::CompileError("default must be the last case"));
+            }
+        }
+
+        self.expect(TokenType::RBrace)?;
+
+        for jump in end_jumps {
+            self.patch_jump(jump)?;
+        }
+
+        self.end_scope();
+
+        Ok(())
+    }
+
+    /// The statements of a switch case, up to the next case or the end of
+    /// the switch, in a scope of their own.
+    fn case_body(&mut self) -> Result<()> {
+        self.begin_scope();
+
+        loop {
+            match self.peek() {
+                Some(TokenType::Case)
+                | Some(TokenType::Default)
+                | Some(TokenType::RBrace)
+                | None => break,
+                _ => self.declaration()?,
+            }
+        }
+
+        self.end_scope();
+
+        Ok(())
+    }
+
+    /// If the condition about to be parsed is a lone literal, as in
+    /// `while (true)`, consumes it along with the closing paren and returns
+    /// its truthiness, so the caller can skip the test and jumps entirely.
+    fn constant_condition(&mut self) -> Result<Option<bool>> {
+        let truthy = match self.peek() {
+            Some(TokenType::True) | Some(TokenType::Num(_)) => true,
+            Some(TokenType::False) | Some(TokenType::Nil) => false,
+            _ => return Ok(None),
+        };
+
+        if self.peek_nth(1) != Some(&TokenType::RParen) {
+            return Ok(None);
+        }
+
+        self.advance()?;
+        self.advance()?;
+
+        Ok(Some(truthy))
+    }
+
+    /// Compiles a statement that runs only if `live`. A dead one is still
+    /// parsed, so its syntax errors are reported, but its code is dropped,
+    /// along with its constants and functions, which stop counting toward the
+    /// `ProgramLimits` once it's parsed.
+    fn branch(&mut self, live: bool) -> Result<()> {
+        let start = self.chunk().code.len();
+        let constants = self.chunk().constants.len();
+        let (constant_count, function_count) = (self.constant_count, self.function_count);
+
+        self.statement()?;
+
+        if !live {
+            self.chunk().code.truncate(start);
+            self.chunk().lines.truncate(start);
+            self.chunk().constants.truncate(constants);
+            self.constant_count = constant_count;
+            self.function_count = function_count;
+
+            // Breaks in the dropped code no longer exist to be patched
+            for context in &mut self.loop_contexts {
+                context.breaks.retain(|&jump| jump < start);
+            }
+        }
+
+        Ok(())
+    }
+
     fn patch_jump(&mut self, offset: usize) -> Result<()> {
         let jump = self.chunk().code.len() - offset - 2;
 
-        if jump > std::u16::MAX as usize {
+        if jump > u16::MAX as usize {
             return Err(LoxError::InternalCompilerError);
         }
 
@@ -508,9 +1035,31 @@ impl<'a> Compiler<'a> {
     fn while_statement(&mut self) -> Result<()> {
         self.expect(TokenType::While)?;
 
+        self.begin_loop();
+        self.begin_loop_context();
+        self.while_loop()?;
+        self.end_loop_context()?;
+        self.end_loop();
+
+        Ok(())
+    }
+
+    /// Compiles the rest of a `while` loop after the keyword.
+    fn while_loop(&mut self) -> Result<()> {
         let loop_start = self.chunk().code.len();
 
         self.expect(TokenType::LParen)?;
+
+        if let Some(condition) = self.constant_condition()? {
+            self.branch(condition)?;
+
+            if condition {
+                self.emit_loop(loop_start)?;
+            }
+
+            return Ok(());
+        }
+
         self.expression()?;
         self.expect(TokenType::RParen)?;
 
@@ -527,34 +1076,384 @@ impl<'a> Compiler<'a> {
         Ok(())
     }
 
-    fn return_statement(&mut self) -> Result<()> {
-        if self.fun_type == FunctionType::Script {
-            return Err(LoxError::CompileError("invalid return"));
-        }
+    fn for_statement(&mut self) -> Result<()> {
+        self.expect(TokenType::For)?;
 
-        self.expect(TokenType::Return)?;
+        self.begin_scope();
+
+        self.expect(TokenType::LParen)?;
+
+        // The loop variable's name and slot, if the initializer declares one
+        let mut loop_variable = None;
 
         match self.peek() {
             Some(TokenType::Semicolon) => {
-                self.emit_return();
+                self.advance()?;
             }
-            _ => {
-                if self.fun_type == FunctionType::Initializer {
-                    return Err(LoxError::CompileError("cannot return value from init"));
+            Some(TokenType::Var) => {
+                self.advance()?;
+
+                let name = self.expect_ident()?;
+
+                if let Some(TokenType::In) = self.peek() {
+                    return self.foreach_statement(name);
                 }
 
-                self.expression()?;
+                self.declare_variable(name.clone())?;
+                self.var_initializer(0)?;
 
-                self.emit_byte(OpCode::Return as u8);
+                let slot = self.locals.len() - 1;
+                loop_variable = Some((name, slot as u8));
             }
-        }
+            Some(TokenType::Ident(_)) => {
+                let name = self.expect_ident()?;
 
-        self.expect(TokenType::Semicolon).map(|_| ())
-    }
+                if let Some(TokenType::In) = self.peek() {
+                    return self.foreach_statement(name);
+                }
 
-    fn expr_statement(&mut self) -> Result<()> {
-        dprintln!("expr_statement");
+                // Not a foreach after all: finish the initializer expression
+                // the identifier started.
+                let start = self.chunk().code.len();
+                self.named_variable(TokenType::Ident(name), true)?;
+                self.parse_infix(start, TokenType::Equal.precedence(), true)?;
+                self.expect(TokenType::Semicolon)?;
+                self.emit_byte(OpCode::Pop as u8);
+            }
+            _ => self.expr_statement()?,
+        }
+
+        self.begin_loop();
+        self.begin_loop_context();
+        self.for_loop(loop_variable)?;
+        self.end_loop_context()?;
+        self.end_loop();
+
+        self.end_scope();
+
+        Ok(())
+    }
+
+    /// Compiles the rest of a `for` loop after its initializer.
+    fn for_loop(&mut self, loop_variable: Option<(String, u8)>) -> Result<()> {
+        let mut loop_start = self.chunk().code.len();
+
+        let exit_jump = match self.peek() {
+            Some(TokenType::Semicolon) => {
+                self.advance()?;
+                None
+            }
+            _ => {
+                self.expression()?;
+                self.expect(TokenType::Semicolon)?;
+
+                let exit_jump = self.emit_jump(OpCode::JumpIfFalse as u8);
+                self.emit_byte(OpCode::Pop as u8);
+
+                Some(exit_jump)
+            }
+        };
+
+        if let Some(TokenType::RParen) = self.peek() {
+            self.advance()?;
+        } else {
+            let body_jump = self.emit_jump(OpCode::Jump as u8);
+
+            let increment_start = self.chunk().code.len();
+
+            self.expression()?;
+            self.emit_byte(OpCode::Pop as u8);
+            self.expect(TokenType::RParen)?;
+
+            self.emit_loop(loop_start)?;
+            loop_start = increment_start;
+
+            self.loop_contexts.last_mut().unwrap().start = increment_start;
+
+            self.patch_jump(body_jump)?;
+        }
+
+        match loop_variable {
+            Some((name, slot)) if self.fresh_loop_bindings => {
+                // Shadow the loop variable with a per-iteration copy and write
+                // any changes back before the increment runs.
+                self.begin_scope();
+
+                self.add_local(name)?;
+                self.emit_bytes(OpCode::GetLocal as u8, slot);
+                self.mark_initialized();
+
+                self.loop_contexts.last_mut().unwrap().loop_variable = Some(slot);
+
+                self.statement()?;
+
+                self.write_back_loop_variable(slot);
+
+                self.end_scope();
+            }
+            _ => self.statement()?,
+        }
+
+        self.emit_loop(loop_start)?;
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump)?;
+            self.emit_byte(OpCode::Pop as u8);
+        }
+
+        Ok(())
+    }
+
+    fn begin_loop(&mut self) {
+        self.loops.push(LoopGlobals::default());
+    }
+
+    /// Warns about the globals the loop just compiled could have hoisted.
+    fn end_loop(&mut self) {
+        let globals = self.loops.pop().unwrap();
+
+        if globals.has_call {
+            return;
+        }
+
+        for (name, line, column) in globals.reads {
+            if globals.assigned.contains(&name) {
+                continue;
+            }
+
+            let warning = Warning::LoopInvariantGlobal { name, line, column };
+
+            // Enclosing loops see the same reads
+            if !self.warnings.contains(&warning) {
+                self.warnings.push(warning);
+            }
+        }
+    }
+
+    /// Starts a loop whose condition begins at the current end of the code.
+    fn begin_loop_context(&mut self) {
+        let start = self.chunk().code.len();
+
+        self.loop_contexts.push(LoopContext {
+            scope_depth: self.scope_depth,
+            start,
+            breaks: vec![],
+            loop_variable: None,
+        });
+    }
+
+    /// Points the breaks out of the loop just compiled to the current end of
+    /// the code.
+    fn end_loop_context(&mut self) -> Result<()> {
+        let context = self.loop_contexts.pop().unwrap();
+
+        for jump in context.breaks {
+            self.patch_jump(jump)?;
+        }
+
+        Ok(())
+    }
+
+    fn break_statement(&mut self) -> Result<()> {
+        self.expect(TokenType::Break)?;
+        self.expect(TokenType::Semicolon)?;
+
+        let scope_depth = match self.loop_contexts.last() {
+            Some(context) => context.scope_depth,
+            None => return Err(LoxError::CompileError("break outside loop")),
+        };
+
+        self.discard_locals(scope_depth);
+
+        let jump = self.emit_jump(OpCode::Jump as u8);
+        self.loop_contexts.last_mut().unwrap().breaks.push(jump);
+
+        Ok(())
+    }
+
+    fn continue_statement(&mut self) -> Result<()> {
+        self.expect(TokenType::Continue)?;
+        self.expect(TokenType::Semicolon)?;
+
+        let (scope_depth, start, loop_variable) = match self.loop_contexts.last() {
+            Some(context) => (context.scope_depth, context.start, context.loop_variable),
+            None => return Err(LoxError::CompileError("continue outside loop")),
+        };
+
+        if let Some(slot) = loop_variable {
+            self.write_back_loop_variable(slot);
+        }
+
+        self.discard_locals(scope_depth);
+        self.emit_loop(start)
+    }
+
+    /// Copies the per-iteration copy of the loop variable in `slot` back into
+    /// it.
+    fn write_back_loop_variable(&mut self, slot: u8) {
+        self.emit_bytes(OpCode::GetLocal as u8, slot + 1);
+        self.emit_bytes(OpCode::SetLocal as u8, slot);
+        self.emit_byte(OpCode::Pop as u8);
+    }
+
+    /// Emits the code `end_scope` would to discard the locals deeper than
+    /// `scope_depth`, without forgetting them, for jumping out of their scopes.
+    fn discard_locals(&mut self, scope_depth: isize) {
+        let discards: Vec<_> = self
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth > scope_depth)
+            .map(|local| local.is_captured)
+            .collect();
+
+        for is_captured in discards {
+            if is_captured {
+                self.emit_byte(OpCode::CloseUpvalue as u8);
+            } else {
+                self.emit_byte(OpCode::Pop as u8);
+            }
+        }
+    }
+
+    fn note_global_read(&mut self, name: &str) {
+        if self.loops.is_empty() {
+            return;
+        }
+
+        let line = self.line;
+        let column = self.column();
+
+        for globals in &mut self.loops {
+            if !globals.reads.iter().any(|(read, ..)| read == name) {
+                globals.reads.push((name.to_owned(), line, column));
+            }
+        }
+    }
+
+    fn note_global_assignment(&mut self, name: &str) {
+        for globals in &mut self.loops {
+            globals.assigned.insert(name.to_owned());
+        }
+    }
+
+    fn note_call(&mut self) {
+        for globals in &mut self.loops {
+            globals.has_call = true;
+        }
+    }
+
+    /// Compiles the rest of `for (x in iterable) body`, starting at `in`. Lists
+    /// and strings are iterated natively; an instance is asked for an iterator
+    /// with `iter()`, which is then driven with `done()` and `next()`. Each
+    /// iteration gets a fresh binding of the loop variable.
+    fn foreach_statement(&mut self, name: String) -> Result<()> {
+        self.expect(TokenType::In)?;
+
+        self.expression()?;
+        self.expect(TokenType::RParen)?;
+
+        self.emit_byte(OpCode::GetIter as u8);
+
+        // Iterating an instance calls its methods
+        self.note_call();
+
+        // The iterator lives in a hidden local no identifier can refer to
+        self.add_local("for iterator".to_owned())?;
+        self.mark_initialized();
+        let iterator_slot = (self.locals.len() - 1) as u8;
+
+        self.begin_loop_context();
+
+        let loop_start = self.chunk().code.len();
+
+        self.emit_bytes(OpCode::GetLocal as u8, iterator_slot);
+        self.emit_bytes(OpCode::IterDone as u8, OpCode::Not as u8);
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse as u8);
+        self.emit_byte(OpCode::Pop as u8);
+
+        self.begin_scope();
+
+        self.emit_bytes(OpCode::GetLocal as u8, iterator_slot);
+        self.emit_byte(OpCode::IterNext as u8);
+        self.add_local(name)?;
+        self.mark_initialized();
+
+        self.statement()?;
+
+        self.end_scope();
+
+        self.emit_loop(loop_start)?;
+
+        self.patch_jump(exit_jump)?;
+        self.emit_byte(OpCode::Pop as u8);
+
+        self.end_loop_context()?;
+
+        self.end_scope();
+
+        Ok(())
+    }
+
+    fn return_statement(&mut self) -> Result<()> {
+        if self.fun_type == FunctionType::Script && !self.top_level_return {
+            return Err(LoxError::CompileError("invalid return"));
+        }
+
+        self.expect(TokenType::Return)?;
+
+        match self.peek() {
+            Some(TokenType::Semicolon) => {
+                self.emit_return();
+            }
+            _ => {
+                if self.fun_type == FunctionType::Initializer {
+                    return Err(LoxError::CompileError("cannot return value from init"));
+                }
+
+                self.expression()?;
+
+                self.emit_byte(OpCode::Return as u8);
+            }
+        }
+
+        self.expect(TokenType::Semicolon).map(|_| ())
+    }
+
+    /// `yield value;` suspends the call, handing `value` (or nil) to whoever
+    /// resumed it. Any function containing one is compiled as a generator.
+    fn yield_statement(&mut self) -> Result<()> {
+        match self.fun_type {
+            FunctionType::Script => return Err(LoxError::CompileError("invalid yield")),
+            FunctionType::Initializer => {
+                return Err(LoxError::CompileError("cannot yield from init"))
+            }
+            FunctionType::Function | FunctionType::Method => (),
+        }
+
+        self.expect(TokenType::Yield)?;
+
+        match self.peek() {
+            Some(TokenType::Semicolon) => self.emit_byte(OpCode::Nil as u8),
+            _ => self.expression()?,
+        }
+
+        self.emit_byte(OpCode::Yield as u8);
+        self.function.is_generator = true;
+
+        self.expect(TokenType::Semicolon).map(|_| ())
+    }
+
+    fn expr_statement(&mut self) -> Result<()> {
+        dprintln!("expr_statement");
         self.expression()?;
+
+        // `name(params) {` is a method declaration, which only a class body accepts
+        if let Some(TokenType::LBrace) = self.peek() {
+            return Err(LoxError::MethodOutsideClass(self.line));
+        }
+
         self.expect(TokenType::Semicolon)?;
 
         self.emit_byte(OpCode::Pop as u8);
@@ -568,14 +1467,42 @@ impl<'a> Compiler<'a> {
 
     fn parse_precedence(&mut self, precedence: usize) -> Result<()> {
         dprintln!("parse_precedence");
+        self.check_code_size()?;
+
         let can_assign = precedence <= TokenType::Equal.precedence();
 
-        self.prefix(can_assign)?;
+        self.nested(|this| {
+            let start = this.chunk().code.len();
+
+            this.prefix(can_assign)?;
+
+            this.parse_infix(start, precedence, can_assign)
+        })
+    }
+
+    /// Parses the operators following an already compiled operand, whose code
+    /// starts at `start`, for as long as they bind at least as tightly as
+    /// `precedence`.
+    fn parse_infix(&mut self, start: usize, precedence: usize, can_assign: bool) -> Result<()> {
+        // Whether the operand so far is an unparenthesized comparison
+        let mut after_comparison = false;
 
         loop {
             match self.peek() {
+                // `=` is only valid when consumed by an assignable prefix.
+                Some(TokenType::Equal) => break,
                 Some(tok_type) if precedence <= tok_type.precedence() => {
-                    self.infix(can_assign)?;
+                    let is_comparison = tok_type.is_comparison();
+
+                    // `a < b < c` would compare the bool `a < b` with `c`
+                    if is_comparison && after_comparison {
+                        return Err(LoxError::CompileError(
+                            "chained comparison, write `a < b and b < c` instead",
+                        ));
+                    }
+
+                    self.infix(start, can_assign)?;
+                    after_comparison = is_comparison;
                 }
                 _ => break,
             }
@@ -583,43 +1510,114 @@ impl<'a> Compiler<'a> {
 
         match self.peek() {
             Some(TokenType::Equal) if can_assign => {
-                Err(LoxError::UnexpectedToken(Some(TokenType::Equal)))
+                Err(LoxError::InvalidAssignmentTarget(self.line))
+            }
+            Some(tok_type) if can_assign && tok_type.is_compound_assignment() => {
+                Err(LoxError::InvalidAssignmentTarget(self.line))
             }
             _ => Ok(()),
         }
     }
 
-    fn binary(&mut self) -> Result<()> {
+    /// Consumes a compound assignment operator like `+=`, returning the
+    /// arithmetic instruction it applies.
+    fn compound_operator(&mut self) -> Result<OpCode> {
+        match self.advance()? {
+            Some(TokenType::PlusEq) => Ok(OpCode::Add),
+            Some(TokenType::MinusEq) => Ok(OpCode::Subtract),
+            Some(TokenType::StarEq) => Ok(OpCode::Multiply),
+            Some(TokenType::SlashEq) => Ok(OpCode::Divide),
+            token => Err(LoxError::UnexpectedToken(token)),
+        }
+    }
+
+    fn binary(&mut self, left_start: usize) -> Result<()> {
         dprintln!("binary");
         let op = self.advance()?.ok_or(LoxError::UnexpectedEof)?;
+        let line = self.line;
 
         self.parse_precedence(op.precedence() + 1)?;
 
-        match op {
-            TokenType::Plus => self.emit_byte(OpCode::Add as u8),
-            TokenType::Minus => self.emit_byte(OpCode::Subtract as u8),
-            TokenType::Star => self.emit_byte(OpCode::Multiply as u8),
-            TokenType::Slash => self.emit_byte(OpCode::Divide as u8),
-            TokenType::BangEq => self.emit_bytes(OpCode::Equal as u8, OpCode::Not as u8),
-            TokenType::EqualEq => self.emit_byte(OpCode::Equal as u8),
-            TokenType::Greater => self.emit_byte(OpCode::Greater as u8),
-            TokenType::GreaterEq => self.emit_bytes(OpCode::Less as u8, OpCode::Not as u8),
-            TokenType::Less => self.emit_byte(OpCode::Less as u8),
-            TokenType::LessEq => self.emit_bytes(OpCode::Greater as u8, OpCode::Not as u8),
+        if op == TokenType::Plus && self.fold_string_concatenation(left_start)? {
+            return Ok(());
+        }
+
+        let bytes: &[u8] = match op {
+            TokenType::Plus => &[OpCode::Add as u8],
+            TokenType::Minus => &[OpCode::Subtract as u8],
+            TokenType::Star => &[OpCode::Multiply as u8],
+            TokenType::Slash => &[OpCode::Divide as u8],
+            TokenType::BangEq => &[OpCode::Equal as u8, OpCode::Not as u8],
+            TokenType::EqualEq => &[OpCode::Equal as u8],
+            TokenType::Greater => &[OpCode::Greater as u8],
+            TokenType::GreaterEq => &[OpCode::Less as u8, OpCode::Not as u8],
+            TokenType::Less => &[OpCode::Less as u8],
+            TokenType::LessEq => &[OpCode::Greater as u8, OpCode::Not as u8],
+            TokenType::Amp => &[OpCode::BitAnd as u8],
+            TokenType::Pipe => &[OpCode::BitOr as u8],
+            TokenType::Caret => &[OpCode::BitXor as u8],
+            TokenType::LessLess => &[OpCode::ShiftLeft as u8],
+            TokenType::GreaterGreater => &[OpCode::ShiftRight as u8],
             token => return Err(LoxError::UnexpectedToken(Some(token))),
+        };
+
+        // Errors should point at the operator, not the end of its right operand
+        for &byte in bytes {
+            self.emit_byte_on_line(byte, line);
         }
 
         Ok(())
     }
 
+    /// Replaces the code of two string literals being added, starting at
+    /// `left_start`, with their concatenation. Returns whether it did.
+    fn fold_string_concatenation(&mut self, left_start: usize) -> Result<bool> {
+        let constant = OpCode::Constant as u8;
+
+        let (left, right) = match self.chunk().code[left_start..] {
+            [op_a, a, op_b, b] if op_a == constant && op_b == constant => (a, b),
+            _ => return Ok(false),
+        };
+
+        let constants = &self.chunk().constants;
+
+        // The operands' constants are dropped too, so they must be the last
+        // two added
+        if left as usize + 2 != constants.len() || right as usize + 1 != constants.len() {
+            return Ok(false);
+        }
+
+        let value = match (constants[left as usize], constants[right as usize]) {
+            (Value::Obj(a), Value::Obj(b)) => match (self.heap.get(&a), self.heap.get(&b)) {
+                (Some(LoxObj::Str(a)), Some(LoxObj::Str(b))) => format!("{}{}", a.value, b.value),
+                _ => return Ok(false),
+            },
+            _ => return Ok(false),
+        };
+
+        self.chunk().code.truncate(left_start);
+        self.chunk().lines.truncate(left_start);
+        self.chunk().constants.truncate(left as usize);
+        self.constant_count -= 2;
+
+        let handle = self.make_string(value);
+        self.emit_const(Value::Obj(handle))?;
+
+        Ok(true)
+    }
+
     fn unary(&mut self) -> Result<()> {
         let op = self.advance()?.ok_or(LoxError::UnexpectedEof)?;
+        let line = self.line;
 
-        self.expression()?;
+        // The operand binds tighter than any binary operator but still picks up
+        // trailing calls and property accesses, e.g. `-f()()`.
+        self.parse_precedence(TokenType::Bang.precedence())?;
 
         match op {
-            TokenType::Minus => self.emit_byte(OpCode::Negate as u8),
-            TokenType::Bang => self.emit_byte(OpCode::Not as u8),
+            TokenType::Minus => self.emit_byte_on_line(OpCode::Negate as u8, line),
+            TokenType::Bang => self.emit_byte_on_line(OpCode::Not as u8, line),
+            TokenType::Tilde => self.emit_byte_on_line(OpCode::BitNot as u8, line),
             token => return Err(LoxError::UnexpectedToken(Some(token))),
         }
 
@@ -651,7 +1649,7 @@ impl<'a> Compiler<'a> {
             Some(TokenType::Nil) => self.emit_byte(OpCode::Nil as u8),
             Some(TokenType::True) => self.emit_byte(OpCode::True as u8),
             Some(TokenType::False) => self.emit_byte(OpCode::False as u8),
-            _ => unreachable!(),
+            token => return Err(LoxError::UnexpectedToken(token)),
         }
 
         Ok(())
@@ -691,33 +1689,78 @@ impl<'a> Compiler<'a> {
         let arg;
         let get_op;
         let set_op;
+        let is_const;
+        let mut global = None;
 
         if let Some(idx) = self.resolve_local(&value)? {
             arg = idx;
             get_op = OpCode::GetLocal;
             set_op = OpCode::SetLocal;
+            is_const = self.locals[idx as usize].is_const;
         } else if let Some(idx) = self.resolve_upvalue(&value)? {
             arg = idx;
             get_op = OpCode::GetUpvalue;
             set_op = OpCode::SetUpvalue;
+            is_const = self.captures_const(&value);
         } else {
+            global = Some(value.clone());
+            is_const = self.const_globals.contains(&value);
+
             let handle = self.make_string(value);
 
             let value = Value::Obj(handle);
 
-            arg = self.chunk().add_constant(value)?;
+            arg = self.add_constant(value)?;
             get_op = OpCode::GetGlobal;
             set_op = OpCode::SetGlobal;
         }
 
+        let assigns = match self.peek() {
+            Some(TokenType::Equal) => can_assign,
+            Some(tok_type) => can_assign && tok_type.is_compound_assignment(),
+            None => false,
+        };
+
+        if assigns && is_const {
+            return Err(LoxError::CompileError("cannot assign to const"));
+        }
+
         match self.peek() {
             Some(TokenType::Equal) if can_assign => {
                 self.advance()?;
                 self.expression()?;
 
+                if let Some(name) = &global {
+                    self.note_global_assignment(name);
+                }
+
+                self.emit_bytes(set_op as u8, arg);
+            }
+            Some(tok_type) if can_assign && tok_type.is_compound_assignment() => {
+                let op = self.compound_operator()?;
+                let line = self.line;
+
+                if let Some(name) = &global {
+                    self.note_global_read(name);
+                }
+
+                self.emit_bytes(get_op as u8, arg);
+                self.expression()?;
+                self.emit_byte_on_line(op as u8, line);
+
+                if let Some(name) = &global {
+                    self.note_global_assignment(name);
+                }
+
                 self.emit_bytes(set_op as u8, arg);
             }
-            _ => self.emit_bytes(get_op as u8, arg),
+            _ => {
+                if let Some(name) = &global {
+                    self.note_global_read(name);
+                }
+
+                self.emit_bytes(get_op as u8, arg)
+            }
         }
 
         Ok(())
@@ -752,9 +1795,9 @@ impl<'a> Compiler<'a> {
             UpvaluesKind::Past(i) => &mut self.upvalues_stack[i],
         };
 
-        for upvalue in upvalues.iter() {
+        for (i, upvalue) in upvalues.iter().enumerate() {
             if (upvalue.index == index) && (upvalue.is_local == is_local) {
-                return Ok(index);
+                return Ok(i as u8);
             }
         }
 
@@ -767,6 +1810,16 @@ impl<'a> Compiler<'a> {
         Ok(upvalues.len() as u8 - 1)
     }
 
+    /// Whether the local of an enclosing function that an upvalue called
+    /// `name` resolves to is a const.
+    fn captures_const(&self, name: &str) -> bool {
+        self.locals_stack
+            .iter()
+            .rev()
+            .find_map(|locals| locals.iter().rev().find(|local| local.name == name))
+            .is_some_and(|local| local.is_const)
+    }
+
     // We implement a poor man's recursion with an explicit pointer and loop.
     fn resolve_upvalue(&mut self, name: &str) -> Result<Option<u8>> {
         if self.locals_stack.is_empty() {
@@ -787,12 +1840,18 @@ impl<'a> Compiler<'a> {
                 // mark local
                 self.locals_stack[i][idx as usize].is_captured = true;
 
-                // unwind
-                while i < self.locals_stack.len() - 1 {
-                    // upvalues = &mut self.upvalues_stack[i];
-                    upvalues_kind = UpvaluesKind::Past(i as usize);
-                    index = self.add_upvalue(upvalues_kind, index, false)?;
+                // unwind, adding an upvalue to each function between the one that
+                // captured the local and the current one
+                while i + 1 < self.locals_stack.len() {
                     i += 1;
+
+                    upvalues_kind = if i + 1 == self.locals_stack.len() {
+                        UpvaluesKind::Current
+                    } else {
+                        UpvaluesKind::Past(i + 1)
+                    };
+
+                    index = self.add_upvalue(upvalues_kind, index, false)?;
                 }
 
                 return Ok(Some(index));
@@ -804,12 +1863,14 @@ impl<'a> Compiler<'a> {
                 return Ok(None);
             }
 
-            upvalues_kind = UpvaluesKind::Past(i as usize);
+            upvalues_kind = UpvaluesKind::Past(i);
             i -= 1;
         }
     }
 
     fn and(&mut self) -> Result<()> {
+        self.expect(TokenType::And)?;
+
         let end_jump = self.emit_jump(OpCode::JumpIfFalse as u8);
 
         self.emit_byte(OpCode::Pop as u8);
@@ -819,6 +1880,8 @@ impl<'a> Compiler<'a> {
     }
 
     fn or(&mut self) -> Result<()> {
+        self.expect(TokenType::Or)?;
+
         let else_jump = self.emit_jump(OpCode::JumpIfFalse as u8);
         let end_jump = self.emit_jump(OpCode::Jump as u8);
 
@@ -829,11 +1892,32 @@ impl<'a> Compiler<'a> {
         self.patch_jump(end_jump)
     }
 
+    /// `cond ? a : b`. The else branch is parsed at the same precedence, so
+    /// chained conditionals group to the right.
+    fn conditional(&mut self) -> Result<()> {
+        self.expect(TokenType::Question)?;
+
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse as u8);
+
+        self.emit_byte(OpCode::Pop as u8);
+        self.expression()?;
+        self.expect(TokenType::Colon)?;
+
+        let end_jump = self.emit_jump(OpCode::Jump as u8);
+
+        self.patch_jump(else_jump)?;
+        self.emit_byte(OpCode::Pop as u8);
+
+        self.parse_precedence(TokenType::Question.precedence())?;
+        self.patch_jump(end_jump)
+    }
+
     fn call(&mut self) -> Result<()> {
         self.expect(TokenType::LParen)?;
 
         let arg_count = self.argument_list()?;
 
+        self.note_call();
         self.emit_bytes(OpCode::Call as u8, arg_count);
 
         Ok(())
@@ -877,7 +1961,7 @@ impl<'a> Compiler<'a> {
 
                 let value = Value::Obj(handle);
 
-                self.chunk().add_constant(value)?
+                self.add_constant(value)?
             }
             token => return Err(LoxError::UnexpectedToken(Some(token))),
         };
@@ -889,9 +1973,21 @@ impl<'a> Compiler<'a> {
                 self.expression()?;
                 self.emit_bytes(OpCode::SetProperty as u8, named_constant);
             }
+            Some(tok_type) if can_assign && tok_type.is_compound_assignment() => {
+                let op = self.compound_operator()?;
+                let line = self.line;
+
+                // The receiver is evaluated once, and kept for the set
+                self.emit_byte(OpCode::Dup as u8);
+                self.emit_bytes(OpCode::GetProperty as u8, named_constant);
+                self.expression()?;
+                self.emit_byte_on_line(op as u8, line);
+                self.emit_bytes(OpCode::SetProperty as u8, named_constant);
+            }
             Some(TokenType::LParen) => {
                 self.advance()?;
                 let arg_count = self.argument_list()?;
+                self.note_call();
                 self.emit_bytes(OpCode::Invoke as u8, named_constant);
                 self.emit_byte(arg_count);
             }
@@ -903,6 +1999,93 @@ impl<'a> Compiler<'a> {
         Ok(())
     }
 
+    fn map(&mut self) -> Result<()> {
+        self.expect(TokenType::LBrace)?;
+
+        let mut entry_count = 0;
+
+        loop {
+            match self.peek() {
+                Some(TokenType::RBrace) | None => break,
+                _ => {
+                    if entry_count == 255 {
+                        return Err(LoxError::CompileError("too many map entries"));
+                    }
+
+                    self.expression()?;
+                    self.expect(TokenType::Colon)?;
+                    self.expression()?;
+
+                    entry_count += 1;
+
+                    match self.peek() {
+                        Some(TokenType::RBrace) | None => (),
+                        _ => {
+                            self.expect(TokenType::Comma)?;
+                        }
+                    };
+                }
+            }
+        }
+
+        self.expect(TokenType::RBrace)?;
+
+        self.emit_bytes(OpCode::BuildMap as u8, entry_count);
+
+        Ok(())
+    }
+
+    fn list(&mut self) -> Result<()> {
+        self.expect(TokenType::LBracket)?;
+
+        let mut item_count = 0;
+
+        loop {
+            match self.peek() {
+                Some(TokenType::RBracket) | None => break,
+                _ => {
+                    if item_count == 255 {
+                        return Err(LoxError::CompileError("too many list items"));
+                    }
+
+                    self.expression()?;
+
+                    item_count += 1;
+
+                    match self.peek() {
+                        Some(TokenType::RBracket) | None => (),
+                        _ => {
+                            self.expect(TokenType::Comma)?;
+                        }
+                    };
+                }
+            }
+        }
+
+        self.expect(TokenType::RBracket)?;
+
+        self.emit_bytes(OpCode::BuildList as u8, item_count);
+
+        Ok(())
+    }
+
+    fn index(&mut self, can_assign: bool) -> Result<()> {
+        self.expect(TokenType::LBracket)?;
+        self.expression()?;
+        self.expect(TokenType::RBracket)?;
+
+        match self.peek() {
+            Some(TokenType::Equal) if can_assign => {
+                self.advance()?;
+                self.expression()?;
+                self.emit_byte(OpCode::SetIndex as u8);
+            }
+            _ => self.emit_byte(OpCode::Index as u8),
+        }
+
+        Ok(())
+    }
+
     fn this(&mut self) -> Result<()> {
         if self.classes.is_empty() {
             return Err(LoxError::CompileError("`this` used outside class"));
@@ -929,7 +2112,7 @@ impl<'a> Compiler<'a> {
             Some(TokenType::Ident(id)) => {
                 let handle = self.make_string(id);
                 let value = Value::Obj(handle);
-                let named_constant = self.chunk().add_constant(value)?;
+                let named_constant = self.add_constant(value)?;
 
                 self.named_variable(TokenType::Ident("this".to_owned()), false)?;
 
@@ -941,6 +2124,7 @@ impl<'a> Compiler<'a> {
 
                         self.named_variable(TokenType::Ident("super".to_owned()), false)?;
 
+                        self.note_call();
                         self.emit_bytes(OpCode::SuperInvoke as u8, named_constant);
                         self.emit_byte(arg_count);
                     }
@@ -960,18 +2144,24 @@ impl<'a> Compiler<'a> {
         dprintln!("prefix");
         match self.peek().ok_or(LoxError::UnexpectedEof)? {
             TokenType::LParen => self.grouping(),
-            TokenType::Minus | TokenType::Bang => self.unary(),
+            TokenType::Minus | TokenType::Bang | TokenType::Tilde => self.unary(),
             TokenType::Num(_) => self.number(),
             TokenType::Nil | TokenType::True | TokenType::False => self.literal(),
             TokenType::Str(_) => self.string(),
             TokenType::Ident(_) => self.variable(can_assign),
             TokenType::This => self.this(),
             TokenType::Super => self.super_(),
-            t => unimplemented!("{:?}", t),
+            TokenType::LBrace => self.map(),
+            TokenType::LBracket => self.list(),
+            TokenType::Fun => self.lambda(),
+            _ => {
+                let token = self.advance()?.ok_or(LoxError::UnexpectedEof)?;
+                Err(LoxError::ExpectedExpression(token, self.line))
+            }
         }
     }
 
-    fn infix(&mut self, can_assign: bool) -> Result<()> {
+    fn infix(&mut self, left_start: usize, can_assign: bool) -> Result<()> {
         dprintln!("infix");
         match self.peek().ok_or(LoxError::UnexpectedEof)? {
             TokenType::Plus
@@ -983,36 +2173,94 @@ impl<'a> Compiler<'a> {
             | TokenType::Less
             | TokenType::LessEq
             | TokenType::Greater
-            | TokenType::GreaterEq => self.binary(),
+            | TokenType::GreaterEq
+            | TokenType::Amp
+            | TokenType::Pipe
+            | TokenType::Caret
+            | TokenType::LessLess
+            | TokenType::GreaterGreater => self.binary(left_start),
             TokenType::And => self.and(),
             TokenType::Or => self.or(),
+            TokenType::Question => self.conditional(),
             TokenType::LParen => self.call(),
             TokenType::Dot => self.dot(can_assign),
-            _ => unimplemented!(),
+            TokenType::LBracket => self.index(can_assign),
+            _ => {
+                let token = self.advance()?.ok_or(LoxError::UnexpectedEof)?;
+                Err(LoxError::UnexpectedOperator(token, self.line))
+            }
         }
     }
 
     fn advance(&mut self) -> Result<Option<TokenType>> {
-        match self.scanner.next() {
-            Some(Ok(Token { line, tok_type })) => {
-                self.line = line;
-                Ok(Some(tok_type))
+        self.scan_ahead(0);
+
+        match self.tokens.get(self.next_token) {
+            Some(Token { line, tok_type, .. }) => {
+                self.line = *line;
+                self.next_token += 1;
+                Ok(Some(tok_type.clone()))
+            }
+            None => match self.scan_error.take() {
+                Some(e) => Err(e),
+                None => Ok(None),
+            },
+        }
+    }
+
+    /// Scans until the token `n` places past the next one is buffered, or the
+    /// source ends or fails to scan.
+    fn scan_ahead(&mut self, n: usize) {
+        while self.tokens.len() <= self.next_token + n && self.scan_error.is_none() {
+            match self.scanner.next() {
+                Some(Ok(token)) => self.tokens.push(token),
+                Some(Err(e)) => self.scan_error = Some(e),
+                None => break,
             }
-            Some(Err(e)) => Err(e),
-            None => Ok(None),
         }
     }
 
+    /// Column, counting characters from 1, of the last token consumed.
+    pub fn column(&self) -> usize {
+        match self.next_token.checked_sub(1) {
+            Some(i) => self.scanner.column(self.tokens[i].start),
+            None => 1,
+        }
+    }
+
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            next_token: self.next_token,
+            line: self.line,
+        }
+    }
+
+    /// Rewinds the token stream to `checkpoint`. Any code emitted since is
+    /// kept, so this is only for looking ahead before committing to a parse.
+    fn restore(&mut self, checkpoint: Checkpoint) {
+        self.next_token = checkpoint.next_token;
+        self.line = checkpoint.line;
+    }
+
     fn with_function_ctx<T>(
         &mut self,
         name: String,
         fun_type: FunctionType,
         compile_fn: &mut T,
-    ) -> Result<ObjClosure>
+    ) -> Result<ObjFunction>
     where
         T: FnMut(&mut Self) -> Result<()>,
     {
-        let handle = self.make_string(name);
+        let handle = match self.debug_info {
+            DebugInfo::Full => Some(self.make_string(name)),
+            _ => None,
+        };
+
+        self.function_count += 1;
+
+        if self.function_count > self.limits.max_functions {
+            return Err(LoxError::ProgramTooLarge(ProgramLimit::Functions));
+        }
 
         let old_scope_depth = mem::replace(&mut self.scope_depth, 0);
 
@@ -1020,12 +2268,12 @@ impl<'a> Compiler<'a> {
 
         let old_function = mem::replace(
             &mut self.function,
-            ObjClosure {
+            ObjFunction {
                 arity: 0,
                 chunk: Chunk::default(),
-                name: Some(handle),
-                upvalues: vec![],
+                name: handle,
                 upvalue_count: 0,
+                is_generator: false,
                 is_marked: false,
             },
         );
@@ -1038,6 +2286,7 @@ impl<'a> Compiler<'a> {
                         depth: 0,
                         name: String::from(""),
                         is_captured: false,
+                        is_const: false,
                     }],
                 ));
             }
@@ -1048,17 +2297,25 @@ impl<'a> Compiler<'a> {
                         depth: 0,
                         name: String::from("this"),
                         is_captured: false,
+                        is_const: false,
                     }],
                 ));
             }
-            _ => unreachable!(),
+            FunctionType::Script => return Err(LoxError::InternalCompilerError),
         }
 
-        self.upvalues_stack
-            .push(mem::replace(&mut self.upvalues, vec![]));
+        self.upvalues_stack.push(mem::take(&mut self.upvalues));
+
+        // The body runs when it's called, not on every iteration of the loops
+        // around its declaration
+        let old_loops = mem::take(&mut self.loops);
+        let old_loop_contexts = mem::take(&mut self.loop_contexts);
 
         compile_fn(self)?;
 
+        self.loops = old_loops;
+        self.loop_contexts = old_loop_contexts;
+
         self.scope_depth = old_scope_depth;
         self.locals = self.locals_stack.pop().unwrap();
 
@@ -1070,10 +2327,16 @@ impl<'a> Compiler<'a> {
     }
 
     fn peek(&mut self) -> Option<&TokenType> {
-        match self.scanner.peek() {
-            Some(Ok(Token { tok_type, .. })) => Some(tok_type),
-            _ => None,
-        }
+        self.peek_nth(0)
+    }
+
+    /// The token `n` places past the next one, without consuming anything.
+    fn peek_nth(&mut self, n: usize) -> Option<&TokenType> {
+        self.scan_ahead(n);
+
+        self.tokens
+            .get(self.next_token + n)
+            .map(|Token { tok_type, .. }| tok_type)
     }
 
     fn expect(&mut self, expected_type: TokenType) -> Result<TokenType> {
@@ -1083,16 +2346,50 @@ impl<'a> Compiler<'a> {
         }
     }
 
+    /// Like `emit_byte`, but attributes the byte to `line` rather than to the
+    /// line of the last token consumed.
+    fn emit_byte_on_line(&mut self, value: u8, line: usize) {
+        match self.debug_info {
+            DebugInfo::None => self.chunk().code.push(value),
+            _ => self.chunk().write(value, line),
+        }
+    }
+
+    fn expect_ident(&mut self) -> Result<String> {
+        match self.advance()? {
+            Some(TokenType::Ident(id)) => Ok(id),
+            token => Err(LoxError::UnexpectedToken(token)),
+        }
+    }
+
     #[inline]
     pub fn chunk(&mut self) -> &mut Chunk {
         &mut self.function.chunk
     }
 
+    fn add_constant(&mut self, value: Value) -> Result<u8> {
+        if self.constant_count >= self.limits.max_constants {
+            return Err(LoxError::ProgramTooLarge(ProgramLimit::Constants));
+        }
+
+        let idx = self.chunk().add_constant(value)?;
+        self.constant_count += 1;
+
+        Ok(idx)
+    }
+
+    /// Checked as statements and operands are parsed, so no function can grow
+    /// much past the limit before it's caught.
+    fn check_code_size(&mut self) -> Result<()> {
+        if self.chunk().code.len() > self.limits.max_code_bytes {
+            return Err(LoxError::ProgramTooLarge(ProgramLimit::CodeBytes));
+        }
+
+        Ok(())
+    }
+
     fn make_string(&mut self, value: String) -> ValueHandle {
-        self.heap.insert(LoxObj::Str(Box::from(ObjString {
-            value,
-            is_marked: false,
-        })))
+        self.heap.intern(value)
     }
 
     fn emit_return(&mut self) {
@@ -1110,19 +2407,18 @@ impl<'a> Compiler<'a> {
 impl<'a> Codegen for Compiler<'a> {
     #[inline]
     fn emit_byte(&mut self, value: u8) {
-        let line = self.line;
-        self.chunk().write(value, line);
+        self.emit_byte_on_line(value, self.line);
     }
 
     fn emit_const(&mut self, value: Value) -> Result<()> {
-        let const_idx = self.chunk().add_constant(value)?;
+        let const_idx = self.add_constant(value)?;
         self.emit_bytes(OpCode::Constant as u8, const_idx);
         Ok(())
     }
 
     fn emit_closure(&mut self, value: Value) -> Result<()> {
         dprintln!("emit_closure");
-        let const_idx = self.chunk().add_constant(value)?;
+        let const_idx = self.add_constant(value)?;
         self.emit_bytes(OpCode::Closure as u8, const_idx);
         Ok(())
     }
@@ -1140,7 +2436,7 @@ impl<'a> Codegen for Compiler<'a> {
 
         let offset = self.chunk().code.len() - loop_start + 2;
 
-        if offset > std::u16::MAX as usize {
+        if offset > u16::MAX as usize {
             return Err(LoxError::InternalCompilerError);
         }
 
@@ -1150,3 +2446,498 @@ impl<'a> Codegen for Compiler<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(source: &str) -> Result<ObjFunction> {
+        Ok(compile_with(source, DebugInfo::Full)?.function)
+    }
+
+    /// Returns the whole compiler so the heap backing the constants stays alive.
+    fn compile_with(source: &str, debug_info: DebugInfo) -> Result<Compiler<'_>> {
+        let mut compiler = Compiler::new(source.chars(), Heap::default());
+        compiler.set_debug_info(debug_info);
+
+        compiler.compile()?;
+
+        Ok(compiler)
+    }
+
+    /// The first function declared in `script`.
+    fn first_function(script: &ObjFunction) -> &ObjFunction {
+        script
+            .chunk
+            .constants
+            .iter()
+            .find_map(|value| match value {
+                Value::Obj(handle) => match unsafe { &*handle.ptr } {
+                    LoxObj::Function(function) => Some(function.as_ref()),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_debug_info() {
+        let source = "var a = 1;
+fun f() {
+  return a;
+}
+";
+
+        let compiler = compile_with(source, DebugInfo::Full).unwrap();
+        let full = &compiler.function;
+        assert_eq!(full.chunk.lines.len(), full.chunk.code.len());
+        assert_eq!(full.chunk.lines[0], 1);
+        let f = first_function(full);
+        assert!(f.name.is_some());
+        // `GetGlobal a` is the function's first instruction
+        assert_eq!(f.chunk.lines[0], 3);
+
+        let compiler = compile_with(source, DebugInfo::LinesOnly).unwrap();
+        let lines_only = &compiler.function;
+        assert_eq!(lines_only.chunk.lines, full.chunk.lines);
+        assert!(first_function(lines_only).name.is_none());
+
+        let compiler = compile_with(source, DebugInfo::None).unwrap();
+        let none = &compiler.function;
+        assert_eq!(none.chunk.code, full.chunk.code);
+        assert!(none.chunk.lines.is_empty());
+        let f = first_function(none);
+        assert!(f.name.is_none());
+        assert!(f.chunk.lines.is_empty());
+    }
+
+    #[test]
+    fn test_invalid_assignment_target() {
+        let sources = [
+            "fun f() {}\nf() = 1;",
+            "\n3 = 1;",
+            "var a; var b;\n(a) = b;",
+            "var a; var b; var c;\na + b = c;",
+            "var a; var b;\na + b += 1;",
+            "var l = [1];\nl[0] -= 1;",
+            "var a; var b;\na ? b : a = 1;",
+        ];
+
+        for source in &sources {
+            assert!(
+                matches!(compile(source), Err(LoxError::InvalidAssignmentTarget(2))),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_misplaced_tokens() {
+        assert!(matches!(
+            compile("var a;\nprint ;"),
+            Err(LoxError::ExpectedExpression(TokenType::Semicolon, 2))
+        ));
+        assert!(matches!(
+            compile("var a = );"),
+            Err(LoxError::ExpectedExpression(TokenType::RParen, 1))
+        ));
+        assert!(matches!(
+            compile("\nprint 1 ! 2;"),
+            Err(LoxError::UnexpectedOperator(TokenType::Bang, 2))
+        ));
+        assert!(matches!(
+            compile("\n\ninit() {}"),
+            Err(LoxError::MethodOutsideClass(3))
+        ));
+    }
+
+    #[test]
+    fn test_valid_assignment() {
+        assert!(compile("var a; a = 1; var b = a = 2;").is_ok());
+    }
+
+    #[test]
+    fn test_chained_comparison() {
+        for source in &[
+            "print 1 < 2 < 3;",
+            "print 1 <= x > 0;",
+            "print 3 > 2 >= 1 == true;",
+        ] {
+            match compile(source) {
+                Err(LoxError::CompileError(message)) => {
+                    assert!(message.contains("a < b and b < c"), "{}", message)
+                }
+                result => panic!("{}: expected an error, got {:?}", source, result.err()),
+            }
+        }
+
+        for source in &[
+            "print (1 < 2) == true;",
+            "print (1 < 2) < 3;",
+            "print 1 < 2 == 2 > 1;",
+            "print 1 < 2 and 2 < 3;",
+        ] {
+            assert!(compile(source).is_ok(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_switch_default_not_last() {
+        for source in &[
+            "switch (1) { default: case 1: }",
+            "switch (1) { default: print 1; default: }",
+        ] {
+            assert!(
+                matches!(
+                    compile(source),
+                    Err(LoxError::CompileError("default must be the last case"))
+                ),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_const_assignment() {
+        for source in &[
+            "const x = 5;\nx = 6;",
+            "{ const x = 5; x = 6; }",
+            "const x = 5;\nx += 1;",
+            "fun f() { const x = 5; fun g() { x = 6; } }",
+            "const x = 5;\nfun f() { x = 6; }",
+        ] {
+            assert!(
+                matches!(
+                    compile(source),
+                    Err(LoxError::CompileError("cannot assign to const"))
+                ),
+                "{}",
+                source
+            );
+        }
+
+        for source in &["const x;", "{ const x; }"] {
+            assert!(
+                matches!(
+                    compile(source),
+                    Err(LoxError::CompileError("const without initializer"))
+                ),
+                "{}",
+                source
+            );
+        }
+
+        for source in &[
+            "const x = 5;\nvar x = 6;",
+            "const x = 5;\nfun x() {}",
+            "const x = 5;\nclass x {}",
+            "const x = 5;\nconst x = 6;",
+        ] {
+            assert!(
+                matches!(
+                    compile(source),
+                    Err(LoxError::CompileError("cannot redeclare const"))
+                ),
+                "{}",
+                source
+            );
+        }
+
+        // Shadowing a const with a variable is fine
+        assert!(compile("const x = 5;\n{ var x = 1; x = 2; }").is_ok());
+        assert!(compile("{ const x = 5; fun f(x) { x = 2; } }").is_ok());
+    }
+
+    #[test]
+    fn test_break_outside_loop() {
+        for source in &[
+            "break;",
+            "if (true) break;",
+            "while (true) { fun f() { break; } }",
+        ] {
+            assert!(
+                matches!(
+                    compile(source),
+                    Err(LoxError::CompileError("break outside loop"))
+                ),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_continue_outside_loop() {
+        for source in &["continue;", "for (var x in []) { fun f() { continue; } }"] {
+            assert!(
+                matches!(
+                    compile(source),
+                    Err(LoxError::CompileError("continue outside loop"))
+                ),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_constant_conditions() {
+        let code = |source: &str| compile(source).unwrap().chunk.code;
+
+        let print_constant = |idx: u8| vec![OpCode::Constant as u8, idx, OpCode::Print as u8];
+
+        // The dead branch's constant is dropped too, so the live one's is 0
+        assert_eq!(code("if (false) print 1; else print 2;"), print_constant(0));
+        assert_eq!(code("if (0) { print 1; } else print 2;"), print_constant(0));
+        assert!(code("if (nil) print 1;").is_empty());
+        assert!(code("while (false) print 1;").is_empty());
+        assert_eq!(
+            code("while (true) print 1;"),
+            [print_constant(0), vec![OpCode::Loop as u8, 0, 6]].concat()
+        );
+
+        // Conditions that merely start with a literal are tested at runtime
+        assert!(code("if (false == nil) print 1;").contains(&(OpCode::JumpIfFalse as u8)));
+
+        // Dead branches must still parse
+        assert!(compile("if (false) print; else print 2;").is_err());
+        assert!(compile("while (false) { print 1 }").is_err());
+    }
+
+    #[test]
+    fn test_fold_string_concatenation() {
+        let compiler = compile_with("print \"foo\" + \"bar\" + \"!\";", DebugInfo::Full).unwrap();
+        let chunk = &compiler.function.chunk;
+
+        assert_eq!(chunk.code, [OpCode::Constant as u8, 0, OpCode::Print as u8]);
+        assert_eq!(chunk.constants.len(), 1);
+
+        match chunk.constants[0] {
+            Value::Obj(handle) => match compiler.heap.get(&handle) {
+                Some(LoxObj::Str(s)) => assert_eq!(s.value, "foobar!"),
+                obj => panic!("expected a string, got {:?}", obj),
+            },
+            value => panic!("expected a string, got {:?}", value),
+        }
+
+        let has_add = |source: &str| {
+            compile(source)
+                .unwrap()
+                .chunk
+                .code
+                .contains(&(OpCode::Add as u8))
+        };
+
+        assert!(has_add("var x = \"foo\"; print x + \"bar\";"));
+        assert!(has_add("print \"foo\" + 1;"));
+        // The left operand is the whole `or`, not just its last literal
+        assert!(has_add("var x; print (x or \"foo\") + \"bar\";"));
+    }
+
+    #[test]
+    fn test_program_limits() {
+        let compile_limited = |source: &str, limits: ProgramLimits| {
+            let mut compiler = Compiler::new(source.chars(), Heap::default());
+            compiler.set_limits(limits);
+            compiler.compile()
+        };
+
+        let limits = ProgramLimits {
+            max_code_bytes: 64,
+            max_constants: 16,
+            max_functions: 3,
+        };
+
+        // 3 bytes per statement
+        let statements = "print true;".repeat(100);
+        assert!(matches!(
+            compile_limited(&statements, limits),
+            Err(LoxError::ProgramTooLarge(ProgramLimit::CodeBytes))
+        ));
+
+        // The limit applies to the bytecode of each function separately
+        let body = "print true;".repeat(20);
+        let functions = format!("fun a() {{ {} }}\nfun b() {{ {} }}", body, body);
+        assert!(compile_limited(&functions, limits).is_ok());
+
+        let functions = "fun a() {} fun b() {} class C { c() {} d() {} }";
+        assert!(matches!(
+            compile_limited(functions, limits),
+            Err(LoxError::ProgramTooLarge(ProgramLimit::Functions))
+        ));
+
+        let constants = "print 1; print 2;".repeat(10);
+        assert!(matches!(
+            compile_limited(&constants, limits),
+            Err(LoxError::ProgramTooLarge(ProgramLimit::Constants))
+        ));
+
+        // Dead code gives its constants and functions back
+        let dead = "if (false) { print 1; print 2; fun a() {} fun b() {} }".repeat(10);
+        assert!(compile_limited(&dead, limits).is_ok());
+
+        // A normal program is well within the defaults
+        let program = "class A { f(x) { return [x, {\"k\": x}]; } }\nfun g() { return A().f(1); }\nprint g();";
+        assert!(compile_limited(program, ProgramLimits::default()).is_ok());
+    }
+
+    #[test]
+    fn test_loop_invariant_global_warnings() {
+        let warnings = |source: &str| {
+            let mut compiler = Compiler::new(source.chars(), Heap::default());
+            compiler.compile().unwrap();
+            compiler.warnings().to_vec()
+        };
+
+        let hint = |name: &str, line, column| Warning::LoopInvariantGlobal {
+            name: name.to_owned(),
+            line,
+            column,
+        };
+
+        // `i` is assigned in the loop, so only `limit` could be hoisted
+        let source = "var limit = 10;\nvar i = 0;\nwhile (i < limit) {\n  i = i + 1;\n}";
+        assert_eq!(warnings(source), [hint("limit", 3, 12)]);
+
+        let source = "var step = 2;\nfor (var i = 0; i < 10; i = i + step) {\n  print step;\n}";
+        assert_eq!(warnings(source), [hint("step", 2, 33)]);
+
+        // Any call might assign the global
+        let source =
+            "var limit = 10;\nfun f() {}\nvar i = 0;\nwhile (i < limit) {\n  f();\n  i = i + 1;\n}";
+        assert!(warnings(source).is_empty());
+
+        // A function declared in the loop runs when called, not every iteration
+        let source =
+            "var g = 1;\nvar i = 0;\nwhile (i < 3) {\n  fun f() { return g; }\n  i = i + 1;\n}";
+        assert!(warnings(source).is_empty());
+
+        // Nested loops report a global once
+        let source = "var n = 3;\nfor (var i = 0; i < 3; i = i + 1) {\n  for (var j = 0; j < n; j = j + 1) {}\n}";
+        assert_eq!(warnings(source), [hint("n", 3, 23)]);
+    }
+
+    #[test]
+    fn test_disassemble_classes() {
+        let source = r#"
+            class Doughnut {
+                finish(number) {
+                    print number;
+                }
+            }
+
+            class Cruller < Doughnut {
+                finish(number) {
+                    super.finish(number);
+                    var method = super.finish;
+                }
+            }
+
+            var cruller = Cruller();
+            cruller.finish(42);
+        "#;
+
+        fn disassemble_all(function: &ObjFunction, output: &mut String) {
+            output.push_str(&function.chunk.disassemble(None));
+            output.push('\n');
+
+            for value in &function.chunk.constants {
+                if let Value::Obj(handle) = value {
+                    if let LoxObj::Function(function) = unsafe { &*handle.ptr } {
+                        disassemble_all(function, output);
+                    }
+                }
+            }
+        }
+
+        let compiler = compile_with(source, DebugInfo::Full).unwrap();
+
+        let mut disassembly = String::new();
+        disassemble_all(&compiler.function, &mut disassembly);
+
+        let instruction = |opcode: &str| {
+            disassembly
+                .lines()
+                .find(|line| line.split_whitespace().nth(2) == Some(opcode))
+                .unwrap_or_else(|| panic!("no {} in\n{}", opcode, disassembly))
+        };
+
+        for opcode in &["Class", "Method", "Inherit", "GetSuper", "CloseUpvalue"] {
+            instruction(opcode);
+        }
+
+        // Both operands of the invocations: the method's name and the arguments
+        assert!(instruction("Invoke").ends_with("'Str(\"finish\")' (1)"));
+        assert!(instruction("SuperInvoke").ends_with("'Str(\"finish\")' (1)"));
+    }
+
+    #[test]
+    fn test_disassemble_jumps() {
+        let source = "var x = true;\nwhile (x) print 1;";
+        let compiler = compile_with(source, DebugInfo::Full).unwrap();
+        let disassembly = compiler.function.chunk.disassemble(None);
+
+        let jumps: Vec<_> = disassembly
+            .lines()
+            .filter(|line| line.contains("->"))
+            .map(|line| {
+                line.split_whitespace()
+                    .skip(2)
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect();
+
+        // The loop jumps back to the condition, which starts at byte 3
+        assert_eq!(jumps, ["JumpIfFalse 5 -> 15", "Loop 12 -> 3"]);
+    }
+
+    #[test]
+    fn test_disassemble_stack_effects() {
+        let source = "fun add(a, b) {\n  var sum = a + b;\n  print [sum, a];\n  return sum;\n}";
+
+        let compiler = compile_with(source, DebugInfo::Full).unwrap();
+        let add = first_function(&compiler.function);
+
+        // The callee's slot and both parameters are on the stack on entry
+        let disassembly = add.chunk.disassemble(Some(1 + add.arity as isize));
+
+        let annotations: Vec<(&str, isize, isize)> = disassembly
+            .lines()
+            .map(|line| {
+                let columns: Vec<_> = line.split_whitespace().collect();
+                (
+                    columns[4],
+                    columns[2].parse().unwrap(),
+                    columns[3].parse().unwrap(),
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            annotations,
+            [
+                ("GetLocal", 1, 4),
+                ("GetLocal", 1, 5),
+                ("Add", -1, 4),
+                ("GetLocal", 1, 5),
+                ("GetLocal", 1, 6),
+                ("BuildList", -1, 5),
+                ("Print", -1, 4),
+                ("GetLocal", 1, 5),
+                ("Return", -1, 4),
+                ("Nil", 1, 5),
+                ("Return", -1, 4),
+            ]
+        );
+
+        // Without an initial depth there are no annotations
+        assert!(add
+            .chunk
+            .disassemble(None)
+            .starts_with("0000    2 GetLocal"));
+    }
+}
@@ -1,7 +1,9 @@
 use crate::chunk::Chunk;
+use crate::gc::Heap;
 use crate::object::LoxObj;
 use crate::opcodes::OpCode;
 use crate::value::Value;
+use std::convert::TryFrom;
 use std::fmt;
 
 macro_rules! simple_instr {
@@ -12,11 +14,11 @@ macro_rules! simple_instr {
 }
 
 macro_rules! const_instr {
-    ($output:expr, $i:expr, $opcode:expr, $chunk:expr) => {{
+    ($output:expr, $i:expr, $opcode:expr, $chunk:expr, $show:expr) => {{
         let constant = $chunk.code[$i + 1] as usize;
-        let handle = $chunk.constants[constant];
+        let handle = $show($chunk.constants[constant]);
 
-        $output.push_str(&format!("{:12} {:4} '{:?}'\n", $opcode, constant, handle));
+        $output.push_str(&format!("{:12} {:4} '{}'\n", $opcode, constant, handle));
 
         $i += 2;
     }};
@@ -37,12 +39,14 @@ macro_rules! jump_instr {
         let mut jump = ($chunk.code[$i + 1] as u16) << 8;
         jump |= $chunk.code[$i + 2] as u16;
 
-        $output.push_str(&format!(
-            "{:12} {:4} -> {}\n",
-            $opcode,
-            $i,
-            $i + 3 + ($sign * jump) as usize
-        ));
+        // Jumps are relative to just past the operands; `Loop` goes backwards
+        let target = if $sign < 0 {
+            $i as isize + 3 - jump as isize
+        } else {
+            $i as isize + 3 + jump as isize
+        };
+
+        $output.push_str(&format!("{:12} {:4} -> {}\n", $opcode, $i, target));
 
         $i += 3;
     }};
@@ -50,24 +54,72 @@ macro_rules! jump_instr {
 
 impl fmt::Debug for Chunk {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.disassemble(None))
+    }
+}
+
+impl Chunk {
+    /// Disassembles the chunk, one instruction per line. Given the stack depth
+    /// on entry (1 + arity for a function, counting the callee's slot), each
+    /// instruction is also annotated with its stack effect and the depth after
+    /// it runs. The depth is simulated in code order, without following jumps.
+    pub fn disassemble(&self, initial_depth: Option<isize>) -> String {
+        self.disassemble_in(initial_depth, None)
+    }
+
+    /// Like `disassemble`, but looks constants up in `heap` rather than
+    /// dereferencing their handles, so freed ones show as `<freed>`.
+    pub fn disassemble_with(&self, initial_depth: Option<isize>, heap: &Heap<LoxObj>) -> String {
+        self.disassemble_in(initial_depth, Some(heap))
+    }
+
+    fn disassemble_in(&self, initial_depth: Option<isize>, heap: Option<&Heap<LoxObj>>) -> String {
+        let show = |value: Value| match heap {
+            Some(heap) => value.debug_with(heap),
+            None => format!("{:?}", value),
+        };
+
         let mut output = String::from("");
 
         let mut i = 0;
         let mut num = 0;
+        let mut depth = initial_depth;
 
         while i < self.code.len() {
-            let opcode = format!("{:?}", OpCode::from(self.code[i]));
-            let line = self.lines[i];
+            let op = OpCode::try_from(self.code[i]);
+            let opcode = match &op {
+                Ok(op) => format!("{:?}", op),
+                Err(_) => format!("Unknown {:#04x}", self.code[i]),
+            };
+
+            // Chunks compiled without debug info have no lines
+            match self.lines.get(i) {
+                Some(line) if i > 0 && Some(line) == self.lines.get(i - 1) => {
+                    output.push_str(&format!("{:04}    | ", num));
+                }
+                Some(line) => output.push_str(&format!("{:04} {:4} ", num, line)),
+                None => output.push_str(&format!("{:04}    ? ", num)),
+            }
+
+            // Without a known opcode the following bytes can't be decoded
+            let op = match op {
+                Ok(op) => op,
+                Err(_) => {
+                    output.push_str(&format!("{}\n", opcode));
+                    break;
+                }
+            };
+
+            if let Some(depth) = &mut depth {
+                let effect = op.stack_effect(&self.code[i + 1..]);
+                *depth += effect;
 
-            if i > 0 && line == self.lines[i - 1] {
-                output.push_str(&format!("{:04}    | ", num));
-            } else {
-                output.push_str(&format!("{:04} {:4} ", num, line));
+                output.push_str(&format!("{:+3} {:3} ", effect, depth));
             }
 
-            match OpCode::from(self.code[i]) {
+            match op {
                 OpCode::Return => simple_instr!(output, i, opcode),
-                OpCode::Constant => const_instr!(output, i, opcode, self),
+                OpCode::Constant => const_instr!(output, i, opcode, self, show),
                 OpCode::Negate => simple_instr!(output, i, opcode),
                 OpCode::Add | OpCode::Subtract => simple_instr!(output, i, opcode),
                 OpCode::Multiply | OpCode::Divide => simple_instr!(output, i, opcode),
@@ -76,38 +128,53 @@ impl fmt::Debug for Chunk {
                 OpCode::Equal | OpCode::Greater | OpCode::Less => simple_instr!(output, i, opcode),
                 OpCode::Print => simple_instr!(output, i, opcode),
                 OpCode::Pop => simple_instr!(output, i, opcode),
-                OpCode::DefineGlobal => const_instr!(output, i, opcode, self),
-                OpCode::GetGlobal => const_instr!(output, i, opcode, self),
-                OpCode::SetGlobal => const_instr!(output, i, opcode, self),
+                OpCode::DefineGlobal => const_instr!(output, i, opcode, self, show),
+                OpCode::DefineConst => const_instr!(output, i, opcode, self, show),
+                OpCode::GetGlobal => const_instr!(output, i, opcode, self, show),
+                OpCode::SetGlobal => const_instr!(output, i, opcode, self, show),
                 OpCode::GetLocal => byte_instr!(output, i, opcode, self),
                 OpCode::SetLocal => byte_instr!(output, i, opcode, self),
                 OpCode::JumpIfFalse => jump_instr!(output, i, opcode, 1, self),
                 OpCode::Jump => jump_instr!(output, i, opcode, 1, self),
-                OpCode::Loop => jump_instr!(output, i, opcode, 1, self),
+                OpCode::Loop => jump_instr!(output, i, opcode, -1, self),
                 OpCode::Call => byte_instr!(output, i, opcode, self),
                 OpCode::Closure => {
                     let constant = self.code[i + 1] as usize;
                     let value = self.constants[constant];
 
-                    output.push_str(&format!("{:12} {:4} {:?}\n", opcode, constant, value));
+                    output.push_str(&format!("{:12} {:4} {}\n", opcode, constant, show(value)));
 
                     i += 2;
 
+                    // Without a function the upvalue operands can't be decoded
                     let handle = match value {
                         Value::Obj(handle) => handle,
-                        _ => panic!("Unexpected value"),
+                        _ => {
+                            output.push_str("Error: closure constant isn't a function\n");
+                            break;
+                        }
                     };
 
-                    // Justification for unsafe: Without it
-                    // we need a separate function which takes the heap as an argument.
-                    let closure = unsafe {
-                        match &*handle.ptr {
-                            LoxObj::Closure(closure) => closure,
-                            _ => panic!("Unexpected value"),
+                    let obj = match heap {
+                        Some(heap) => match heap.get(&handle) {
+                            Some(obj) => obj,
+                            // The upvalue operands' count is lost with the function
+                            None => break,
+                        },
+                        // Justification for unsafe: Without a heap there's no
+                        // way to check the handle, so it's assumed live.
+                        None => unsafe { &*handle.ptr },
+                    };
+
+                    let function = match obj {
+                        LoxObj::Function(function) => function,
+                        _ => {
+                            output.push_str("Error: closure constant isn't a function\n");
+                            break;
                         }
                     };
 
-                    let upvalue_count = closure.upvalue_count;
+                    let upvalue_count = function.upvalue_count;
 
                     for _ in 0..upvalue_count {
                         let is_local = self.code[i] != 0;
@@ -122,31 +189,61 @@ impl fmt::Debug for Chunk {
                         ));
                     }
 
-                    output.push_str(&format!("----End {:?}----\n", &closure.name.unwrap()));
+                    output.push_str(&format!("----End {:?}----\n", &function.name));
                 }
                 OpCode::GetUpvalue => byte_instr!(output, i, opcode, self),
                 OpCode::SetUpvalue => byte_instr!(output, i, opcode, self),
                 OpCode::CloseUpvalue => simple_instr!(output, i, opcode),
-                OpCode::Class => const_instr!(output, i, opcode, self),
-                OpCode::GetProperty => const_instr!(output, i, opcode, self),
-                OpCode::SetProperty => const_instr!(output, i, opcode, self),
-                OpCode::Method => const_instr!(output, i, opcode, self),
+                OpCode::Class => const_instr!(output, i, opcode, self, show),
+                OpCode::GetProperty => const_instr!(output, i, opcode, self, show),
+                OpCode::SetProperty => const_instr!(output, i, opcode, self, show),
+                OpCode::Method => const_instr!(output, i, opcode, self, show),
                 OpCode::Invoke => {
                     let constant = self.code[i + 1] as usize;
                     let arg_count = self.code[i + 2] as usize;
-                    let handle = self.constants[constant];
-                    output.push_str(&format!("{:16?} ({}) {:4}\n", handle, arg_count, constant));
+                    let handle = show(self.constants[constant]);
+                    output.push_str(&format!(
+                        "{:12} {:4} '{}' ({})\n",
+                        opcode, constant, handle, arg_count
+                    ));
 
                     i += 3;
                 }
                 OpCode::Inherit => simple_instr!(output, i, opcode),
-                OpCode::GetSuper => const_instr!(output, i, opcode, self),
-                OpCode::SuperInvoke => const_instr!(output, i, opcode, self),
+                OpCode::GetSuper => const_instr!(output, i, opcode, self, show),
+                OpCode::SuperInvoke => {
+                    let constant = self.code[i + 1] as usize;
+                    let arg_count = self.code[i + 2] as usize;
+                    let handle = show(self.constants[constant]);
+                    output.push_str(&format!(
+                        "{:12} {:4} '{}' ({})\n",
+                        opcode, constant, handle, arg_count
+                    ));
+
+                    i += 3;
+                }
+                OpCode::BuildMap => byte_instr!(output, i, opcode, self),
+                OpCode::Index | OpCode::SetIndex => simple_instr!(output, i, opcode),
+                OpCode::GetIter | OpCode::IterDone | OpCode::IterNext => {
+                    simple_instr!(output, i, opcode)
+                }
+                OpCode::Field => const_instr!(output, i, opcode, self, show),
+                OpCode::BuildList => byte_instr!(output, i, opcode, self),
+                OpCode::PrivateMethod => const_instr!(output, i, opcode, self, show),
+                OpCode::Yield => simple_instr!(output, i, opcode),
+                OpCode::Assert => const_instr!(output, i, opcode, self, show),
+                OpCode::Dup => simple_instr!(output, i, opcode),
+                OpCode::BitAnd | OpCode::BitOr | OpCode::BitXor | OpCode::BitNot => {
+                    simple_instr!(output, i, opcode)
+                }
+                OpCode::ShiftLeft | OpCode::ShiftRight => simple_instr!(output, i, opcode),
             }
 
             num += 1;
         }
 
-        write!(f, "{}", &output[..output.len() - 1])
+        output.pop();
+
+        output
     }
 }
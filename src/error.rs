@@ -1,12 +1,12 @@
 use crate::token::TokenType;
-use crate::value::Value;
 
 #[derive(Debug)]
 pub enum LoxError {
     CompileError(&'static str),
     InternalCompilerError,
     RuntimeError,
-    StackOverflow,
+    // Number of frames on the call stack when it overflowed
+    StackOverflow(usize),
     StackUnderflow,
     UnexpectedToken(Option<TokenType>),
     UnexpectedEof,
@@ -17,9 +17,17 @@ pub enum LoxError {
     InternalError(Internal),
     InvalidTypeForEquals,
     ValueNotCallable,
-    UnexpectedValue(Value),
+    // An operand of the wrong type, rendered while it was alive, since the
+    // error can outlive the heap
+    UnexpectedValue(String),
+    // Name of a global read or assigned before its declaration ran
+    UndefinedVariable(String),
+    // Name of a global declared again while redefinition is an error
+    GlobalAlreadyDefined(String),
     UndefinedProperty(String),
     UndefinedMethod(String),
+    // A private method accessed from outside its class's methods
+    PrivateMethod(String),
     NonInstance,
     InvalidObject,
     InvalidField,
@@ -29,15 +37,208 @@ pub enum LoxError {
     InvalidSuper,
     InvalidUpvalue,
     InvalidArguments(&'static str),
+    // Number of parameters of a function and of arguments it was called with
+    WrongArgumentCount(usize, usize),
+    // An operand of a bitwise operator that isn't an integer
+    NotAnInteger(f64),
+    // A shift amount outside 0 to 63
+    InvalidShift(i64),
+    // Name of a const global assigned or defined again
+    ConstAssignment(String),
     InternalVmError(&'static str),
     InvalidHandle,
+    UnhashableKey,
+    NotIndexable,
+    // A foreach loop over a value that's neither a list, a string nor an
+    // instance implementing the iterator protocol
+    NotIterable,
+    // A generator resumed from its own body
+    GeneratorRunning,
+    // A list index that isn't a non-negative integer, rendered while it was
+    // alive, since the error can outlive the heap
+    InvalidIndex(String),
+    // Index and length of the list
+    IndexOutOfRange(usize, usize),
+    // Length in bytes of a string longer than the VM's limit
+    StringTooLong(usize),
+    TimedOut,
+    IoError(std::io::Error),
+    InvalidAssignmentTarget(usize),
+    // Token (and its line) found where an expression should start
+    ExpectedExpression(TokenType, usize),
+    // Token (and its line) that can't continue an expression
+    UnexpectedOperator(TokenType, usize),
+    // Line of a method-like declaration outside any class body
+    MethodOutsideClass(usize),
+    // Message and line (if known) of an explicit `panic(msg)` call
+    Panic(String, Option<usize>),
+    // Message of a failed `assert_eq` or `assert_true`
+    AssertionFailed(String),
+    // Number of tests the test harness saw fail
+    TestsFailed(usize),
+    // Which of the compiler's `ProgramLimits` the source exceeded
+    ProgramTooLarge(ProgramLimit),
+}
+
+impl LoxError {
+    /// A short code identifying the kind of error, for tools to key off
+    /// instead of matching variants or messages. Codes never change meaning
+    /// once assigned, and new variants get new codes:
+    ///
+    /// - `E0xxx`: the source failed to scan or compile
+    /// - `E1xxx`: the program failed at runtime
+    /// - `E9xxx`: the compiler or VM hit an internal inconsistency
+    pub fn code(&self) -> &'static str {
+        match self {
+            LoxError::UnexpectedToken(_) => "E0001",
+            LoxError::UnexpectedEof => "E0002",
+            LoxError::UnexpectedCharacter => "E0003",
+            LoxError::CompileError(_) => "E0004",
+            LoxError::TooManyLocalVariables => "E0005",
+            LoxError::InvalidAssignmentTarget(_) => "E0006",
+            LoxError::ExpectedExpression(..) => "E0007",
+            LoxError::UnexpectedOperator(..) => "E0008",
+            LoxError::MethodOutsideClass(_) => "E0009",
+            LoxError::ProgramTooLarge(_) => "E0010",
+            LoxError::TypeError => "E1001",
+            LoxError::InvalidTypeForAddition => "E1002",
+            LoxError::InvalidTypeForEquals => "E1003",
+            LoxError::UnexpectedValue(_) => "E1004",
+            LoxError::ValueNotCallable => "E1005",
+            LoxError::UndefinedVariable(_) => "E1006",
+            LoxError::GlobalAlreadyDefined(_) => "E1007",
+            LoxError::UndefinedProperty(_) => "E1008",
+            LoxError::UndefinedMethod(_) => "E1009",
+            LoxError::PrivateMethod(_) => "E1010",
+            LoxError::NonInstance => "E1011",
+            LoxError::InvalidField => "E1012",
+            LoxError::InvalidClass => "E1013",
+            LoxError::InvalidSuperClass => "E1014",
+            LoxError::InvalidSubClass => "E1015",
+            LoxError::InvalidSuper => "E1016",
+            LoxError::InvalidArguments(_) => "E1017",
+            LoxError::UnhashableKey => "E1018",
+            LoxError::NotIndexable => "E1019",
+            LoxError::NotIterable => "E1020",
+            LoxError::GeneratorRunning => "E1021",
+            LoxError::InvalidIndex(_) => "E1022",
+            LoxError::IndexOutOfRange(..) => "E1023",
+            LoxError::StringTooLong(_) => "E1024",
+            LoxError::StackOverflow(_) => "E1025",
+            LoxError::RuntimeError => "E1026",
+            LoxError::Panic(..) => "E1027",
+            LoxError::AssertionFailed(_) => "E1028",
+            LoxError::TestsFailed(_) => "E1029",
+            LoxError::TimedOut => "E1030",
+            LoxError::IoError(_) => "E1031",
+            LoxError::WrongArgumentCount(..) => "E1032",
+            LoxError::NotAnInteger(_) => "E1033",
+            LoxError::InvalidShift(_) => "E1034",
+            LoxError::ConstAssignment(_) => "E1035",
+            LoxError::InternalCompilerError => "E9001",
+            LoxError::InternalError(_) => "E9002",
+            LoxError::InternalVmError(_) => "E9003",
+            LoxError::InvalidHandle => "E9004",
+            LoxError::InvalidObject => "E9005",
+            LoxError::InvalidUpvalue => "E9006",
+            LoxError::StackUnderflow => "E9007",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ProgramLimit {
+    CodeBytes,
+    Constants,
+    Functions,
 }
 
 #[derive(Debug)]
 pub enum Internal {
     InvalidHandle,
-    GlobalLookupFailure,
     CorruptedStack,
+    // Opcode and stack slot of a local access outside the live stack
+    LocalOutOfRange(&'static str, usize),
 }
 
 pub type Result<T> = std::result::Result<T, LoxError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::io;
+
+    #[test]
+    fn test_error_codes() {
+        // One of each variant. Codes are part of the public interface, so this
+        // list only ever grows.
+        let snapshot = [
+            (LoxError::UnexpectedToken(None), "E0001"),
+            (LoxError::UnexpectedEof, "E0002"),
+            (LoxError::UnexpectedCharacter, "E0003"),
+            (LoxError::CompileError(""), "E0004"),
+            (LoxError::TooManyLocalVariables, "E0005"),
+            (LoxError::InvalidAssignmentTarget(1), "E0006"),
+            (
+                LoxError::ExpectedExpression(TokenType::Semicolon, 1),
+                "E0007",
+            ),
+            (
+                LoxError::UnexpectedOperator(TokenType::Semicolon, 1),
+                "E0008",
+            ),
+            (LoxError::MethodOutsideClass(1), "E0009"),
+            (LoxError::ProgramTooLarge(ProgramLimit::CodeBytes), "E0010"),
+            (LoxError::TypeError, "E1001"),
+            (LoxError::InvalidTypeForAddition, "E1002"),
+            (LoxError::InvalidTypeForEquals, "E1003"),
+            (LoxError::UnexpectedValue(String::new()), "E1004"),
+            (LoxError::ValueNotCallable, "E1005"),
+            (LoxError::UndefinedVariable(String::new()), "E1006"),
+            (LoxError::GlobalAlreadyDefined(String::new()), "E1007"),
+            (LoxError::UndefinedProperty(String::new()), "E1008"),
+            (LoxError::UndefinedMethod(String::new()), "E1009"),
+            (LoxError::PrivateMethod(String::new()), "E1010"),
+            (LoxError::NonInstance, "E1011"),
+            (LoxError::InvalidField, "E1012"),
+            (LoxError::InvalidClass, "E1013"),
+            (LoxError::InvalidSuperClass, "E1014"),
+            (LoxError::InvalidSubClass, "E1015"),
+            (LoxError::InvalidSuper, "E1016"),
+            (LoxError::InvalidArguments(""), "E1017"),
+            (LoxError::UnhashableKey, "E1018"),
+            (LoxError::NotIndexable, "E1019"),
+            (LoxError::NotIterable, "E1020"),
+            (LoxError::GeneratorRunning, "E1021"),
+            (LoxError::InvalidIndex(String::new()), "E1022"),
+            (LoxError::IndexOutOfRange(0, 0), "E1023"),
+            (LoxError::StringTooLong(0), "E1024"),
+            (LoxError::StackOverflow(0), "E1025"),
+            (LoxError::RuntimeError, "E1026"),
+            (LoxError::Panic(String::new(), None), "E1027"),
+            (LoxError::AssertionFailed(String::new()), "E1028"),
+            (LoxError::TestsFailed(1), "E1029"),
+            (LoxError::TimedOut, "E1030"),
+            (LoxError::IoError(io::Error::other("")), "E1031"),
+            (LoxError::WrongArgumentCount(0, 1), "E1032"),
+            (LoxError::NotAnInteger(0.5), "E1033"),
+            (LoxError::InvalidShift(64), "E1034"),
+            (LoxError::ConstAssignment(String::new()), "E1035"),
+            (LoxError::InternalCompilerError, "E9001"),
+            (LoxError::InternalError(Internal::CorruptedStack), "E9002"),
+            (LoxError::InternalVmError(""), "E9003"),
+            (LoxError::InvalidHandle, "E9004"),
+            (LoxError::InvalidObject, "E9005"),
+            (LoxError::InvalidUpvalue, "E9006"),
+            (LoxError::StackUnderflow, "E9007"),
+        ];
+
+        for (error, code) in &snapshot {
+            assert_eq!(error.code(), *code, "{:?}", error);
+        }
+
+        let distinct: HashSet<_> = snapshot.iter().map(|(_, code)| code).collect();
+        assert_eq!(distinct.len(), snapshot.len());
+    }
+}
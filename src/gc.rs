@@ -2,11 +2,14 @@
 
 use crate::dprintln;
 use crate::error::{LoxError, Result};
-use crate::object::LoxObj;
+use crate::object::{LoxObj, ObjKind, ObjString};
 use crate::value::{Value, ValueHandle};
+use std::borrow::Borrow;
 use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::hash::{Hash, Hasher};
+use std::mem::MaybeUninit;
+use std::ptr;
 
 //****************************************************************************
 // Handle
@@ -22,12 +25,18 @@ impl<T: fmt::Debug> fmt::Debug for Handle<T> {
     }
 }
 
+impl<T: fmt::Debug + fmt::Display> fmt::Display for Handle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        unsafe { write!(f, "{}", &*self.ptr) }
+    }
+}
+
 impl<T: fmt::Debug> Handle<T> {}
 
 impl<T: fmt::Debug> Copy for Handle<T> {}
 impl<T: fmt::Debug> Clone for Handle<T> {
     fn clone(&self) -> Self {
-        Self { ptr: self.ptr }
+        *self
     }
 }
 
@@ -45,17 +54,170 @@ impl<T: fmt::Debug> Hash for Handle<T> {
     }
 }
 
+//****************************************************************************
+// Arena
+//****************************************************************************
+
+const ARENA_CHUNK_SLOTS: usize = 1024;
+
+/// Storage for values of a single type, carved out of chunks of
+/// `ARENA_CHUNK_SLOTS` slots. Freed slots go on a free list for the next
+/// allocation to reuse, and chunks are only returned to the system allocator
+/// when the arena is dropped, without dropping any values still in them.
+pub struct Arena<T> {
+    chunks: Vec<Vec<MaybeUninit<T>>>,
+    free_slots: Vec<*mut T>,
+}
+
+impl<T> Arena<T> {
+    pub fn alloc(&mut self, value: T) -> *mut T {
+        let ptr = match self.free_slots.pop() {
+            Some(ptr) => ptr,
+            None => self.grow(),
+        };
+
+        unsafe { ptr::write(ptr, value) };
+
+        ptr
+    }
+
+    /// Drops the value at `ptr` and makes its slot available again.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by this arena's `alloc` and not freed
+    /// since.
+    pub unsafe fn free(&mut self, ptr: *mut T) {
+        ptr::drop_in_place(ptr);
+
+        self.free_slots.push(ptr);
+    }
+
+    /// Number of slots in the arena's chunks, whether used or not.
+    pub fn capacity(&self) -> usize {
+        self.chunks.len() * ARENA_CHUNK_SLOTS
+    }
+
+    // Adds a chunk, returning its first slot and putting the rest on the free
+    // list. Moving the chunk's Vec doesn't move its slots, so the pointers
+    // stay valid.
+    fn grow(&mut self) -> *mut T {
+        let mut chunk: Vec<MaybeUninit<T>> = Vec::with_capacity(ARENA_CHUNK_SLOTS);
+        chunk.resize_with(ARENA_CHUNK_SLOTS, MaybeUninit::uninit);
+
+        // Reversed so slots are handed out in address order
+        for slot in chunk[1..].iter_mut().rev() {
+            self.free_slots.push(slot.as_mut_ptr());
+        }
+
+        let first = chunk[0].as_mut_ptr();
+
+        self.chunks.push(chunk);
+
+        first
+    }
+}
+
+impl<T> Default for Arena<T> {
+    fn default() -> Self {
+        Self {
+            chunks: Vec::new(),
+            free_slots: Vec::new(),
+        }
+    }
+}
+
+/// Drops the object at `ptr` and releases its memory, either to `arena` or
+/// (without one) to the system allocator.
+///
+/// # Safety
+///
+/// `ptr` must point to a live object allocated by the heap `arena` belongs to.
+pub unsafe fn free_object<T>(arena: &mut Option<Arena<T>>, ptr: *mut T) {
+    match arena {
+        Some(arena) => arena.free(ptr),
+        None => drop(Box::from_raw(ptr)),
+    }
+}
+
 //****************************************************************************
 // Heap
 //****************************************************************************
 
+/// Called with an object and its size in bytes when it's allocated or freed.
+pub type HeapHook<T> = Box<dyn FnMut(&T, usize)>;
+
+/// Objects a heap can hold, some of which may be strings it interns.
+pub trait Internable {
+    /// The contents of the object, if it's a string.
+    fn interned_str(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// An entry of the intern table: a string object, hashed and compared by its
+/// contents so it can be looked up by a `&str` without a copy of them.
+pub struct InternedStr<T: fmt::Debug>(pub Handle<T>);
+
+impl<T: fmt::Debug + Internable> InternedStr<T> {
+    fn as_str(&self) -> &str {
+        // Justification for unsafe: entries leave the table before their
+        // objects are freed.
+        unsafe { &*self.0.ptr }.interned_str().unwrap_or_default()
+    }
+}
+
+impl<T: fmt::Debug + Internable> Hash for InternedStr<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state)
+    }
+}
+
+impl<T: fmt::Debug + Internable> PartialEq for InternedStr<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl<T: fmt::Debug + Internable> Eq for InternedStr<T> {}
+
+impl<T: fmt::Debug + Internable> Borrow<str> for InternedStr<T> {
+    fn borrow(&self) -> &str {
+        self.as_str()
+    }
+}
+
 pub struct Heap<T: fmt::Debug> {
     pub objects: HashSet<Handle<T>>,
+    pub on_alloc: Option<HeapHook<T>>,
+    pub on_free: Option<HeapHook<T>>,
+    // Where objects are allocated, if not individually boxed
+    pub arena: Option<Arena<T>>,
+    // Every live string, looked up by its contents, so equal strings share
+    // one object. Entries don't keep their strings alive: the GC drops the
+    // ones it frees.
+    pub strings: HashSet<InternedStr<T>>,
 }
 
 impl<T: fmt::Debug> Heap<T> {
+    /// A heap allocating its objects from an `Arena` instead of boxing each
+    /// one, which saves allocator calls for programs creating many objects.
+    pub fn with_arena() -> Self {
+        Self {
+            objects: HashSet::new(),
+            on_alloc: None,
+            on_free: None,
+            arena: Some(Arena::default()),
+            strings: HashSet::new(),
+        }
+    }
+
     pub fn insert(&mut self, value: T) -> Handle<T> {
-        let ptr = Box::into_raw(Box::new(value));
+        if let Some(hook) = &mut self.on_alloc {
+            hook(&value, std::mem::size_of::<T>());
+        }
+
+        let ptr = self.alloc_ptr(value);
 
         let handle = Handle { ptr };
 
@@ -76,6 +238,7 @@ impl<T: fmt::Debug> Heap<T> {
         }
     }
 
+    #[allow(clippy::mut_from_ref)]
     pub fn get_mut(&self, handle: &Handle<T>) -> Option<&mut T> {
         if self.contains(handle) {
             Some(unsafe { &mut *handle.ptr })
@@ -86,20 +249,119 @@ impl<T: fmt::Debug> Heap<T> {
 
     pub fn set(&mut self, handle: &mut Handle<T>, value: T) {
         if self.contains(handle) {
-            handle.ptr = Box::into_raw(Box::new(value));
+            handle.ptr = self.alloc_ptr(value);
+        }
+    }
+
+    /// Frees every object, leaving an empty heap ready for reuse. The free
+    /// hook is called for each object.
+    ///
+    /// Every handle obtained from this heap is invalidated. Dereferencing its
+    /// `ptr` is undefined behaviour, and since addresses get reused it may
+    /// even look valid to `contains` once new objects are inserted. Callers
+    /// must drop anything that still holds one (the VM's stack, globals, etc.)
+    /// before running code against the heap again.
+    pub fn clear(&mut self) {
+        self.strings.clear();
+
+        for handle in self.objects.drain() {
+            if let Some(hook) = &mut self.on_free {
+                hook(unsafe { &*handle.ptr }, std::mem::size_of::<T>());
+            }
+
+            unsafe { free_object(&mut self.arena, handle.ptr) };
         }
     }
 
+    fn alloc_ptr(&mut self, value: T) -> *mut T {
+        match &mut self.arena {
+            Some(arena) => arena.alloc(value),
+            None => Box::into_raw(Box::new(value)),
+        }
+    }
+}
+
+impl<T: fmt::Debug + Internable> Heap<T> {
     pub fn remove(&mut self, handle: Handle<T>) {
         let res = self.objects.remove(&handle);
-        debug_assert!(!res, "Attempted to remove handle not in heap.");
+        debug_assert!(res, "Attempted to remove handle not in heap.");
+
+        if res {
+            // Only the interned copy of a string is in the table
+            if let Some(value) = unsafe { &*handle.ptr }.interned_str() {
+                if self
+                    .strings
+                    .get(value)
+                    .is_some_and(|interned| interned.0 == handle)
+                {
+                    self.strings.remove(value);
+                }
+            }
+
+            if let Some(hook) = &mut self.on_free {
+                hook(unsafe { &*handle.ptr }, std::mem::size_of::<T>());
+            }
+
+            unsafe { free_object(&mut self.arena, handle.ptr) };
+        }
+    }
+}
+
+impl Internable for LoxObj {
+    fn interned_str(&self) -> Option<&str> {
+        match self {
+            LoxObj::Str(obj) => Some(&obj.value),
+            _ => None,
+        }
+    }
+}
+
+impl Heap<LoxObj> {
+    /// Registers `hook` to be called with the kind and size of every object
+    /// allocated from now on.
+    pub fn set_alloc_hook<F>(&mut self, mut hook: F)
+    where
+        F: FnMut(ObjKind, usize) + 'static,
+    {
+        self.on_alloc = Some(Box::new(move |obj: &LoxObj, size| hook(obj.kind(), size)));
+    }
+
+    /// Registers `hook` to be called with the kind and size of every object
+    /// freed from now on.
+    pub fn set_free_hook<F>(&mut self, mut hook: F)
+    where
+        F: FnMut(ObjKind, usize) + 'static,
+    {
+        self.on_free = Some(Box::new(move |obj: &LoxObj, size| hook(obj.kind(), size)));
+    }
+
+    /// Returns the string object holding `value`, if there is one.
+    pub fn interned(&self, value: &str) -> Option<ValueHandle> {
+        self.strings.get(value).map(|interned| interned.0)
+    }
+
+    /// Returns the string object holding `value`, inserting and interning a
+    /// new one if there isn't one yet.
+    pub fn intern(&mut self, value: String) -> ValueHandle {
+        if let Some(handle) = self.interned(&value) {
+            return handle;
+        }
+
+        let handle = self.insert(LoxObj::Str(Box::from(ObjString {
+            value,
+            is_marked: false,
+        })));
+
+        self.strings.insert(InternedStr(handle));
+
+        handle
     }
 }
 
 impl<T: fmt::Debug> Drop for Heap<T> {
     fn drop(&mut self) {
         for handle in &self.objects {
-            drop(unsafe { Box::from_raw(handle.ptr) });
+            unsafe { free_object(&mut self.arena, handle.ptr) };
         }
     }
 }
@@ -108,6 +370,10 @@ impl<T: fmt::Debug> Default for Heap<T> {
     fn default() -> Self {
         Self {
             objects: HashSet::new(),
+            on_alloc: None,
+            on_free: None,
+            arena: None,
+            strings: HashSet::new(),
         }
     }
 }
@@ -117,10 +383,10 @@ impl<T: fmt::Debug> Default for Heap<T> {
 //****************************************************************************
 
 macro_rules! mark {
-    ($obj:expr, $gray_stack:expr, $handle:expr) => {{
+    ($obj:expr, $gray_stack:expr, $handle:expr, $heap:expr) => {{
         if !$obj.is_marked {
             $obj.is_marked = true;
-            dprintln!("Marking {:?}", $handle);
+            dprintln!("Marking {}", Value::Obj(*$handle).debug_with($heap));
             $gray_stack.push(*$handle);
         }
     }};
@@ -131,13 +397,19 @@ pub fn mark_object(
     gray_stack: &mut Vec<ValueHandle>,
     handle: &ValueHandle,
 ) -> Result<()> {
-    match heap.get_mut(&handle).ok_or(LoxError::InvalidHandle)? {
-        LoxObj::Closure(obj) => mark!(obj, gray_stack, handle),
-        LoxObj::Str(obj) => mark!(obj, gray_stack, handle),
-        LoxObj::Upvalue(obj) => mark!(obj, gray_stack, handle),
-        LoxObj::Class(obj) => mark!(obj, gray_stack, handle),
-        LoxObj::Instance(obj) => mark!(obj, gray_stack, handle),
-        LoxObj::BoundMethod(obj) => mark!(obj, gray_stack, handle),
+    match heap.get_mut(handle).ok_or(LoxError::InvalidHandle)? {
+        LoxObj::Function(obj) => mark!(obj, gray_stack, handle, heap),
+        LoxObj::Closure(obj) => mark!(obj, gray_stack, handle, heap),
+        LoxObj::Str(obj) => mark!(obj, gray_stack, handle, heap),
+        LoxObj::Upvalue(obj) => mark!(obj, gray_stack, handle, heap),
+        LoxObj::Class(obj) => mark!(obj, gray_stack, handle, heap),
+        LoxObj::Instance(obj) => mark!(obj, gray_stack, handle, heap),
+        LoxObj::BoundMethod(obj) => mark!(obj, gray_stack, handle, heap),
+        LoxObj::Map(obj) => mark!(obj, gray_stack, handle, heap),
+        LoxObj::List(obj) => mark!(obj, gray_stack, handle, heap),
+        LoxObj::Native(obj) => mark!(obj, gray_stack, handle, heap),
+        LoxObj::Iterator(obj) => mark!(obj, gray_stack, handle, heap),
+        LoxObj::Generator(obj) => mark!(obj, gray_stack, handle, heap),
     }
 
     Ok(())
@@ -161,6 +433,8 @@ pub fn mark_table(
 mod tests {
     use super::*;
 
+    impl Internable for Vec<usize> {}
+
     #[test]
     fn test_multi_mut() {
         let mut heap: Heap<Vec<usize>> = Heap::default();
@@ -177,8 +451,79 @@ mod tests {
 
         heap.remove(handle);
 
-        assert_eq!(heap.contains(&handle), false);
+        assert!(!heap.contains(&handle));
 
         assert_eq!(heap.get(&handle), None);
     }
+
+    #[test]
+    fn test_remove_interned() {
+        let mut heap = Heap::default();
+
+        let interned = heap.intern(String::from("lox"));
+        let copy = heap.insert(LoxObj::Str(Box::from(ObjString {
+            value: String::from("lox"),
+            is_marked: false,
+        })));
+
+        // Only the interned string's removal takes it out of the table
+        heap.remove(copy);
+        assert_eq!(heap.interned("lox"), Some(interned));
+
+        heap.remove(interned);
+        assert_eq!(heap.interned("lox"), None);
+        assert!(heap.strings.is_empty());
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut heap: Heap<Vec<usize>> = Heap::default();
+
+        let handles: Vec<_> = (0..5).map(|i| heap.insert(vec![i])).collect();
+
+        heap.clear();
+
+        assert!(heap.objects.is_empty());
+        assert!(handles.iter().all(|handle| !heap.contains(handle)));
+
+        let handle = heap.insert(vec![42]);
+
+        assert_eq!(heap.get(&handle), Some(&vec![42]));
+        assert_eq!(heap.objects.len(), 1);
+    }
+
+    #[test]
+    fn test_arena() {
+        let mut heap: Heap<Vec<usize>> = Heap::with_arena();
+
+        for round in 0..10 {
+            let handles: Vec<_> = (0..2000).map(|i| heap.insert(vec![round, i])).collect();
+
+            // Free every other object, keeping the rest across the next round
+            for (i, handle) in handles.iter().enumerate() {
+                if i % 2 == 0 {
+                    heap.remove(*handle);
+                } else {
+                    assert_eq!(heap.get(handle), Some(&vec![round, i]));
+                }
+            }
+        }
+
+        assert_eq!(heap.objects.len(), 10_000);
+
+        for handle in &heap.objects {
+            let obj = unsafe { &*handle.ptr };
+            assert_eq!(obj[1] % 2, 1);
+        }
+
+        // Freed slots were reused rather than growing the arena every round
+        let capacity = heap.arena.as_ref().unwrap().capacity();
+        assert!(capacity < 20_000, "capacity {}", capacity);
+
+        heap.clear();
+
+        let handle = heap.insert(vec![42]);
+
+        assert_eq!(heap.get(&handle), Some(&vec![42]));
+    }
 }
@@ -4,14 +4,29 @@ pub mod compiler;
 mod debug;
 mod error;
 mod gc;
+mod natives;
 mod object;
 mod opcodes;
+mod rng;
 mod scanner;
 mod token;
 mod value;
 pub mod vm;
 
+pub use error::LoxError;
+pub use object::NativeFn;
+pub use value::Value;
+
 pub fn interpret(source: String) -> Result<(), error::LoxError> {
+    interpret_with(source, |_| ())
+}
+
+/// Like `interpret`, but hands the VM to `setup` before running the script,
+/// e.g. to register the host's natives with `Vm::define_native`.
+pub fn interpret_with<F>(source: String, setup: F) -> Result<(), error::LoxError>
+where
+    F: FnOnce(&mut vm::Vm),
+{
     let heap = gc::Heap::default();
 
     let mut compiler = compiler::Compiler::new(source.chars(), heap);
@@ -19,6 +34,82 @@ pub fn interpret(source: String) -> Result<(), error::LoxError> {
     compiler.compile()?;
 
     let mut vm = vm::Vm::new(compiler.heap);
+    setup(&mut vm);
+
+    vm.interpret(Box::from(compiler.function))
+}
+
+/// A VM kept between scripts, so each one sees the globals defined by those
+/// evaluated before it, e.g. for a REPL.
+pub struct Session {
+    pub vm: vm::Vm,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self {
+            vm: vm::Vm::new(gc::Heap::default()),
+        }
+    }
+
+    /// Compiles and runs `source` in the session. After an error the session
+    /// can carry on, keeping any globals the failed script had defined.
+    pub fn eval(&mut self, source: &str) -> Result<(), error::LoxError> {
+        self.vm.eval(source)
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Compiles `source` without running it, for linting. Its warnings and, if it
+/// doesn't compile, its error are formatted as `line:column: severity:
+/// message`, and returned as `Err` if there was an error.
+pub fn check(source: String) -> Result<Vec<String>, Vec<String>> {
+    let heap = gc::Heap::default();
+
+    let mut compiler = compiler::Compiler::new(source.chars(), heap);
+
+    let compiled = compiler.compile();
+
+    let mut diagnostics: Vec<_> = compiler
+        .warnings()
+        .iter()
+        .map(|warning| {
+            let (line, column) = warning.position();
+            format!("{}:{}: warning: {}", line, column, warning)
+        })
+        .collect();
+
+    match compiled {
+        Ok(()) => Ok(diagnostics),
+        Err(e) => {
+            diagnostics.push(format!(
+                "{}:{}: error: {:?}",
+                compiler.line,
+                compiler.column(),
+                e
+            ));
+
+            Err(diagnostics)
+        }
+    }
+}
+
+/// Like `interpret`, but with the test harness installed, so it fails if any
+/// of the script's tests do.
+pub fn interpret_tests(source: String) -> Result<(), error::LoxError> {
+    let heap = gc::Heap::default();
+
+    let mut compiler = compiler::Compiler::new(source.chars(), heap);
+
+    compiler.compile()?;
+
+    let mut vm = vm::Vm::new(compiler.heap);
+    vm.install_test_harness();
 
     vm.interpret(Box::from(compiler.function))
 }
@@ -27,6 +118,110 @@ pub fn interpret(source: String) -> Result<(), error::LoxError> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_malformed_input() {
+        let deep_parens = format!("print {}1;", "(".repeat(100_000));
+        let deep_negation = format!("print {}1;", "-".repeat(100_000));
+        let deep_blocks = "{".repeat(100_000);
+        let deep_ifs = "if (x) ".repeat(100_000);
+        let deep_functions = "fun f() {".repeat(10_000);
+
+        let corpus = [
+            "+",
+            "*;",
+            "!",
+            "print ==;",
+            "1 +",
+            "{",
+            "}",
+            "{ print 1;",
+            "(1));",
+            "[1, 2",
+            "{\"k\": }",
+            "print 1 @ 2;",
+            "var ~ = 1;",
+            "\"unterminated",
+            "print 99999999999999999999999999999999999999 nil;",
+            "[1][99999999999999999999];",
+            "this;",
+            "super.x;",
+            "return 1;",
+            "class A < A {}",
+            "fun f() { f(); } f();",
+            "fun g() { for (x in g()) yield x; } for (y in g()) print y;",
+            "1 - \"a\";",
+            "[1][\"x\"];",
+            &deep_parens,
+            &deep_negation,
+            &deep_blocks,
+            &deep_ifs,
+            &deep_functions,
+        ];
+
+        for source in &corpus {
+            match interpret(source.to_string()) {
+                // Formatted after the VM is gone, as the CLI reports it
+                Err(e) => assert!(!format!("{:?}", e).is_empty()),
+                Ok(()) => panic!("{:.40} didn't fail", source),
+            }
+        }
+    }
+
+    #[test]
+    fn test_error_outlives_heap() {
+        // The VM and its heap are gone by the time the error is formatted
+        for source in &["print [1][\"x\"];", "var l = [1]; l[\"k\"] = 2;"] {
+            let e = interpret(source.to_string()).unwrap_err();
+            assert!(
+                format!("{:?}", e).starts_with("InvalidIndex("),
+                "{}",
+                source
+            );
+        }
+
+        let e = interpret(String::from("print [1][\"x\"];")).unwrap_err();
+        assert_eq!(format!("{:?}", e), "InvalidIndex(\"x\")");
+    }
+
+    #[test]
+    fn test_session() {
+        use std::cell::RefCell;
+        use std::io::{self, Write};
+        use std::rc::Rc;
+
+        #[derive(Clone, Default)]
+        struct Output(Rc<RefCell<Vec<u8>>>);
+
+        impl Write for Output {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.borrow_mut().write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let output = Output::default();
+        let mut session = Session {
+            vm: vm::Vm::with_writer(gc::Heap::default(), Box::new(output.clone())),
+        };
+
+        session.eval("var x = 1;").unwrap();
+        session.eval("fun next() { x = x + 1; return x; }").unwrap();
+        session.eval("print next();").unwrap();
+
+        // A failed script leaves the session usable
+        assert!(session.eval("print next(); nil + 1;").is_err());
+        assert!(session.eval("print;").is_err());
+
+        session.eval("var y = next();").unwrap();
+        session.eval("print x + y;").unwrap();
+
+        let text = String::from_utf8(output.0.borrow().clone()).unwrap();
+        assert_eq!(text, "2\n3\n8\n");
+    }
+
     #[test]
     fn test_sandbox() {
         use crate::gc::Heap;
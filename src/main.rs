@@ -1,14 +1,90 @@
-use rslox::interpret;
+use rslox::{check, Session};
 use std::env;
 use std::fs;
+use std::process;
+
+const USAGE: &str = "usage: rslox [--test | --check] (<file> | --eval <code>)";
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut test_mode = false;
+    let mut check_mode = false;
+    let mut eval = None;
+    let mut filepath = None;
+
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            // Runs the source with the test harness installed
+            "--test" => test_mode = true,
+            // Only compiles the source, reporting its errors and warnings
+            "--check" => check_mode = true,
+            // Runs the following argument as source instead of reading a file
+            "--eval" => match args.next() {
+                Some(code) => eval = Some(code),
+                None => usage_error("--eval needs the code to run"),
+            },
+            _ if filepath.is_none() => filepath = Some(arg),
+            _ => usage_error(&format!("unexpected argument `{}`", arg)),
+        }
+    }
+
+    if test_mode && check_mode {
+        usage_error("--test can't be combined with --check");
+    }
+
+    let source = match (eval, filepath) {
+        (Some(_), Some(_)) => usage_error("--eval can't be combined with a file"),
+        (Some(code), None) => code,
+        (None, Some(filepath)) => {
+            if !check_mode {
+                println!("{}", filepath);
+            }
+
+            match fs::read_to_string(&filepath) {
+                Ok(source) => source,
+                Err(e) => {
+                    eprintln!("error: can't read {}: {}", filepath, e);
+                    process::exit(66);
+                }
+            }
+        }
+        (None, None) => usage_error("no file or --eval code given"),
+    };
+
+    if check_mode {
+        let (diagnostics, failed) = match check(source) {
+            Ok(diagnostics) => (diagnostics, false),
+            Err(diagnostics) => (diagnostics, true),
+        };
 
-    let filepath = &args[1];
-    println!("{}", filepath);
+        for diagnostic in diagnostics {
+            eprintln!("{}", diagnostic);
+        }
 
-    let source = fs::read_to_string(filepath).unwrap();
+        process::exit(if failed { 65 } else { 0 });
+    }
+
+    let mut session = Session::new();
+
+    if test_mode {
+        session.vm.install_test_harness();
+    }
+
+    // Runtime errors are reported with their line and a trace of the calls
+    session.vm.set_report_errors(true);
+
+    if let Err(e) = session.eval(&source) {
+        // Only errors raised while the script ran have been reported
+        if session.vm.error_line().is_none() {
+            eprintln!("error: {:?}", e);
+        }
+
+        process::exit(70);
+    }
+}
 
-    interpret(source).unwrap();
+fn usage_error(message: &str) -> ! {
+    eprintln!("error: {}\n{}", message, USAGE);
+    process::exit(64);
 }
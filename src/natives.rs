@@ -0,0 +1,358 @@
+use crate::error::{LoxError, Result};
+use crate::object::LoxObj;
+use crate::value::{MapKey, Value};
+use crate::vm::Vm;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// `write(value)`: writes `value` to the VM's output without a trailing newline.
+pub fn write(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    write!(vm.writer(), "{}", args[0]).map_err(LoxError::IoError)?;
+
+    Ok(Value::Nil)
+}
+
+/// `substring(s, start, end)`: the characters of `s` from `start` up to (but
+/// not including) `end`. Indices count Unicode scalar values and `end` is
+/// clamped to the length of `s`.
+pub fn substring(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let s = vm
+        .get_str(args[0])
+        .ok_or(LoxError::InvalidArguments("substring expects a string"))?;
+
+    let start = expect_index(args[1])?;
+    let end = expect_index(args[2])?;
+
+    let len = s.chars().count();
+
+    if start > len {
+        return Err(LoxError::InvalidArguments("substring start out of range"));
+    }
+
+    let end = end.min(len);
+
+    let value = if start >= end {
+        String::new()
+    } else {
+        s.chars().skip(start).take(end - start).collect()
+    };
+
+    vm.new_string(value)
+}
+
+fn expect_index(value: Value) -> Result<usize> {
+    match value {
+        Value::Number(n) if n >= 0.0 && n.fract() == 0.0 => Ok(n as usize),
+        _ => Err(LoxError::InvalidArguments(
+            "expected a non-negative integer index",
+        )),
+    }
+}
+
+/// `len(x)`: the number of characters in a string, items in a list, or
+/// entries in a map.
+pub fn len(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let len = match get_obj(vm, args[0]) {
+        Some(LoxObj::Str(s)) => s.value.chars().count(),
+        Some(LoxObj::List(list)) => list.items.len(),
+        Some(LoxObj::Map(map)) => map.entries.len(),
+        _ => {
+            return Err(LoxError::InvalidArguments(
+                "len expects a string, list or map",
+            ))
+        }
+    };
+
+    Ok(Value::Number(len as f64))
+}
+
+/// `contains(collection, item)`: whether a list has an item equal to `item`,
+/// or a map has `item` as a key.
+pub fn contains(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let found = match get_obj(vm, args[0]) {
+        Some(LoxObj::List(list)) => list
+            .items
+            .iter()
+            .any(|&item| vm.values_equal(item, args[1])),
+        Some(LoxObj::Map(map)) => {
+            let key = match args[1] {
+                Value::Number(n) => Some(MapKey::number(n)),
+                value => vm.get_str(value).map(|s| MapKey::Str(s.to_owned())),
+            };
+
+            // Values that can't be keys are in no map
+            key.is_some_and(|key| map.entries.contains_key(&key))
+        }
+        _ => return Err(LoxError::InvalidArguments("contains expects a list or map")),
+    };
+
+    Ok(Value::Bool(found))
+}
+
+/// `sorted_keys(map)`: the map's string keys in lexicographic order. Number
+/// keys are left out.
+pub fn sorted_keys(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let mut keys: Vec<String> = match get_obj(vm, args[0]) {
+        Some(LoxObj::Map(map)) => map
+            .entries
+            .keys()
+            .filter_map(|key| match key {
+                MapKey::Str(s) => Some(s.clone()),
+                MapKey::Num(_) => None,
+            })
+            .collect(),
+        _ => return Err(LoxError::InvalidArguments("sorted_keys expects a map")),
+    };
+
+    keys.sort();
+
+    vm.new_string_list(keys)
+}
+
+/// `push(list, item)`: appends `item` to `list`.
+///
+/// Loops over a list read it through an index on every iteration, so pushing
+/// or popping inside one is safe, though the loop sees the new length.
+pub fn push(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    list_mut(vm, args[0], "push expects a list")?.push(args[1]);
+
+    Ok(Value::Nil)
+}
+
+/// `append(list, item)`: the same as `push`.
+pub fn append(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    list_mut(vm, args[0], "append expects a list")?.push(args[1]);
+
+    Ok(Value::Nil)
+}
+
+/// `pop(list)`: removes and returns the last item of `list`.
+pub fn pop(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    list_mut(vm, args[0], "pop expects a list")?
+        .pop()
+        .ok_or(LoxError::InvalidArguments("pop from an empty list"))
+}
+
+/// `clone(instance)`: a new instance of the same class with the same fields.
+/// The copy is shallow, so fields holding objects share them with the
+/// original.
+pub fn clone(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let (class, fields) = match get_obj(vm, args[0]) {
+        Some(LoxObj::Instance(instance)) => (
+            Value::Obj(instance.class),
+            instance
+                .fields
+                .iter()
+                .map(|(name, value)| (name.clone(), *value))
+                .collect(),
+        ),
+        _ => return Err(LoxError::InvalidArguments("clone expects an instance")),
+    };
+
+    vm.new_instance(class, fields)
+}
+
+/// `is_number(value)`: whether `value` is a number.
+pub fn is_number(_vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    Ok(Value::Bool(matches!(args[0], Value::Number(_))))
+}
+
+/// `is_string(value)`: whether `value` is a string.
+pub fn is_string(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    Ok(Value::Bool(matches!(
+        get_obj(vm, args[0]),
+        Some(LoxObj::Str(_))
+    )))
+}
+
+/// `is_bool(value)`: whether `value` is `true` or `false`.
+pub fn is_bool(_vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    Ok(Value::Bool(matches!(args[0], Value::Bool(_))))
+}
+
+/// `is_nil(value)`: whether `value` is nil.
+pub fn is_nil(_vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    Ok(Value::Bool(matches!(args[0], Value::Nil)))
+}
+
+/// `is_instance(value)`: whether `value` is an instance of some class.
+pub fn is_instance(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    Ok(Value::Bool(matches!(
+        get_obj(vm, args[0]),
+        Some(LoxObj::Instance(_))
+    )))
+}
+
+/// `is_class(value)`: whether `value` is a class.
+pub fn is_class(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    Ok(Value::Bool(matches!(
+        get_obj(vm, args[0]),
+        Some(LoxObj::Class(_))
+    )))
+}
+
+/// `is_callable(value)`: whether calling `value` can succeed, i.e. it's a
+/// function, native, class or bound method.
+pub fn is_callable(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    Ok(Value::Bool(matches!(
+        get_obj(vm, args[0]),
+        Some(LoxObj::Closure(_) | LoxObj::Native(_) | LoxObj::Class(_) | LoxObj::BoundMethod(_))
+    )))
+}
+
+/// `clock()`: seconds since the Unix epoch, with a fractional part, for timing
+/// scripts.
+pub fn clock(_vm: &mut Vm, _args: &[Value]) -> Result<Value> {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |elapsed| elapsed.as_secs_f64());
+
+    Ok(Value::Number(seconds))
+}
+
+/// `random()`: a number in `[0, 1)`.
+pub fn random(vm: &mut Vm, _args: &[Value]) -> Result<Value> {
+    Ok(Value::Number(vm.rng().next_f64()))
+}
+
+/// `random_int(lo, hi)`: an integer between `lo` and `hi`, inclusive.
+pub fn random_int(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let (lo, hi) = match (args[0], args[1]) {
+        (Value::Number(lo), Value::Number(hi)) if lo.fract() == 0.0 && hi.fract() == 0.0 => {
+            (lo, hi)
+        }
+        _ => {
+            return Err(LoxError::InvalidArguments(
+                "random_int expects integer bounds",
+            ))
+        }
+    };
+
+    if lo > hi {
+        return Err(LoxError::InvalidArguments(
+            "random_int lower bound is above upper bound",
+        ));
+    }
+
+    let n = lo + (vm.rng().next_f64() * (hi - lo + 1.0)).floor();
+
+    Ok(Value::Number(n))
+}
+
+/// `readline()`: the next line of input without its line ending, or nil at the
+/// end of input.
+pub fn readline(vm: &mut Vm, _args: &[Value]) -> Result<Value> {
+    match read_line(vm)? {
+        Some(line) => vm.new_string(line),
+        None => Ok(Value::Nil),
+    }
+}
+
+/// `input_number()`: the next line of input parsed as a number, ignoring
+/// surrounding whitespace. Nil if it isn't one or at the end of input.
+pub fn input_number(vm: &mut Vm, _args: &[Value]) -> Result<Value> {
+    let number = read_line(vm)?.and_then(|line| line.trim().parse().ok());
+
+    Ok(number.map_or(Value::Nil, Value::Number))
+}
+
+fn read_line(vm: &mut Vm) -> Result<Option<String>> {
+    // Show any prompt the script printed before blocking on input
+    vm.writer().flush().map_err(LoxError::IoError)?;
+
+    let mut line = String::new();
+
+    if vm
+        .reader()
+        .read_line(&mut line)
+        .map_err(LoxError::IoError)?
+        == 0
+    {
+        return Ok(None);
+    }
+
+    if line.ends_with('\n') {
+        line.pop();
+
+        if line.ends_with('\r') {
+            line.pop();
+        }
+    }
+
+    Ok(Some(line))
+}
+
+fn get_obj(vm: &Vm, value: Value) -> Option<&LoxObj> {
+    match value {
+        Value::Obj(handle) => vm.heap.get(&handle),
+        _ => None,
+    }
+}
+
+fn list_mut<'a>(vm: &'a mut Vm, value: Value, message: &'static str) -> Result<&'a mut Vec<Value>> {
+    let obj = match value {
+        Value::Obj(handle) => vm.heap.get_mut(&handle),
+        _ => None,
+    };
+
+    match obj {
+        Some(LoxObj::List(list)) => Ok(&mut list.items),
+        _ => Err(LoxError::InvalidArguments(message)),
+    }
+}
+
+/// `panic(msg)`: aborts the script with `msg` (stringified if it isn't a string).
+pub fn panic(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    Err(LoxError::Panic(args[0].to_string(), vm.current_line()))
+}
+
+/// `assert_eq(a, b)`: fails unless `a` and `b` are equal, comparing strings by
+/// content and other objects by identity.
+pub fn assert_eq(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    if vm.values_equal(args[0], args[1]) {
+        Ok(Value::Nil)
+    } else {
+        Err(LoxError::AssertionFailed(format!(
+            "assert_eq failed: {} != {}",
+            args[0], args[1]
+        )))
+    }
+}
+
+/// `assert_true(x)`: fails if `x` is falsey.
+pub fn assert_true(_vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    if args[0].is_falsey() {
+        Err(LoxError::AssertionFailed(format!(
+            "assert_true failed: got {}",
+            args[0]
+        )))
+    } else {
+        Ok(Value::Nil)
+    }
+}
+
+/// `test(name, f)`: calls `f` and records whether it passed, i.e. returned
+/// without a runtime error. Timeouts and I/O errors aren't test failures and
+/// abort the script as usual.
+pub fn test(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+    let name = vm
+        .get_str(args[0])
+        .ok_or(LoxError::InvalidArguments("test expects a name"))?
+        .to_owned();
+
+    let outcome = match vm.call(args[1], &[]) {
+        Ok(_) => Ok(()),
+        Err(e @ LoxError::TimedOut) | Err(e @ LoxError::IoError(_)) => return Err(e),
+        Err(LoxError::AssertionFailed(message)) => Err(message),
+        Err(e) => Err(format!("{:?}", e)),
+    };
+
+    match &outcome {
+        Ok(()) => writeln!(vm.writer(), "test {} ... ok", name),
+        Err(message) => writeln!(vm.writer(), "test {} ... FAILED: {}", name, message),
+    }
+    .map_err(LoxError::IoError)?;
+
+    vm.record_test(outcome.is_ok());
+
+    Ok(Value::Nil)
+}
@@ -1,18 +1,90 @@
 use crate::chunk::Chunk;
-use crate::value::{Value, ValueHandle};
-use std::collections::HashMap;
+use crate::error::Result;
+use crate::value::{MapKey, Value, ValueHandle};
+use crate::vm::Vm;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 
 const EXPAND_CLOSURES: bool = false;
 
+thread_local! {
+    // Addresses of the lists and maps being formatted, outermost first, so a
+    // collection containing itself prints as `[...]` or `{...}`
+    static FORMATTING: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
 #[derive(Debug)]
 pub enum LoxObj {
     Str(Box<ObjString>),
+    Function(Box<ObjFunction>),
     Closure(Box<ObjClosure>),
     Upvalue(Box<ObjUpvalue>),
     Class(Box<ObjClass>),
     Instance(Box<ObjInstance>),
     BoundMethod(Box<ObjBoundMethod>),
+    Map(Box<ObjMap>),
+    List(Box<ObjList>),
+    Native(Box<ObjNative>),
+    Iterator(Box<ObjIterator>),
+    Generator(Box<ObjGenerator>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ObjKind {
+    Str,
+    Function,
+    Closure,
+    Upvalue,
+    Class,
+    Instance,
+    BoundMethod,
+    Map,
+    List,
+    Native,
+    Iterator,
+    Generator,
+}
+
+impl LoxObj {
+    pub fn kind(&self) -> ObjKind {
+        match self {
+            LoxObj::Str(_) => ObjKind::Str,
+            LoxObj::Function(_) => ObjKind::Function,
+            LoxObj::Closure(_) => ObjKind::Closure,
+            LoxObj::Upvalue(_) => ObjKind::Upvalue,
+            LoxObj::Class(_) => ObjKind::Class,
+            LoxObj::Instance(_) => ObjKind::Instance,
+            LoxObj::BoundMethod(_) => ObjKind::BoundMethod,
+            LoxObj::Map(_) => ObjKind::Map,
+            LoxObj::List(_) => ObjKind::List,
+            LoxObj::Native(_) => ObjKind::Native,
+            LoxObj::Iterator(_) => ObjKind::Iterator,
+            LoxObj::Generator(_) => ObjKind::Generator,
+        }
+    }
+}
+
+impl fmt::Display for LoxObj {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoxObj::Str(s) => write!(f, "{}", &s.value),
+            LoxObj::Function(function) => match function.name {
+                Some(name) => write!(f, "<fn {}>", name),
+                None => write!(f, "<script>"),
+            },
+            LoxObj::Closure(closure) => write!(f, "{}", closure.function),
+            LoxObj::Class(class) => write!(f, "{:?}", class),
+            LoxObj::Instance(instance) => write!(f, "{:?}", instance),
+            LoxObj::Map(map) => write!(f, "{:?}", map),
+            LoxObj::List(list) => write!(f, "{:?}", list),
+            LoxObj::BoundMethod(method) => write!(f, "{:?}", method),
+            LoxObj::Native(native) => write!(f, "{:?}", native),
+            LoxObj::Iterator(iterator) => write!(f, "{:?}", iterator),
+            LoxObj::Generator(generator) => write!(f, "{:?}", generator),
+            obj => write!(f, "{:?}", obj),
+        }
+    }
 }
 
 pub struct ObjString {
@@ -26,21 +98,23 @@ impl fmt::Debug for ObjString {
     }
 }
 
-pub struct ObjClosure {
+/// A compiled function. It's shared by every closure created from the same
+/// declaration, so it holds nothing specific to a single evaluation.
+pub struct ObjFunction {
     pub arity: usize,
     pub chunk: Chunk,
     // Lox String
     pub name: Option<ValueHandle>,
-    // Lox Upvalues
-    pub upvalues: Vec<ValueHandle>,
     pub upvalue_count: usize,
+    // Whether the body contains `yield`, making calls return a generator
+    pub is_generator: bool,
     pub is_marked: bool,
 }
 
-impl fmt::Debug for ObjClosure {
+impl fmt::Debug for ObjFunction {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         if !EXPAND_CLOSURES {
-            return write!(f, "<Lox Closure {:?}>", &self.name);
+            return write!(f, "<Lox Function {:?}>", &self.name);
         }
 
         let mut output = format!(
@@ -62,27 +136,66 @@ impl fmt::Debug for ObjClosure {
     }
 }
 
+/// A function together with the upvalues captured when its declaration was
+/// evaluated.
+pub struct ObjClosure {
+    // Lox Function
+    pub function: ValueHandle,
+    // Lox Upvalues
+    pub upvalues: Vec<ValueHandle>,
+    pub is_marked: bool,
+}
+
+impl fmt::Debug for ObjClosure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<Lox Closure {:?}>", &self.function)
+    }
+}
+
+/// Where a captured variable lives: in its stack slot while the frame that
+/// declared it is live, then in the upvalue itself once it's closed.
+#[derive(Clone, Copy)]
+pub enum UpvalueState {
+    Open(usize),
+    Closed(Value),
+}
+
 pub struct ObjUpvalue {
-    pub location: usize,
-    pub value: Option<Value>,
+    pub state: UpvalueState,
     pub is_marked: bool,
 }
 
 impl fmt::Debug for ObjUpvalue {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", &self.location)
+        match &self.state {
+            UpvalueState::Open(location) => write!(f, "<upvalue at {}>", location),
+            UpvalueState::Closed(value) => write!(f, "<upvalue {:?}>", value),
+        }
     }
 }
 
 pub struct ObjClass {
     pub name: String,
+    // Lox Class, whose methods this class inherits
+    pub superclass: Option<ValueHandle>,
+    // Methods declared in this class's own body
     pub methods: HashMap<String, Value>,
+    // Field names with the Lox Closures computing their defaults, in
+    // declaration order (superclass fields first)
+    pub fields: Vec<(String, Value)>,
+    // Names of the methods declared `private` in this class's body, which
+    // only methods of the class (and its subclasses) may access
+    pub private_methods: HashSet<String>,
+    // Memoized lookups through the superclass chain: the method found (if
+    // any) and, if it's private, the Lox Class declaring it. Cleared when a
+    // method is added.
+    pub method_cache: HashMap<String, Option<(Value, Option<ValueHandle>)>>,
     pub is_marked: bool,
 }
 
 impl fmt::Debug for ObjClass {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "<Class {}>", &self.name)
+        write!(f, "<class {}>", &self.name)
     }
 }
 
@@ -95,7 +208,11 @@ pub struct ObjInstance {
 
 impl fmt::Debug for ObjInstance {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Instance of {:?}", &self.class)
+        // Justification for unsafe: a live instance's class is live too.
+        match unsafe { &*self.class.ptr } {
+            LoxObj::Class(class) => write!(f, "<{} instance>", &class.name),
+            _ => write!(f, "<instance>"),
+        }
     }
 }
 
@@ -109,6 +226,196 @@ pub struct ObjBoundMethod {
 
 impl fmt::Debug for ObjBoundMethod {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Bound Method")
+        // Justification for unsafe: like `Handle`'s Debug impl, this relies on
+        // the objects a live object points to being live too.
+        let method = match unsafe { &*self.method.ptr } {
+            LoxObj::Closure(closure) => match unsafe { &*closure.function.ptr } {
+                LoxObj::Function(function) => function.name,
+                _ => None,
+            },
+            _ => None,
+        };
+
+        let class = match self.receiver {
+            Value::Obj(handle) => match unsafe { &*handle.ptr } {
+                LoxObj::Instance(instance) => match unsafe { &*instance.class.ptr } {
+                    LoxObj::Class(class) => Some(&class.name),
+                    _ => None,
+                },
+                _ => None,
+            },
+            _ => None,
+        };
+
+        write!(f, "<fn ")?;
+
+        // Functions compiled without debug info have no name
+        if let Some(name) = method {
+            write!(f, "{} ", name)?;
+        }
+
+        match class {
+            Some(class) => write!(f, "bound to {} instance>", class),
+            None => write!(f, "bound to {}>", self.receiver),
+        }
+    }
+}
+
+pub struct ObjMap {
+    pub entries: HashMap<MapKey, Value>,
+    pub is_marked: bool,
+}
+
+impl fmt::Debug for ObjMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt_collection(self as *const Self as usize, "{...}", f, |f| {
+            write!(f, "{{")?;
+
+            for (i, (key, value)) in self.entries.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+
+                write!(f, "{:?}: ", key)?;
+                fmt_item(value, f)?;
+            }
+
+            write!(f, "}}")
+        })
+    }
+}
+
+pub struct ObjList {
+    pub items: Vec<Value>,
+    pub is_marked: bool,
+}
+
+impl fmt::Debug for ObjList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt_collection(self as *const Self as usize, "[...]", f, |f| {
+            write!(f, "[")?;
+
+            for (i, item) in self.items.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+
+                fmt_item(item, f)?;
+            }
+
+            write!(f, "]")
+        })
+    }
+}
+
+/// Formats the collection at `address` with `fmt_items`, or as `cycle` if
+/// it's already being formatted further out. Items are written straight to
+/// `f`, so printing a collection never builds its whole text in memory.
+fn fmt_collection<F>(
+    address: usize,
+    cycle: &str,
+    f: &mut fmt::Formatter<'_>,
+    fmt_items: F,
+) -> fmt::Result
+where
+    F: FnOnce(&mut fmt::Formatter<'_>) -> fmt::Result,
+{
+    let is_cycle = FORMATTING.with(|formatting| {
+        let mut formatting = formatting.borrow_mut();
+        let is_cycle = formatting.contains(&address);
+
+        if !is_cycle {
+            formatting.push(address);
+        }
+
+        is_cycle
+    });
+
+    if is_cycle {
+        return write!(f, "{}", cycle);
+    }
+
+    let result = fmt_items(f);
+
+    FORMATTING.with(|formatting| formatting.borrow_mut().pop());
+
+    result
+}
+
+/// Formats a value inside a collection: strings are quoted, everything else
+/// prints as it would on its own.
+fn fmt_item(value: &Value, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match value {
+        // Justification for unsafe: a live collection only holds live objects.
+        Value::Obj(handle) => match unsafe { &*handle.ptr } {
+            LoxObj::Str(s) => write!(f, "{:?}", s),
+            obj => write!(f, "{}", obj),
+        },
+        value => write!(f, "{}", value),
+    }
+}
+
+/// A function implemented in Rust. It receives the VM (so it can allocate or
+/// write output) and its arguments, which stay rooted on the stack for the
+/// duration of the call.
+pub type NativeFn = fn(&mut Vm, &[Value]) -> Result<Value>;
+
+pub struct ObjNative {
+    pub name: String,
+    pub arity: usize,
+    pub function: NativeFn,
+    pub is_marked: bool,
+}
+
+impl fmt::Debug for ObjNative {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<native fn {}>", &self.name)
+    }
+}
+
+/// The state of a foreach loop over a built-in list or string. `index` is the
+/// position of the next item; for strings it's a byte offset.
+pub struct ObjIterator {
+    // Lox List or String
+    pub target: ValueHandle,
+    pub index: usize,
+    pub is_marked: bool,
+}
+
+impl fmt::Debug for ObjIterator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<iterator>")
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum GeneratorState {
+    Suspended,
+    Running,
+    Done,
+}
+
+/// A call of a function containing `yield`. Calling the function creates one
+/// of these without running any of it; `next()` and `done()` then resume the
+/// call until it yields or returns.
+pub struct ObjGenerator {
+    // Lox Closure
+    pub closure: ValueHandle,
+    pub state: GeneratorState,
+    // Where to resume the closure's code
+    pub ip: usize,
+    // The call's stack slots while it's suspended, starting with the callee
+    pub slots: Vec<Value>,
+    // Upvalues over those slots, closed while the call is suspended, with the
+    // offsets of the slots to reopen them on
+    pub upvalues: Vec<(usize, ValueHandle)>,
+    // The last value yielded, until `next()` takes it
+    pub yielded: Option<Value>,
+    pub is_marked: bool,
+}
+
+impl fmt::Debug for ObjGenerator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<generator>")
     }
 }
@@ -1,85 +1,161 @@
+use crate::error::LoxError;
+use std::convert::TryFrom;
+
 #[derive(Debug, PartialEq)]
+#[repr(u8)]
 pub enum OpCode {
-    Return,
-    Constant,
-    Negate,
-    Add,
-    Subtract,
-    Multiply,
-    Divide,
-    Nil,
-    True,
-    False,
-    Not,
-    Equal,
-    Greater,
-    Less,
-    Print,
-    Pop,
-    DefineGlobal,
-    GetGlobal,
-    SetGlobal,
-    GetLocal,
-    SetLocal,
-    JumpIfFalse,
-    Jump,
-    Loop,
-    Call,
-    Closure,
-    GetUpvalue,
-    SetUpvalue,
-    CloseUpvalue,
-    Class,
-    GetProperty,
-    SetProperty,
-    Method,
-    Invoke,
-    Inherit,
-    GetSuper,
-    SuperInvoke,
+    Return = 0x00,
+    Constant = 0x01,
+    Negate = 0x02,
+    Add = 0x03,
+    Subtract = 0x04,
+    Multiply = 0x05,
+    Divide = 0x06,
+    Nil = 0x07,
+    True = 0x08,
+    False = 0x09,
+    Not = 0x0A,
+    Equal = 0x0B,
+    Greater = 0x0C,
+    Less = 0x0D,
+    Print = 0x0E,
+    Pop = 0x0F,
+    DefineGlobal = 0x10,
+    GetGlobal = 0x11,
+    SetGlobal = 0x12,
+    GetLocal = 0x13,
+    SetLocal = 0x14,
+    JumpIfFalse = 0x15,
+    Jump = 0x16,
+    Loop = 0x17,
+    Call = 0x18,
+    Closure = 0x19,
+    GetUpvalue = 0x1A,
+    SetUpvalue = 0x1B,
+    CloseUpvalue = 0x1C,
+    Class = 0x1D,
+    GetProperty = 0x1E,
+    SetProperty = 0x1F,
+    Method = 0x20,
+    Invoke = 0x21,
+    Inherit = 0x22,
+    GetSuper = 0x23,
+    SuperInvoke = 0x24,
+    BuildMap = 0x25,
+    Index = 0x26,
+    SetIndex = 0x27,
+    Field = 0x28,
+    BuildList = 0x29,
+    GetIter = 0x2A,
+    IterDone = 0x2B,
+    IterNext = 0x2C,
+    PrivateMethod = 0x2D,
+    Yield = 0x2E,
+    Assert = 0x2F,
+    Dup = 0x30,
+    BitAnd = 0x31,
+    BitOr = 0x32,
+    BitXor = 0x33,
+    BitNot = 0x34,
+    ShiftLeft = 0x35,
+    ShiftRight = 0x36,
+    DefineConst = 0x37,
+}
+
+impl OpCode {
+    /// The highest opcode. Opcodes are numbered contiguously from 0.
+    pub const LAST: u8 = OpCode::DefineConst as u8;
+
+    /// Converts a byte known to be an opcode, e.g. one `try_from` has
+    /// accepted. Unchecked in release builds.
+    #[inline(always)]
+    pub fn decode(byte: u8) -> OpCode {
+        debug_assert!(byte <= OpCode::LAST, "Byte doesn't map to any opcode.");
+
+        // Justification for unsafe: `OpCode` is `repr(u8)` with contiguous
+        // discriminants up to `LAST`, and the compiler only emits opcodes.
+        unsafe { std::mem::transmute::<u8, OpCode>(byte) }
+    }
+
+    /// Net change in stack size from executing this instruction, given the
+    /// bytes following the opcode. Calls count as returning, i.e. the callee
+    /// and its arguments are replaced by the result. `Return` counts as
+    /// popping its value, and so does `Yield`.
+    pub fn stack_effect(&self, operands: &[u8]) -> isize {
+        let operand = |i: usize| operands.get(i).copied().unwrap_or(0) as isize;
+
+        match self {
+            OpCode::Constant
+            | OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::GetGlobal
+            | OpCode::GetLocal
+            | OpCode::GetUpvalue
+            | OpCode::Closure
+            | OpCode::Class
+            | OpCode::Dup => 1,
+            OpCode::Negate
+            | OpCode::Not
+            | OpCode::BitNot
+            | OpCode::SetGlobal
+            | OpCode::SetLocal
+            | OpCode::SetUpvalue
+            | OpCode::JumpIfFalse
+            | OpCode::Jump
+            | OpCode::Loop
+            | OpCode::GetProperty
+            | OpCode::GetIter
+            | OpCode::IterDone
+            | OpCode::IterNext => 0,
+            OpCode::Return
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::BitAnd
+            | OpCode::BitOr
+            | OpCode::BitXor
+            | OpCode::ShiftLeft
+            | OpCode::ShiftRight
+            | OpCode::Equal
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::Print
+            | OpCode::Pop
+            | OpCode::DefineGlobal
+            | OpCode::DefineConst
+            | OpCode::CloseUpvalue
+            | OpCode::SetProperty
+            | OpCode::Method
+            | OpCode::PrivateMethod
+            | OpCode::Yield
+            | OpCode::Assert
+            | OpCode::Field
+            | OpCode::Inherit
+            | OpCode::GetSuper
+            | OpCode::Index => -1,
+            OpCode::SetIndex => -2,
+            // Callee (or receiver) and arguments are replaced by the result
+            OpCode::Call => -operand(0),
+            OpCode::Invoke => -operand(1),
+            // The superclass is popped as well
+            OpCode::SuperInvoke => -operand(1) - 1,
+            OpCode::BuildMap => 1 - 2 * operand(0),
+            OpCode::BuildList => 1 - operand(0),
+        }
+    }
 }
 
-impl From<u8> for OpCode {
-    fn from(byte: u8) -> Self {
-        match byte {
-            0x00 => OpCode::Return,
-            0x01 => OpCode::Constant,
-            0x02 => OpCode::Negate,
-            0x03 => OpCode::Add,
-            0x04 => OpCode::Subtract,
-            0x05 => OpCode::Multiply,
-            0x06 => OpCode::Divide,
-            0x07 => OpCode::Nil,
-            0x08 => OpCode::True,
-            0x09 => OpCode::False,
-            0x0A => OpCode::Not,
-            0x0B => OpCode::Equal,
-            0x0C => OpCode::Greater,
-            0x0D => OpCode::Less,
-            0x0E => OpCode::Print,
-            0x0F => OpCode::Pop,
-            0x10 => OpCode::DefineGlobal,
-            0x11 => OpCode::GetGlobal,
-            0x12 => OpCode::SetGlobal,
-            0x13 => OpCode::GetLocal,
-            0x14 => OpCode::SetLocal,
-            0x15 => OpCode::JumpIfFalse,
-            0x16 => OpCode::Jump,
-            0x17 => OpCode::Loop,
-            0x18 => OpCode::Call,
-            0x19 => OpCode::Closure,
-            0x1A => OpCode::GetUpvalue,
-            0x1B => OpCode::SetUpvalue,
-            0x1C => OpCode::CloseUpvalue,
-            0x1D => OpCode::Class,
-            0x1E => OpCode::GetProperty,
-            0x1F => OpCode::SetProperty,
-            0x20 => OpCode::Method,
-            0x21 => OpCode::Invoke,
-            0x22 => OpCode::Inherit,
-            0x23 => OpCode::GetSuper,
-            0x24 => OpCode::SuperInvoke,
-            _ => panic!("Byte doesn't map to any opcode."),
+impl TryFrom<u8> for OpCode {
+    type Error = LoxError;
+
+    /// Fails on a byte that isn't an opcode, e.g. from corrupted bytecode.
+    fn try_from(byte: u8) -> Result<Self, LoxError> {
+        if byte > OpCode::LAST {
+            return Err(LoxError::InternalVmError("unknown opcode"));
         }
+
+        Ok(OpCode::decode(byte))
     }
 }
@@ -4,99 +4,193 @@ use std::iter::{Iterator, Peekable};
 use std::str::Chars;
 
 macro_rules! token {
-    ($type:tt, $line:expr) => {
-        Some(Ok(Token {
-            tok_type: TokenType::$type,
-            line: $line,
-        }))
+    ($type:tt, $self:expr) => {
+        Some(Ok($self.token(TokenType::$type)))
     };
 }
 
 macro_rules! consume_and_token {
-    ($type:tt, $line:expr, $self:expr) => {{
-        $self.source.next();
-        token!($type, $line)
+    ($type:tt, $self:expr) => {{
+        $self.bump();
+        token!($type, $self)
     }};
 }
 
 pub struct Scanner<'a> {
     source: Peekable<Chars<'a>>,
+    // The whole source, for `text` to slice
+    text: &'a str,
     line: usize,
+    // Byte offset of the next character in `source`
+    offset: usize,
+    // Byte offset of the token being scanned
+    token_start: usize,
 }
 
 impl<'a> Scanner<'a> {
     pub fn new(source: Chars<'a>) -> Self {
-        Scanner {
+        let text = source.as_str();
+        let has_shebang = text.starts_with("#!");
+
+        let mut scanner = Scanner {
             source: source.peekable(),
-            line: 0,
+            text,
+            line: 1,
+            offset: 0,
+            token_start: 0,
+        };
+
+        // Skip a `#!` interpreter line so scripts can be made executable.
+        if has_shebang {
+            scanner.scan_comment();
+        }
+
+        scanner
+    }
+
+    /// The source text between two byte offsets, e.g. a token's `start` and
+    /// `end`.
+    pub fn text(&self, start: usize, end: usize) -> Option<&'a str> {
+        self.text.get(start..end)
+    }
+
+    /// Column, counting characters from 1, of the byte offset `offset`.
+    pub fn column(&self, offset: usize) -> usize {
+        let before = self.text.get(..offset).unwrap_or(self.text);
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+
+        before[line_start..].chars().count() + 1
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.source.next()?;
+        self.offset += c.len_utf8();
+
+        Some(c)
+    }
+
+    fn token(&self, tok_type: TokenType) -> Token {
+        Token {
+            tok_type,
+            line: self.line,
+            start: self.token_start,
+            end: self.offset,
         }
     }
 
     fn scan_string(&mut self) -> Result<Token> {
         let value = self.scan_until(|c| c == '"');
 
-        self.expect('"').map(|_| Token {
-            tok_type: TokenType::Str(value),
-            line: self.line,
-        })
+        self.expect('"').map(|_| self.token(TokenType::Str(value)))
     }
 
     fn scan_number(&mut self, c: char) -> Result<Token> {
-        let mut value = c.to_string();
+        if c == '0' {
+            if let Some('x') | Some('X') = self.source.peek() {
+                self.bump();
+
+                return self.scan_hex_number();
+            }
+        }
 
-        value.push_str(&self.scan_until(|c| !c.is_ascii_digit()));
+        let mut value = self.scan_digits(c.to_string(), |c| c.is_ascii_digit())?;
 
         if let Some('.') = self.source.peek() {
-            value.push(self.source.next().unwrap());
+            value.push(self.bump().unwrap());
+
+            value.push_str(&self.scan_digits(String::new(), |c| c.is_ascii_digit())?);
+        }
+
+        if let Some('e') | Some('E') = self.source.peek() {
+            value.push(self.bump().unwrap());
+
+            if let Some('+') | Some('-') = self.source.peek() {
+                value.push(self.bump().unwrap());
+            }
+
+            let exponent = self.scan_digits(String::new(), |c| c.is_ascii_digit())?;
 
-            value.push_str(&self.scan_until(|c| !c.is_ascii_digit()));
+            if exponent.is_empty() {
+                return Err(LoxError::UnexpectedCharacter);
+            }
+
+            value.push_str(&exponent);
         }
 
         value
             .parse()
             .map_err(|_| LoxError::UnexpectedCharacter)
-            .map(|num: f64| Token {
-                tok_type: TokenType::Num(num),
-                line: self.line,
-            })
+            .map(|num: f64| self.token(TokenType::Num(num)))
+    }
+
+    /// Scans a run of digits onto the ones in `digits`, returning them all.
+    /// Digits may be grouped by single underscores between them, as in
+    /// `1_000`, which are left out of the result.
+    fn scan_digits<F>(&mut self, mut digits: String, is_digit: F) -> Result<String>
+    where
+        F: Fn(char) -> bool,
+    {
+        digits.push_str(&self.scan_until(|c| !(is_digit(c) || c == '_')));
+
+        if digits.starts_with('_') || digits.ends_with('_') || digits.contains("__") {
+            return Err(LoxError::UnexpectedCharacter);
+        }
+
+        Ok(digits.replace('_', ""))
+    }
+
+    /// Scans the digits of a hex integer literal following its `0x`.
+    fn scan_hex_number(&mut self) -> Result<Token> {
+        let digits = self.scan_digits(String::new(), |c| c.is_ascii_hexdigit())?;
+
+        // A lone `0x` fails to parse too
+        i64::from_str_radix(&digits, 16)
+            .map_err(|_| LoxError::UnexpectedCharacter)
+            .map(|num| self.token(TokenType::Num(num as f64)))
     }
 
     fn scan_identifier(&mut self, c: char) -> Option<Result<Token>> {
         let mut value = c.to_string();
 
-        value.push_str(&self.scan_until(|c| !c.is_ascii_alphanumeric()));
+        value.push_str(&self.scan_until(|c| !(c.is_ascii_alphanumeric() || c == '_')));
 
         match &value[..] {
-            "and" => token!(And, self.line),
-            "class" => token!(Class, self.line),
-            "else" => token!(Else, self.line),
-            "false" => token!(False, self.line),
-            "for" => token!(For, self.line),
-            "fun" => token!(Fun, self.line),
-            "if" => token!(If, self.line),
-            "nil" => token!(Nil, self.line),
-            "or" => token!(Or, self.line),
-            "print" => token!(Print, self.line),
-            "return" => token!(Return, self.line),
-            "super" => token!(Super, self.line),
-            "this" => token!(This, self.line),
-            "true" => token!(True, self.line),
-            "var" => token!(Var, self.line),
-            "while" => token!(While, self.line),
-            _ => Some(Ok(Token {
-                tok_type: TokenType::Ident(value),
-                line: self.line,
-            })),
+            "and" => token!(And, self),
+            "assert" => token!(Assert, self),
+            "break" => token!(Break, self),
+            "case" => token!(Case, self),
+            "class" => token!(Class, self),
+            "const" => token!(Const, self),
+            "continue" => token!(Continue, self),
+            "default" => token!(Default, self),
+            "else" => token!(Else, self),
+            "false" => token!(False, self),
+            "for" => token!(For, self),
+            "fun" => token!(Fun, self),
+            "if" => token!(If, self),
+            "in" => token!(In, self),
+            "nil" => token!(Nil, self),
+            "or" => token!(Or, self),
+            "print" => token!(Print, self),
+            "return" => token!(Return, self),
+            "super" => token!(Super, self),
+            "switch" => token!(Switch, self),
+            "this" => token!(This, self),
+            "true" => token!(True, self),
+            "var" => token!(Var, self),
+            "while" => token!(While, self),
+            "yield" => token!(Yield, self),
+            _ => Some(Ok(self.token(TokenType::Ident(value)))),
         }
     }
 
     fn consume_whitespace(&mut self) {
         loop {
             match self.source.peek() {
-                Some(' ') | Some('\t') | Some('\r') => self.source.next(),
+                Some(' ') | Some('\t') | Some('\r') => self.bump(),
                 Some('\n') => {
                     self.line += 1;
-                    self.source.next()
+                    self.bump()
                 }
                 _ => break,
             };
@@ -107,11 +201,37 @@ impl<'a> Scanner<'a> {
         loop {
             match self.source.peek() {
                 None | Some('\n') => break,
-                _ => self.source.next(),
+                _ => self.bump(),
             };
         }
     }
 
+    /// Skips the rest of a `/* */` comment after its opening `/*`. Block
+    /// comments nest, so `/* a /* b */ c */` is one comment.
+    fn scan_block_comment(&mut self) -> Result<()> {
+        let mut depth = 1;
+
+        loop {
+            match self.bump() {
+                Some('\n') => self.line += 1,
+                Some('/') if self.source.peek() == Some(&'*') => {
+                    self.bump();
+                    depth += 1;
+                }
+                Some('*') if self.source.peek() == Some(&'/') => {
+                    self.bump();
+                    depth -= 1;
+
+                    if depth == 0 {
+                        return Ok(());
+                    }
+                }
+                Some(_) => (),
+                None => return Err(LoxError::UnexpectedEof),
+            }
+        }
+    }
+
     fn scan_until<F>(&mut self, pred: F) -> String
     where
         F: Fn(char) -> bool,
@@ -123,11 +243,20 @@ impl<'a> Scanner<'a> {
                 Some(&c) if pred(c) => break,
                 Some('\n') => {
                     self.line += 1;
-                    self.source.next();
+                    self.bump();
                     value.push('\n');
                 }
+                // `\r\n` becomes `\n`, so strings don't depend on the line
+                // endings the source was saved with
+                Some('\r') => {
+                    self.bump();
+
+                    if self.source.peek() != Some(&'\n') {
+                        value.push('\r');
+                    }
+                }
                 Some(&c) => {
-                    self.source.next();
+                    self.bump();
                     value.push(c);
                 }
                 None => break,
@@ -138,7 +267,7 @@ impl<'a> Scanner<'a> {
     }
 
     fn expect(&mut self, value: char) -> Result<()> {
-        match self.source.next() {
+        match self.bump() {
             Some(c) if c == value => Ok(()),
             _ => Err(LoxError::UnexpectedCharacter),
         }
@@ -152,39 +281,68 @@ impl<'a> Iterator for Scanner<'a> {
         loop {
             self.consume_whitespace();
 
-            match self.source.next() {
-                Some('(') => return token!(LParen, self.line),
-                Some(')') => return token!(RParen, self.line),
-                Some('{') => return token!(LBrace, self.line),
-                Some('}') => return token!(RBrace, self.line),
-                Some(';') => return token!(Semicolon, self.line),
-                Some(',') => return token!(Comma, self.line),
-                Some('.') => return token!(Dot, self.line),
-                Some('-') => return token!(Minus, self.line),
-                Some('+') => return token!(Plus, self.line),
-                Some('*') => return token!(Star, self.line),
+            self.token_start = self.offset;
+
+            match self.bump() {
+                Some('(') => return token!(LParen, self),
+                Some(')') => return token!(RParen, self),
+                Some('{') => return token!(LBrace, self),
+                Some('}') => return token!(RBrace, self),
+                Some('[') => return token!(LBracket, self),
+                Some(']') => return token!(RBracket, self),
+                Some(';') => return token!(Semicolon, self),
+                Some(':') => return token!(Colon, self),
+                Some('?') => return token!(Question, self),
+                Some('&') => return token!(Amp, self),
+                Some('|') => return token!(Pipe, self),
+                Some('^') => return token!(Caret, self),
+                Some('~') => return token!(Tilde, self),
+                Some(',') => return token!(Comma, self),
+                Some('.') => return token!(Dot, self),
+                Some('-') => match self.source.peek() {
+                    Some('=') => return consume_and_token!(MinusEq, self),
+                    _ => return token!(Minus, self),
+                },
+                Some('+') => match self.source.peek() {
+                    Some('=') => return consume_and_token!(PlusEq, self),
+                    _ => return token!(Plus, self),
+                },
+                Some('*') => match self.source.peek() {
+                    Some('=') => return consume_and_token!(StarEq, self),
+                    _ => return token!(Star, self),
+                },
                 Some('/') => match self.source.peek() {
+                    Some('=') => return consume_and_token!(SlashEq, self),
                     Some('/') => {
-                        self.source.next();
+                        self.bump();
                         self.scan_comment()
                     }
-                    _ => return token!(Slash, self.line),
+                    Some('*') => {
+                        self.bump();
+
+                        if let Err(e) = self.scan_block_comment() {
+                            return Some(Err(e));
+                        }
+                    }
+                    _ => return token!(Slash, self),
                 },
                 Some('!') => match self.source.peek() {
-                    Some('=') => return consume_and_token!(BangEq, self.line, self),
-                    _ => return token!(Bang, self.line),
+                    Some('=') => return consume_and_token!(BangEq, self),
+                    _ => return token!(Bang, self),
                 },
                 Some('=') => match self.source.peek() {
-                    Some('=') => return consume_and_token!(EqualEq, self.line, self),
-                    _ => return token!(Equal, self.line),
+                    Some('=') => return consume_and_token!(EqualEq, self),
+                    _ => return token!(Equal, self),
                 },
                 Some('<') => match self.source.peek() {
-                    Some('=') => return consume_and_token!(LessEq, self.line, self),
-                    _ => return token!(Less, self.line),
+                    Some('=') => return consume_and_token!(LessEq, self),
+                    Some('<') => return consume_and_token!(LessLess, self),
+                    _ => return token!(Less, self),
                 },
                 Some('>') => match self.source.peek() {
-                    Some('=') => return consume_and_token!(GreaterEq, self.line, self),
-                    _ => return token!(Greater, self.line),
+                    Some('=') => return consume_and_token!(GreaterEq, self),
+                    Some('>') => return consume_and_token!(GreaterGreater, self),
+                    _ => return token!(Greater, self),
                 },
                 Some('"') => return Some(self.scan_string()),
                 Some(c) if c.is_ascii_digit() => return Some(self.scan_number(c)),
@@ -217,4 +375,174 @@ mod tests {
 
         println!("{:#?}", scanner.collect::<Vec<_>>());
     }
+
+    #[test]
+    fn test_number_literals() {
+        let numbers: Vec<_> = Scanner::new("1e3 1.5e-2 2E+2 0xff 0XFF 0x0 0".chars())
+            .map(|token| token.unwrap().tok_type)
+            .collect();
+
+        assert_eq!(
+            numbers,
+            vec![
+                TokenType::Num(1000.0),
+                TokenType::Num(0.015),
+                TokenType::Num(200.0),
+                TokenType::Num(255.0),
+                TokenType::Num(255.0),
+                TokenType::Num(0.0),
+                TokenType::Num(0.0),
+            ]
+        );
+
+        for source in &["0x", "0x;", "1e", "1e+", "2.5E-"] {
+            assert!(
+                matches!(
+                    Scanner::new(source.chars()).next(),
+                    Some(Err(LoxError::UnexpectedCharacter))
+                ),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_digit_separators() {
+        let numbers: Vec<_> = Scanner::new("1_000_000 0.000_25 0xff_ff 1e1_0 12".chars())
+            .map(|token| token.unwrap().tok_type)
+            .collect();
+
+        assert_eq!(
+            numbers,
+            vec![
+                TokenType::Num(1_000_000.0),
+                TokenType::Num(0.000_25),
+                TokenType::Num(65535.0),
+                TokenType::Num(1e10),
+                TokenType::Num(12.0),
+            ]
+        );
+
+        for source in &["1_", "1__0", "1_.5", "1._5", "1e_5", "0x_f", "0xf_"] {
+            assert!(
+                matches!(
+                    Scanner::new(source.chars()).next(),
+                    Some(Err(LoxError::UnexpectedCharacter))
+                ),
+                "{}",
+                source
+            );
+        }
+
+        // A leading underscore starts an identifier instead
+        assert!(matches!(
+            Scanner::new("_1".chars()).next(),
+            Some(Ok(Token {
+                tok_type: TokenType::Ident(_),
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_shebang() {
+        let source = "#!/usr/bin/env rslox\nprint 1;";
+
+        let tokens: Vec<_> = Scanner::new(source.chars())
+            .map(|token| token.unwrap())
+            .collect();
+
+        let types: Vec<_> = tokens.iter().map(|token| &token.tok_type).collect();
+
+        assert_eq!(
+            types,
+            vec![
+                &TokenType::Print,
+                &TokenType::Num(1.0),
+                &TokenType::Semicolon
+            ]
+        );
+        assert_eq!(tokens[0].line, 2);
+    }
+
+    #[test]
+    fn test_hash_outside_shebang() {
+        let source = "print 1; #!";
+
+        let result: Result<Vec<_>> = Scanner::new(source.chars()).collect();
+
+        assert!(matches!(result, Err(LoxError::UnexpectedCharacter)));
+    }
+
+    #[test]
+    fn test_identifier_with_underscores() {
+        let types: Vec<_> = Scanner::new("_a random_int b_".chars())
+            .map(|token| token.unwrap().tok_type)
+            .collect();
+
+        assert_eq!(
+            types,
+            vec![
+                TokenType::Ident("_a".to_owned()),
+                TokenType::Ident("random_int".to_owned()),
+                TokenType::Ident("b_".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_crlf_string() {
+        let source = "print \"one\r\ntwo\r\nthree\rfour\";\r\nprint 1;";
+
+        let tokens: Vec<_> = Scanner::new(source.chars())
+            .map(|token| token.unwrap())
+            .collect();
+
+        assert_eq!(
+            tokens[1].tok_type,
+            TokenType::Str("one\ntwo\nthree\rfour".to_owned())
+        );
+        assert_eq!(tokens[1].line, 3);
+        assert_eq!(tokens[3].tok_type, TokenType::Print);
+        assert_eq!(tokens[3].line, 4);
+    }
+
+    #[test]
+    fn test_block_comment() {
+        let source = "/* a /* b\n */ c */ print /* d */ 1; /**/\nprint 2;";
+
+        let tokens: Vec<_> = Scanner::new(source.chars())
+            .map(|token| token.unwrap())
+            .collect();
+
+        let types: Vec<_> = tokens.iter().map(|token| &token.tok_type).collect();
+
+        assert_eq!(
+            types,
+            vec![
+                &TokenType::Print,
+                &TokenType::Num(1.0),
+                &TokenType::Semicolon,
+                &TokenType::Print,
+                &TokenType::Num(2.0),
+                &TokenType::Semicolon
+            ]
+        );
+        assert_eq!(tokens[0].line, 2);
+        assert_eq!(tokens[3].line, 3);
+    }
+
+    #[test]
+    fn test_unterminated_block_comment() {
+        for source in &["print 1; /* a", "/* a /* b */ c"] {
+            let tokens: Vec<_> = Scanner::new(source.chars()).collect();
+
+            assert!(
+                matches!(tokens.last(), Some(Err(LoxError::UnexpectedEof))),
+                "{}",
+                source
+            );
+        }
+    }
 }
@@ -2,22 +2,39 @@
 pub struct Token {
     pub tok_type: TokenType,
     pub line: usize,
+    // Byte offsets of the token's text in the source
+    pub start: usize,
+    pub end: usize,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum TokenType {
     LParen,
     RParen,
     LBrace,
     RBrace,
+    LBracket,
+    RBracket,
     Comma,
     Dot,
     Minus,
     Plus,
     Semicolon,
+    Colon,
+    Question,
     Slash,
     Star,
 
+    Amp,
+    Caret,
+    Pipe,
+    Tilde,
+
+    MinusEq,
+    PlusEq,
+    SlashEq,
+    StarEq,
+
     Bang,
     BangEq,
     Equal,
@@ -26,41 +43,76 @@ pub enum TokenType {
     GreaterEq,
     Less,
     LessEq,
+    LessLess,
+    GreaterGreater,
 
     Ident(String),
     Str(String),
     Num(f64),
 
     And,
+    Assert,
+    Break,
+    Case,
     Class,
+    Const,
+    Continue,
+    Default,
     Else,
     False,
     For,
     Fun,
     If,
+    In,
     Nil,
     Or,
     Print,
     Return,
     Super,
+    Switch,
     This,
     True,
     Var,
     While,
+    Yield,
 }
 
 impl TokenType {
+    /// Whether this is one of the ordering operators `<`, `<=`, `>` and `>=`.
+    pub fn is_comparison(&self) -> bool {
+        matches!(
+            self,
+            TokenType::Less | TokenType::LessEq | TokenType::Greater | TokenType::GreaterEq
+        )
+    }
+
+    /// Whether this is one of the compound assignment operators `+=`, `-=`,
+    /// `*=` and `/=`.
+    pub fn is_compound_assignment(&self) -> bool {
+        matches!(
+            self,
+            TokenType::PlusEq | TokenType::MinusEq | TokenType::StarEq | TokenType::SlashEq
+        )
+    }
+
     pub fn precedence(&self) -> usize {
         match self {
             TokenType::Equal => 1,
-            TokenType::Or => 2,
-            TokenType::And => 3,
-            TokenType::EqualEq | TokenType::BangEq => 4,
-            TokenType::Less | TokenType::LessEq | TokenType::Greater | TokenType::GreaterEq => 5,
-            TokenType::Plus | TokenType::Minus => 6,
-            TokenType::Star | TokenType::Slash => 7,
-            TokenType::Bang => 8,
-            TokenType::Dot | TokenType::LParen => 9,
+            TokenType::Question => 2,
+            TokenType::Or => 3,
+            TokenType::And => 4,
+            TokenType::EqualEq | TokenType::BangEq => 5,
+            TokenType::Less | TokenType::LessEq | TokenType::Greater | TokenType::GreaterEq => 6,
+            // Bitwise operators bind tighter than comparisons (unlike C), so
+            // `flags & MASK == 0` tests the masked bits
+            TokenType::Pipe => 7,
+            TokenType::Caret => 8,
+            TokenType::Amp => 9,
+            TokenType::LessLess | TokenType::GreaterGreater => 10,
+            TokenType::Plus | TokenType::Minus => 11,
+            TokenType::Star | TokenType::Slash => 12,
+            TokenType::Bang | TokenType::Tilde => 13,
+            TokenType::Dot | TokenType::LParen | TokenType::LBracket => 14,
             _ => 0,
         }
     }
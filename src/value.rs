@@ -1,4 +1,4 @@
-use crate::gc::Handle;
+use crate::gc::{Handle, Heap};
 use crate::object::LoxObj;
 use std::fmt;
 
@@ -16,6 +16,85 @@ impl Value {
     pub fn is_falsey(&self) -> bool {
         matches!(self, Value::Nil | Value::Bool(false))
     }
+
+    /// Formats the value like `Debug`, but looks its object up in `heap`
+    /// instead of dereferencing the handle, so one that's been freed renders
+    /// as `<freed>`. Objects a live object refers to are assumed live, as they
+    /// are everywhere else.
+    pub fn debug_with(&self, heap: &Heap<LoxObj>) -> String {
+        match self {
+            Value::Obj(handle) => match heap.get(handle) {
+                Some(obj) => format!("{:?}", obj),
+                None => String::from("<freed>"),
+            },
+            value => format!("{:?}", value),
+        }
+    }
+}
+
+/// Key of a Lox map. Numbers are keyed by their bit pattern, with all NaNs
+/// and both zeroes canonicalized so equal-looking keys hash the same.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum MapKey {
+    Str(String),
+    Num(u64),
+}
+
+impl MapKey {
+    pub fn number(n: f64) -> Self {
+        let bits = if n.is_nan() {
+            f64::NAN.to_bits()
+        } else if n == 0.0 {
+            0
+        } else {
+            n.to_bits()
+        };
+
+        MapKey::Num(bits)
+    }
+}
+
+impl fmt::Debug for MapKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapKey::Str(s) => write!(f, "\"{}\"", s),
+            MapKey::Num(bits) => fmt_number(f64::from_bits(*bits), f),
+        }
+    }
+}
+
+/// Formats a number for printing and error messages alike. Whole numbers have
+/// no `.0` and the rest use the shortest representation that reads back as the
+/// same number, while `inf`, `-inf` and `nan` are spelled the way clox does.
+/// Numbers from 1e21 up, or below 1e-6, are written with an exponent the way
+/// clox's `%g` writes it, e.g. `1e+21` and `1.5e-07`, though the digits are
+/// still the shortest rather than `%g`'s six significant ones.
+fn fmt_number(n: f64, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if n.is_nan() {
+        write!(f, "nan")
+    } else if n.is_infinite() {
+        write!(f, "{}", if n > 0.0 { "inf" } else { "-inf" })
+    } else if n != 0.0 && (n.abs() >= 1e21 || n.abs() < 1e-6) {
+        let formatted = format!("{:e}", n);
+        let (mantissa, exponent) = formatted.split_once('e').unwrap_or((&formatted, "0"));
+        let exponent: i32 = exponent.parse().unwrap_or(0);
+        let sign = if exponent < 0 { '-' } else { '+' };
+
+        write!(f, "{}e{}{:02}", mantissa, sign, exponent.abs())
+    } else {
+        write!(f, "{}", n)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Obj(handle) => write!(f, "{}", handle),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Number(n) => fmt_number(*n, f),
+            Value::Nil => write!(f, "nil"),
+        }
+    }
 }
 
 impl fmt::Debug for Value {
@@ -23,8 +102,78 @@ impl fmt::Debug for Value {
         match self {
             Value::Obj(handle) => write!(f, "{:?}", handle),
             Value::Bool(b) => write!(f, "{}", b),
-            Value::Number(n) => write!(f, "{}", n),
+            Value::Number(n) => fmt_number(*n, f),
             Value::Nil => write!(f, "nil"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::Chunk;
+    use crate::object::ObjString;
+    use crate::opcodes::OpCode;
+
+    #[test]
+    fn test_debug_with_freed_object() {
+        let mut heap = Heap::default();
+
+        let handle = heap.insert(LoxObj::Str(Box::from(ObjString {
+            value: String::from("hi"),
+            is_marked: false,
+        })));
+        let value = Value::Obj(handle);
+
+        let mut chunk = Chunk::default();
+        chunk.constants.push(value);
+        chunk.write(OpCode::Constant as u8, 1);
+        chunk.write(0, 1);
+
+        assert_eq!(value.debug_with(&heap), format!("{:?}", value));
+        assert!(chunk
+            .disassemble_with(None, &heap)
+            .contains("'Str(\"hi\")'"));
+
+        heap.remove(handle);
+
+        assert_eq!(value.debug_with(&heap), "<freed>");
+        assert!(chunk.disassemble_with(None, &heap).contains("'<freed>'"));
+        assert_eq!(Value::Number(1.5).debug_with(&heap), "1.5");
+    }
+
+    #[test]
+    fn test_disassemble_malformed_closure() {
+        let mut chunk = Chunk::default();
+        chunk.constants.push(Value::Number(1.0));
+        chunk.write(OpCode::Closure as u8, 1);
+        chunk.write(0, 1);
+        chunk.write(OpCode::Return as u8, 1);
+
+        let disassembly = chunk.disassemble(None);
+
+        assert!(disassembly.contains("closure constant isn't a function"));
+        assert!(!disassembly.contains("Return"));
+    }
+
+    #[test]
+    fn test_number_formatting() {
+        for (n, expected) in [
+            (42.0, "42"),
+            (6.0 / 2.0, "3"),
+            (-0.0, "-0"),
+            (1e21, "1e+21"),
+            (1e300, "1e+300"),
+            (-2.5e22, "-2.5e+22"),
+            (1e20, "100000000000000000000"),
+            (1.5e-7, "1.5e-07"),
+            (0.000_001, "0.000001"),
+            (2.5, "2.5"),
+            (0.1 + 0.2, "0.30000000000000004"),
+            (1.0 / 3.0, "0.3333333333333333"),
+        ] {
+            assert_eq!(Value::Number(n).to_string(), expected);
+            assert_eq!(format!("{:?}", Value::Number(n)), expected);
+        }
+    }
+}
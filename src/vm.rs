@@ -1,22 +1,43 @@
 use crate::chunk::Chunk;
+use crate::compiler::Compiler;
 use crate::error::{Internal, LoxError, Result};
-use crate::gc::{mark_object, mark_table, Heap};
+use crate::gc::{free_object, mark_object, mark_table, Heap, InternedStr};
+use crate::natives;
 use crate::object::{
-    LoxObj, ObjBoundMethod, ObjClass, ObjClosure, ObjInstance, ObjString, ObjUpvalue,
+    GeneratorState, LoxObj, NativeFn, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction,
+    ObjGenerator, ObjInstance, ObjIterator, ObjList, ObjMap, ObjNative, ObjString, ObjUpvalue,
+    UpvalueState,
 };
 use crate::opcodes::OpCode;
-use crate::value::{Value, ValueHandle};
-use std::collections::HashMap;
+use crate::rng::Rng;
+use crate::value::{MapKey, Value, ValueHandle};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::mem;
+use std::time::Instant;
 
 pub static INIT_STRING: &str = "init";
+pub static FINALIZE_STRING: &str = "finalize";
+// Methods of the iterator protocol driving foreach loops over instances
+static ITER_STRING: &str = "iter";
+static DONE_STRING: &str = "done";
+static NEXT_STRING: &str = "next";
+// Method `print` and string concatenation use to turn instances into strings
+static TO_STRING_STRING: &str = "to_string";
 
 const FRAMES_MAX: usize = 64;
 const STACK_MAX: usize = FRAMES_MAX * 256;
 const INITIAL_GC_THRESHOLD: usize = 1024 * 1024;
 const GC_HEAP_GROW_FACTOR: usize = 2;
-
-// To force the GC to be called upon every allocation
-const DEV_GC_TESTING: bool = true;
+// How deeply natives and generators may nest runs of the dispatch loop,
+// which recurse on the Rust stack
+const RUN_DEPTH_MAX: usize = 32;
+// Longest string, in bytes, concatenation may build unless configured
+const DEFAULT_MAX_STRING_LEN: usize = 1 << 28;
+// Number of instructions executed between deadline checks
+const DEADLINE_CHECK_INTERVAL: usize = 1024;
+const DEFAULT_STEPS_PER_RESUME: usize = 10_000;
 
 const fn lox_obj_size() -> usize {
     std::mem::size_of::<LoxObj>()
@@ -48,18 +69,33 @@ macro_rules! binary_op {
     }};
 }
 
+macro_rules! bitwise_op {
+    ($op:tt, $self:expr) => {{
+        let b = $self.pop_integer()?;
+        let a = $self.pop_integer()?;
+
+        $self.push(Value::Number((a $op b) as f64))?;
+    }};
+}
+
 macro_rules! sweep_obj {
-    ($obj:expr, $handle:expr, $bytes_freed:expr) => {{
+    ($obj:expr, $handle:expr, $bytes_freed:expr, $on_free:expr, $arena:expr) => {{
         let is_marked = $obj.is_marked;
 
         if is_marked {
             $obj.is_marked = false;
         } else {
-            dprintln!("Dropping {:?}", $handle);
+            // Only the kind: formatting the object itself may follow handles
+            // to objects this same sweep already freed.
+            dprintln!("Dropping {:?}", unsafe { &*$handle.ptr }.kind());
 
             $bytes_freed += lox_obj_size();
 
-            drop(unsafe { Box::from_raw($handle.ptr) });
+            if let Some(hook) = $on_free.as_mut() {
+                hook(unsafe { &*$handle.ptr }, lox_obj_size());
+            }
+
+            unsafe { free_object($arena, $handle.ptr) };
         }
 
         is_marked
@@ -68,8 +104,65 @@ macro_rules! sweep_obj {
 
 pub struct CallFrame {
     pub closure: ValueHandle,
+    // Lox Function of `closure`, kept here to save a lookup per instruction
+    pub function: ValueHandle,
     pub ip: usize,
+    // Offset of the instruction being executed. `ip` has already moved past
+    // its opcode (and maybe its operands), so line lookups use this instead.
+    pub opcode_ip: usize,
     pub fp: usize,
+    pub kind: FrameKind,
+}
+
+/// What happens when a frame returns.
+pub enum FrameKind {
+    /// An ordinary call: the return value replaces the callee on the stack.
+    Call,
+    /// Computes a field default for a new instance (which sits in the frame's
+    /// slot 0). When it returns, the VM stores the field and moves on to the
+    /// next default or to `init`.
+    FieldInit(FieldInit),
+    /// Runs the `finalize` method of a collected instance. The return value is
+    /// discarded.
+    Finalizer,
+    /// Resumes a generator (the handle) until it yields or returns. Either way
+    /// its slots leave the stack and nothing is pushed in their place.
+    Generator(ValueHandle),
+}
+
+/// How far a call to `Vm::resume` got.
+#[derive(Debug, Clone, Copy)]
+pub enum StepResult {
+    /// The script used up its steps and has more to run.
+    Running,
+    /// The script finished, with the value of its top-level `return` or nil.
+    Done(Value),
+}
+
+/// When the VM collects garbage. The first collection happens once
+/// `initial_threshold` bytes of objects have been allocated, and each one
+/// after when the heap has grown `grow_factor` times bigger than what survived
+/// the last.
+#[derive(Debug, Clone, Copy)]
+pub struct GcConfig {
+    pub initial_threshold: usize,
+    pub grow_factor: usize,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        Self {
+            initial_threshold: INITIAL_GC_THRESHOLD,
+            grow_factor: GC_HEAP_GROW_FACTOR,
+        }
+    }
+}
+
+pub struct FieldInit {
+    // Lox Class
+    pub class: ValueHandle,
+    pub index: usize,
+    pub arg_count: usize,
 }
 
 pub struct Vm {
@@ -77,61 +170,805 @@ pub struct Vm {
     pub heap: Heap<LoxObj>,
     pub frames: Vec<CallFrame>,
     globals: HashMap<String, Value>,
+    // The host's globals while `eval_isolated` runs a snippet: readable, but
+    // never written to
+    host_globals: Option<HashMap<String, Value>>,
+    // Names of the globals defined with `const`, which can't be assigned or
+    // defined again
+    const_globals: HashSet<String>,
     sp: usize,
     // TODO: use a BTreeMap instead
     open_upvalues: Vec<(usize, ValueHandle)>,
     gray_stack: Vec<ValueHandle>,
     bytes_allocated: usize,
     next_gc: usize,
+    gc_grow_factor: usize,
+    // Whether to collect garbage on every allocation
+    stress_gc: bool,
+    deadline: Option<Instant>,
+    // Frames below the script loaded by `start`, until it finishes
+    resume_base: Option<usize>,
+    steps_per_resume: usize,
+    // Instructions the current call to `resume` may still start. None when
+    // running to completion.
+    step_limit: Option<usize>,
+    // Instructions run by the last call to `resume`
+    steps: usize,
+    // Whether `dispatch` returned because the step limit was reached
+    yielded: bool,
+    // Longest string, in bytes, concatenation may build
+    max_string_len: usize,
+    // Whether defining a global that already exists is an error
+    error_on_global_redefinition: bool,
+    // Live instances whose class has a `finalize` method, oldest first. Not
+    // GC roots.
+    finalizable: Vec<ValueHandle>,
+    // Collected instances waiting for their `finalize` method to run. These
+    // are roots, so the instances (and whatever they reference) survive until
+    // then.
+    pending_finalizers: Vec<ValueHandle>,
+    rng: Rng,
+    // Flushed when the buffer fills up and when `interpret` returns
+    writer: BufWriter<Box<dyn Write>>,
+    // Where `readline` and `input_number` read from
+    reader: Box<dyn BufRead>,
+    // Number of nested calls to `run`
+    run_depth: usize,
+    // Source line of the instruction that raised the last runtime error
+    error_line: Option<usize>,
+    // Value of the last script's top-level `return`
+    result: Value,
+    // Whether `interpret` prints a report of uncaught errors to `error_writer`
+    report_errors: bool,
+    error_writer: Box<dyn Write>,
+    // Outcomes of the test harness's `test` calls, if it's installed
+    test_tally: Option<TestTally>,
+}
+
+#[derive(Default, Clone, Copy)]
+struct TestTally {
+    passed: usize,
+    failed: usize,
 }
 
 impl Vm {
     pub fn new(heap: Heap<LoxObj>) -> Self {
-        Self {
+        // Output is buffered, so stdout's lock is only taken once per flush
+        // rather than held, which would block other threads from printing.
+        Self::with_writer(heap, Box::new(io::stdout()))
+    }
+
+    /// Creates a VM that collects garbage as `config` says rather than with the
+    /// defaults.
+    pub fn with_gc_config(heap: Heap<LoxObj>, config: GcConfig) -> Self {
+        let mut vm = Self::new(heap);
+
+        vm.next_gc = config.initial_threshold;
+        vm.gc_grow_factor = config.grow_factor;
+
+        vm
+    }
+
+    /// Creates a VM whose `print` output goes to `writer` instead of stdout.
+    /// Output is buffered, so it may only reach `writer` once `interpret`
+    /// returns.
+    pub fn with_writer(heap: Heap<LoxObj>, writer: Box<dyn Write>) -> Self {
+        let mut vm = Self {
             stack: vec![None; STACK_MAX],
             heap,
             frames: Vec::with_capacity(FRAMES_MAX),
             globals: HashMap::new(),
+            host_globals: None,
+            const_globals: HashSet::new(),
             sp: 0,
             open_upvalues: Vec::with_capacity(8),
             gray_stack: Vec::with_capacity(8),
             bytes_allocated: 0,
             next_gc: INITIAL_GC_THRESHOLD,
+            gc_grow_factor: GC_HEAP_GROW_FACTOR,
+            stress_gc: false,
+            deadline: None,
+            resume_base: None,
+            steps_per_resume: DEFAULT_STEPS_PER_RESUME,
+            step_limit: None,
+            steps: 0,
+            yielded: false,
+            max_string_len: DEFAULT_MAX_STRING_LEN,
+            error_on_global_redefinition: false,
+            finalizable: vec![],
+            pending_finalizers: vec![],
+            rng: Rng::default(),
+            writer: BufWriter::new(writer),
+            reader: Box::new(BufReader::new(io::stdin())),
+            run_depth: 0,
+            error_line: None,
+            result: Value::Nil,
+            report_errors: false,
+            error_writer: Box::new(io::stderr()),
+            test_tally: None,
+        };
+
+        vm.define_native("write", 1, natives::write);
+        vm.define_native("substring", 3, natives::substring);
+        vm.define_native("panic", 1, natives::panic);
+        vm.define_native("len", 1, natives::len);
+        vm.define_native("contains", 2, natives::contains);
+        vm.define_native("sorted_keys", 1, natives::sorted_keys);
+        vm.define_native("push", 2, natives::push);
+        vm.define_native("append", 2, natives::append);
+        vm.define_native("pop", 1, natives::pop);
+        vm.define_native("clone", 1, natives::clone);
+        vm.define_native("is_number", 1, natives::is_number);
+        vm.define_native("is_string", 1, natives::is_string);
+        vm.define_native("is_bool", 1, natives::is_bool);
+        vm.define_native("is_nil", 1, natives::is_nil);
+        vm.define_native("is_instance", 1, natives::is_instance);
+        vm.define_native("is_class", 1, natives::is_class);
+        vm.define_native("is_callable", 1, natives::is_callable);
+        vm.define_native("clock", 0, natives::clock);
+        vm.define_native("random", 0, natives::random);
+        vm.define_native("random_int", 2, natives::random_int);
+        vm.define_native("readline", 0, natives::readline);
+        vm.define_native("input_number", 0, natives::input_number);
+
+        vm
+    }
+
+    /// Makes the VM collect garbage on every allocation instead of once enough
+    /// has been allocated, to shake out objects that aren't rooted while they
+    /// should be. Very slow.
+    pub fn set_stress_gc(&mut self, enabled: bool) {
+        self.stress_gc = enabled;
+    }
+
+    /// Makes `function` available to scripts as the global `name`. Calls with
+    /// other than `arity` arguments fail before reaching it.
+    ///
+    /// A native returns numbers, booleans and nil as they are. Strings it
+    /// returns must be allocated on the VM's heap, with `new_string`, which is
+    /// safe because the value is rooted as soon as the native returns:
+    ///
+    /// ```
+    /// # use rslox::{vm::Vm, LoxError, Value};
+    /// fn greet(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    ///     let name = vm.get_str(args[0]).unwrap_or("stranger").to_owned();
+    ///     vm.new_string(format!("hello, {}", name))
+    /// }
+    /// ```
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        let handle = self.heap.insert(LoxObj::Native(Box::from(ObjNative {
+            name: name.to_owned(),
+            arity,
+            function,
+            is_marked: false,
+        })));
+
+        // Counted, but never collects, so it's safe before anything is rooted
+        self.bytes_allocated += lox_obj_size();
+
+        self.globals.insert(name.to_owned(), Value::Obj(handle));
+    }
+
+    pub fn writer(&mut self) -> &mut dyn Write {
+        &mut self.writer
+    }
+
+    /// Makes `interpret` print uncaught runtime errors, with the line they
+    /// were raised on and a trace of the calls leading there, before returning
+    /// them. Off by default.
+    pub fn set_report_errors(&mut self, report_errors: bool) {
+        self.report_errors = report_errors;
+    }
+
+    /// Sends error reports to `writer` instead of stderr.
+    pub fn set_error_writer(&mut self, writer: Box<dyn Write>) {
+        self.error_writer = writer;
+    }
+
+    /// Sets the longest string, in bytes, that concatenation may build before
+    /// failing with `LoxError::StringTooLong`. It bounds how much memory a
+    /// script can grab by repeatedly doubling a string.
+    pub fn set_max_string_len(&mut self, max_string_len: usize) {
+        self.max_string_len = max_string_len;
+    }
+
+    /// When enabled, a `var`, `fun` or `class` declaration of a global that's
+    /// already defined (natives included) fails with
+    /// `LoxError::GlobalAlreadyDefined` instead of replacing it.
+    pub fn set_error_on_global_redefinition(&mut self, enabled: bool) {
+        self.error_on_global_redefinition = enabled;
+    }
+
+    /// Makes `readline` and `input_number` read from `reader` instead of
+    /// stdin.
+    pub fn set_reader(&mut self, reader: Box<dyn BufRead>) {
+        self.reader = reader;
+    }
+
+    pub fn reader(&mut self) -> &mut dyn BufRead {
+        &mut self.reader
+    }
+
+    /// Reseeds the generator behind `random` and `random_int`, making their
+    /// results reproducible. VMs start with a clock-based seed.
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+
+    pub fn rng(&mut self) -> &mut Rng {
+        &mut self.rng
+    }
+
+    /// Returns a Lox string holding `value`, e.g. for a native to return.
+    /// Strings are interned, so this only allocates if no live string is
+    /// equal to `value`.
+    pub fn new_string(&mut self, value: String) -> Result<Value> {
+        if let Some(handle) = self.heap.interned(&value) {
+            return Ok(Value::Obj(handle));
+        }
+
+        let handle = self.alloc(LoxObj::Str(Box::from(ObjString {
+            value,
+            is_marked: false,
+        })))?;
+
+        self.heap.strings.insert(InternedStr(handle));
+
+        Ok(Value::Obj(handle))
+    }
+
+    /// Allocates an instance of `class` with the given fields, e.g. for a
+    /// native to return. Neither the class's field defaults nor its `init`
+    /// run. The fields' values are kept alive while the instance is
+    /// allocated, but the instance itself is only safe until the next
+    /// allocation unless it's rooted somewhere.
+    pub fn new_instance(&mut self, class: Value, fields: Vec<(String, Value)>) -> Result<Value> {
+        let handle = match class {
+            Value::Obj(handle) => match self.get_obj(handle)? {
+                LoxObj::Class(_) => handle,
+                _ => return Err(LoxError::InvalidClass),
+            },
+            _ => return Err(LoxError::InvalidClass),
+        };
+
+        let finalizable = self.find_method(handle, FINALIZE_STRING)?.is_some();
+
+        let base = self.sp;
+
+        self.push(class)?;
+        for (_, value) in &fields {
+            self.push(*value)?;
+        }
+
+        let instance = self.alloc_value(LoxObj::Instance(Box::from(ObjInstance {
+            class: handle,
+            fields: fields.into_iter().collect(),
+            is_marked: false,
+        })));
+
+        self.sp = base;
+
+        let instance = instance?;
+
+        if finalizable {
+            self.finalizable.push(self.get_handle(&instance)?);
+        }
+
+        Ok(instance)
+    }
+
+    /// Allocates a list of new Lox strings, e.g. for a native to return. The
+    /// list keeps each string alive as it's added, but the list itself is
+    /// only safe until the next allocation unless it's rooted somewhere.
+    pub fn new_string_list(&mut self, items: Vec<String>) -> Result<Value> {
+        let list = self.alloc_value(LoxObj::List(Box::from(ObjList {
+            items: Vec::with_capacity(items.len()),
+            is_marked: false,
+        })))?;
+
+        // The list roots the strings already in it while the rest allocate
+        self.push(list)?;
+
+        let result = self.fill_string_list(list, items);
+
+        self.pop()?;
+
+        result.map(|_| list)
+    }
+
+    fn fill_string_list(&mut self, list: Value, items: Vec<String>) -> Result<()> {
+        let handle = self.get_handle(&list)?;
+
+        for item in items {
+            let value = self.new_string(item)?;
+
+            match self.get_obj_mut(handle)? {
+                LoxObj::List(obj) => obj.items.push(value),
+                _ => return Err(LoxError::InternalVmError("not a list")),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Source line of the instruction currently executing in the innermost
+    /// frame, or `None` if it was compiled without line info.
+    pub fn current_line(&self) -> Option<usize> {
+        let frame = self.frames.last()?;
+
+        match self.get_obj(frame.function) {
+            Ok(LoxObj::Function(function)) => function.chunk.lines.get(frame.opcode_ip).copied(),
+            _ => None,
+        }
+    }
+
+    /// Returns the contents of `value` if it's a Lox string.
+    pub fn get_str(&self, value: Value) -> Option<&str> {
+        match value {
+            Value::Obj(handle) => match self.get_obj(handle) {
+                Ok(LoxObj::Str(s)) => Some(&s.value),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Runs a compiled script. With the test harness installed, a summary of
+    /// the tests it ran is printed at the end, and any failure is an error.
+    pub fn interpret(&mut self, function: Box<ObjFunction>) -> Result<()> {
+        let base_frames = self.frames.len();
+
+        let result = self
+            .load_script(function)
+            .and_then(|_| self.run(base_frames));
+
+        self.finish_script(base_frames, result)
+    }
+
+    /// Loads a compiled script to be run a few steps at a time by `resume`,
+    /// rather than all at once like `interpret`.
+    pub fn start(&mut self, function: Box<ObjFunction>) -> Result<()> {
+        let base_frames = self.frames.len();
+
+        let result = self.load_script(function);
+
+        if result.is_err() {
+            return self.finish_script(base_frames, result);
+        }
+
+        self.resume_base = Some(base_frames);
+
+        Ok(())
+    }
+
+    /// Runs the script loaded by `start` for at most `steps_per_resume`
+    /// instructions, then returns so the host can get on with other work. All
+    /// the script's state stays in the VM, so the next call carries on where
+    /// this one stopped. Instructions run by natives that call back into Lox
+    /// count towards the limit, but can't be interrupted, so they may take a
+    /// call over it.
+    ///
+    /// Once the script is done, or has failed, it's unloaded, along with the
+    /// test report and error report that `interpret` would give.
+    pub fn resume(&mut self) -> Result<StepResult> {
+        let base_frames = self
+            .resume_base
+            .ok_or(LoxError::InternalVmError("no script to resume"))?;
+
+        self.steps = 0;
+        self.step_limit = Some(self.steps_per_resume);
+
+        let result = self.run(base_frames);
+
+        self.step_limit = None;
+
+        if mem::take(&mut self.yielded) && result.is_ok() {
+            return Ok(StepResult::Running);
+        }
+
+        self.resume_base = None;
+
+        self.finish_script(base_frames, result)?;
+
+        Ok(StepResult::Done(self.result))
+    }
+
+    /// Sets how many instructions each call to `resume` runs.
+    pub fn set_steps_per_resume(&mut self, steps: usize) {
+        self.steps_per_resume = steps;
+    }
+
+    /// Number of instructions the last call to `resume` ran.
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    /// Records the outcome of the script running on top of `base_frames`,
+    /// reporting errors and tests, and flushes its output.
+    fn finish_script(&mut self, base_frames: usize, result: Result<()>) -> Result<()> {
+        self.record_error_line(&result);
+
+        // A script that returned left its value where its closure was;
+        // otherwise its frame is still there.
+        self.result = match result {
+            Ok(()) if self.frames.len() == base_frames => self.peek()?,
+            _ => Value::Nil,
+        };
+
+        if let Err(e) = &result {
+            if self.report_errors {
+                self.report_error(e)?;
+            }
+        }
+
+        let reported = self.report_tests();
+
+        let flushed = self.writer.flush().map_err(LoxError::IoError);
+
+        result.and(reported).and(flushed)
+    }
+
+    /// Writes `error` and the stack trace of the frames it unwound through,
+    /// innermost first, to the error writer.
+    fn report_error(&mut self, error: &LoxError) -> Result<()> {
+        // Anything the script printed came before the error
+        self.writer.flush().map_err(LoxError::IoError)?;
+
+        let mut report = format!("Runtime error: {:?}\n", error);
+
+        for (depth, frame) in self.frames.iter().enumerate().rev() {
+            let function = match self.get_obj(frame.function)? {
+                LoxObj::Function(function) => function,
+                _ => return Err(LoxError::InvalidObject),
+            };
+
+            let line = function
+                .chunk
+                .lines
+                .get(frame.opcode_ip)
+                .copied()
+                .unwrap_or(0);
+
+            // Functions compiled without debug info have no name
+            let name = match function.name {
+                Some(name) => format!("{}()", name),
+                None if depth == 0 => String::from("script"),
+                None => String::from("<fn>"),
+            };
+
+            report.push_str(&format!("[line {}] in {}\n", line, name));
         }
+
+        self.error_writer
+            .write_all(report.as_bytes())
+            .and_then(|_| self.error_writer.flush())
+            .map_err(LoxError::IoError)
+    }
+
+    fn record_error_line(&mut self, result: &Result<()>) {
+        self.error_line = match result {
+            Err(_) => self.current_line(),
+            Ok(_) => None,
+        };
     }
 
-    pub fn interpret(&mut self, closure: Box<ObjClosure>) -> Result<()> {
+    /// Pushes a frame calling the script `function`, ready to run.
+    fn load_script(&mut self, function: Box<ObjFunction>) -> Result<()> {
         // No GC alloc
-        let handle = self.heap.insert(LoxObj::Closure(closure));
+        let function = self.heap.insert(LoxObj::Function(function));
+
+        let handle = self.heap.insert(LoxObj::Closure(Box::from(ObjClosure {
+            function,
+            upvalues: vec![],
+            is_marked: false,
+        })));
 
         // Mark closure so it's not GCd
         mark_object(&self.heap, &mut self.gray_stack, &handle)?;
 
+        // The compiler put its objects in the heap without counting them
+        self.bytes_allocated = self.heap.objects.len() * lox_obj_size();
+
         let value = Value::Obj(handle);
 
         self.push(value)?;
 
-        self.call_value(value, 0)?;
+        self.call_value(value, 0)
+    }
+
+    /// Compiles and runs `source` against the VM's existing state, so it sees
+    /// the globals defined by the scripts run before it and its own are kept
+    /// for those run after it, e.g. for each line of a REPL. Whatever the
+    /// script leaves on the stack, even after an error, is cleared for the
+    /// next one.
+    pub fn eval(&mut self, source: &str) -> Result<()> {
+        // The compiler allocates straight into the VM's heap.
+        let mut compiler = Compiler::new(source.chars(), mem::take(&mut self.heap));
+        compiler.set_const_globals(self.const_globals.clone());
+
+        let compiled = compiler.compile();
+
+        self.heap = compiler.heap;
+        compiled?;
+
+        let base_frames = self.frames.len();
+        let base_sp = self.sp;
+
+        let result = self.interpret(Box::from(compiler.function));
+
+        self.frames.truncate(base_frames);
+        let closed = self.close_upvalues(base_sp);
+        self.sp = base_sp;
+
+        result.and(closed)
+    }
+
+    /// Compiles and runs `source` in isolation from the host's script state,
+    /// returning the value of a top-level `return` (or nil). The snippet can
+    /// read the host's globals, natives included, but its definitions and
+    /// assignments go to globals of its own that are dropped afterwards, so
+    /// neither the host nor later snippets see them.
+    ///
+    /// A returned object is only kept alive until the VM next collects
+    /// garbage, unless the host roots it somewhere, e.g. in a global.
+    pub fn eval_isolated(&mut self, source: &str) -> Result<Value> {
+        // The compiler allocates straight into the VM's heap.
+        let mut compiler = Compiler::new(source.chars(), mem::take(&mut self.heap));
+        compiler.set_top_level_return(true);
+
+        let compiled = compiler.compile();
+
+        self.heap = compiler.heap;
+        compiled?;
+
+        let base_frames = self.frames.len();
+        let base_sp = self.sp;
+
+        self.host_globals = Some(mem::take(&mut self.globals));
+        let host_consts = mem::take(&mut self.const_globals);
+
+        let result = self
+            .load_script(Box::from(compiler.function))
+            .and_then(|_| self.run(base_frames));
+
+        self.record_error_line(&result);
+
+        // A snippet that returned left its value where its closure was;
+        // otherwise its frame is still there.
+        let value = match result {
+            Ok(()) if self.frames.len() == base_frames => self.peek(),
+            Ok(()) => Ok(Value::Nil),
+            Err(e) => Err(e),
+        };
+
+        self.frames.truncate(base_frames);
+        let closed = self.close_upvalues(base_sp);
+        self.sp = base_sp;
+
+        if let Some(host_globals) = self.host_globals.take() {
+            self.globals = host_globals;
+        }
+
+        self.const_globals = host_consts;
+
+        let flushed = self.writer.flush().map_err(LoxError::IoError);
+
+        closed.and(flushed).and(value)
+    }
+
+    /// Calls `callee` with `args` and returns its result, running it to
+    /// completion before returning. Meant for natives calling back into Lox.
+    /// If the call fails, the stack and frames are unwound to where they were,
+    /// so the caller can recover from the error.
+    pub fn call(&mut self, callee: Value, args: &[Value]) -> Result<Value> {
+        let base_frames = self.frames.len();
+        let base_sp = self.sp;
+
+        let result = self.call_and_run(callee, args, base_frames);
+
+        if result.is_err() {
+            self.frames.truncate(base_frames);
+            self.close_upvalues(base_sp)?;
+            self.sp = base_sp;
+        }
+
+        result
+    }
+
+    fn call_and_run(&mut self, callee: Value, args: &[Value], base_frames: usize) -> Result<Value> {
+        self.push(callee)?;
+
+        for &arg in args {
+            self.push(arg)?;
+        }
+
+        self.call_value(callee, args.len())?;
+
+        // Natives have already returned; Lox functions run until they do.
+        if self.frames.len() > base_frames {
+            self.run(base_frames)?;
+        }
+
+        self.pop()
+    }
+
+    /// Defines the `assert_eq`, `assert_true` and `test` natives, and makes
+    /// `interpret` report how many tests passed and failed.
+    pub fn install_test_harness(&mut self) {
+        self.define_native("assert_eq", 2, natives::assert_eq);
+        self.define_native("assert_true", 1, natives::assert_true);
+        self.define_native("test", 2, natives::test);
+
+        self.test_tally = Some(TestTally::default());
+    }
+
+    /// Counts the outcome of a test run by the harness's `test` native.
+    pub fn record_test(&mut self, passed: bool) {
+        if let Some(tally) = &mut self.test_tally {
+            if passed {
+                tally.passed += 1;
+            } else {
+                tally.failed += 1;
+            }
+        }
+    }
+
+    fn report_tests(&mut self) -> Result<()> {
+        // Starts the next run's tally afresh, if the harness is installed
+        let tally = match self.test_tally.as_mut().map(mem::take) {
+            Some(tally) => tally,
+            None => return Ok(()),
+        };
+
+        writeln!(
+            self.writer,
+            "{} passed, {} failed",
+            tally.passed, tally.failed
+        )
+        .map_err(LoxError::IoError)?;
+
+        if tally.failed > 0 {
+            Err(LoxError::TestsFailed(tally.failed))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The value the script run by the last call to `interpret` returned with a
+    /// top-level `return` (see `Compiler::set_top_level_return`), or nil.
+    pub fn result(&self) -> Value {
+        self.result
+    }
+
+    /// Source line of the instruction that raised the runtime error returned by
+    /// the last call to `interpret`, if it was compiled with line info.
+    pub fn error_line(&self) -> Option<usize> {
+        self.error_line
+    }
+
+    /// The globals the script defined, sorted by name, for inspecting a VM's
+    /// state. Natives are left out.
+    pub fn globals_snapshot(&self) -> Vec<(String, Value)> {
+        let mut globals: Vec<_> = self
+            .globals
+            .iter()
+            .filter(|(_, value)| match value {
+                Value::Obj(handle) => !matches!(self.heap.get(handle), Some(LoxObj::Native(_))),
+                _ => true,
+            })
+            .map(|(name, value)| (name.clone(), *value))
+            .collect();
+
+        globals.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        globals
+    }
+
+    /// Like `interpret`, but gives up with `LoxError::TimedOut` once `deadline`
+    /// has passed. The clock is only checked every `DEADLINE_CHECK_INTERVAL`
+    /// instructions.
+    pub fn interpret_with_deadline(
+        &mut self,
+        function: Box<ObjFunction>,
+        deadline: Instant,
+    ) -> Result<()> {
+        self.deadline = Some(deadline);
+
+        let result = self.interpret(function);
+
+        self.deadline = None;
+
+        result
+    }
+
+    /// Runs until the code of the innermost frame runs out, or until a return
+    /// leaves only `base_frames` frames.
+    fn run(&mut self, base_frames: usize) -> Result<()> {
+        if self.run_depth == RUN_DEPTH_MAX {
+            return Err(LoxError::StackOverflow(self.frames.len()));
+        }
+
+        self.run_depth += 1;
+        let result = self.dispatch(base_frames);
+        self.run_depth -= 1;
 
-        self.run()
+        result
     }
 
-    fn run(&mut self) -> Result<()> {
-        while let Some(opcode) = self.fetch_opcode() {
-            match OpCode::from(*opcode) {
+    fn dispatch(&mut self, base_frames: usize) -> Result<()> {
+        let mut ticks: usize = 0;
+
+        loop {
+            // Only the outermost run can stop, since a nested one has callers
+            // on the Rust stack waiting for it to finish.
+            if let Some(limit) = self.step_limit {
+                if self.steps >= limit && self.run_depth == 1 {
+                    self.yielded = true;
+
+                    return Ok(());
+                }
+            }
+
+            let opcode = match self.fetch_opcode()? {
+                Some(opcode) => opcode,
+                None => break,
+            };
+
+            if self.step_limit.is_some() {
+                self.steps += 1;
+            }
+
+            if let Some(deadline) = self.deadline {
+                ticks += 1;
+
+                if ticks == DEADLINE_CHECK_INTERVAL {
+                    ticks = 0;
+
+                    if Instant::now() >= deadline {
+                        return Err(LoxError::TimedOut);
+                    }
+                }
+            }
+
+            match OpCode::try_from(opcode)? {
                 OpCode::Return => {
                     let value = self.pop()?;
 
-                    let popped_frame = self.frames.pop().unwrap();
+                    let popped_frame = self
+                        .frames
+                        .pop()
+                        .ok_or(LoxError::InternalVmError("no frame"))?;
 
                     self.close_upvalues(popped_frame.fp)?;
 
-                    self.sp = popped_frame.fp;
+                    match popped_frame.kind {
+                        FrameKind::Call => {
+                            self.sp = popped_frame.fp;
 
-                    self.push(value)?;
+                            self.push(value)?;
+                        }
+                        FrameKind::FieldInit(field_init) => {
+                            self.finish_field_init(popped_frame.fp, field_init, value)?
+                        }
+                        FrameKind::Finalizer => self.sp = popped_frame.fp,
+                        FrameKind::Generator(handle) => {
+                            self.sp = popped_frame.fp;
+
+                            match self.get_obj_mut(handle)? {
+                                LoxObj::Generator(generator) => {
+                                    generator.state = GeneratorState::Done;
+                                }
+                                _ => return Err(LoxError::InternalVmError("not a generator")),
+                            }
+                        }
+                    }
+
+                    if self.frames.len() == base_frames {
+                        return Ok(());
+                    }
                 }
                 OpCode::Constant => {
-                    let value = self.fetch_const();
+                    let value = self.fetch_const()?;
 
                     self.push(value)?
                 }
@@ -156,18 +993,25 @@ impl Vm {
 
                             match (obj_a, obj_b) {
                                 (LoxObj::Str(a), LoxObj::Str(b)) => {
-                                    let mut value = String::from(&a.value);
+                                    let len = a.value.len() + b.value.len();
+
+                                    if len > self.max_string_len {
+                                        return Err(LoxError::StringTooLong(len));
+                                    }
+
+                                    let mut value = String::with_capacity(len);
+                                    value.push_str(&a.value);
                                     value.push_str(&b.value);
 
-                                    let lox_val =
-                                        self.alloc_value(LoxObj::Str(Box::from(ObjString {
-                                            value,
-                                            is_marked: false,
-                                        })));
+                                    let lox_val = self.new_string(value)?;
 
                                     self.push(lox_val)?;
                                 }
-                                _ => return Err(LoxError::TypeError),
+                                _ => {
+                                    let value = self.concat_to_string(a, b)?;
+
+                                    self.push(value)?;
+                                }
                             }
                         }
                         _ => return Err(LoxError::InvalidTypeForAddition),
@@ -176,8 +1020,28 @@ impl Vm {
                 OpCode::Subtract => binary_op!(-, self),
                 OpCode::Multiply => binary_op!(*, self),
                 OpCode::Divide => binary_op!(/, self),
+                OpCode::BitAnd => bitwise_op!(&, self),
+                OpCode::BitOr => bitwise_op!(|, self),
+                OpCode::BitXor => bitwise_op!(^, self),
+                OpCode::BitNot => {
+                    let n = self.pop_integer()?;
 
-                OpCode::Nil => self.push(Value::Nil)?,
+                    self.push(Value::Number(!n as f64))?;
+                }
+                OpCode::ShiftLeft => {
+                    let amount = shift_amount(self.pop_integer()?)?;
+                    let n = self.pop_integer()?;
+
+                    self.push(Value::Number((n << amount) as f64))?;
+                }
+                OpCode::ShiftRight => {
+                    let amount = shift_amount(self.pop_integer()?)?;
+                    let n = self.pop_integer()?;
+
+                    self.push(Value::Number((n >> amount) as f64))?;
+                }
+
+                OpCode::Nil => self.push(Value::Nil)?,
                 OpCode::True => self.push(Value::Bool(true))?,
                 OpCode::False => self.push(Value::Bool(false))?,
 
@@ -190,39 +1054,63 @@ impl Vm {
                     let b = self.pop()?;
                     let a = self.pop()?;
 
-                    match (a, b) {
-                        (Value::Number(a), Value::Number(b)) => {
-                            let cmp = a.eq(&b);
-                            self.push(Value::Bool(cmp))?;
-                        }
-                        (Value::Obj(handle_a), Value::Obj(handle_b)) => {
-                            let obj_a = self.get_obj(handle_a)?;
-                            let obj_b = self.get_obj(handle_b)?;
-
-                            match (obj_a, obj_b) {
-                                (LoxObj::Str(a), LoxObj::Str(b)) => {
-                                    let cmp = a.value == b.value;
+                    // Everything but two numbers goes through `values_equal`, so
+                    // `==` agrees with the natives; mismatched types aren't equal
+                    let cmp = match (a, b) {
+                        (Value::Number(a), Value::Number(b)) => a == b,
+                        _ => self.values_equal(a, b),
+                    };
 
-                                    self.push(Value::Bool(cmp))?;
-                                }
-                                _ => return Err(LoxError::TypeError),
-                            }
-                        }
-                        _ => return Err(LoxError::InvalidTypeForEquals),
-                    }
+                    self.push(Value::Bool(cmp))?;
                 }
                 OpCode::Greater => binary_op!(>, self, Bool),
                 OpCode::Less => binary_op!(<, self, Bool),
 
                 OpCode::Print => {
                     let value = self.pop()?;
-                    println!("{:?}", value);
+
+                    match self.call_to_string(value)? {
+                        Some(s) => writeln!(self.writer, "{}", s),
+                        None => writeln!(self.writer, "{}", value),
+                    }
+                    .map_err(LoxError::IoError)?;
                 }
                 OpCode::Pop => {
                     self.pop()?;
                 }
-                OpCode::DefineGlobal => {
+                OpCode::Dup => {
+                    let value = self.peek()?;
+
+                    self.push(value)?;
+                }
+                OpCode::Assert => {
+                    let text = self.fetch_const()?;
+                    let condition = self.pop()?;
+
+                    if condition.is_falsey() {
+                        let text = self.get_str(text).unwrap_or_default();
+
+                        return Err(LoxError::AssertionFailed(format!(
+                            "assertion failed: {}",
+                            text
+                        )));
+                    }
+                }
+                OpCode::DefineGlobal | OpCode::DefineConst => {
                     let name = self.fetch_str_const()?;
+
+                    if self.const_globals.contains(&name) {
+                        return Err(LoxError::ConstAssignment(name));
+                    }
+
+                    if self.error_on_global_redefinition && self.globals.contains_key(&name) {
+                        return Err(LoxError::GlobalAlreadyDefined(name));
+                    }
+
+                    if opcode == OpCode::DefineConst as u8 {
+                        self.const_globals.insert(name.clone());
+                    }
+
                     let value = self.pop()?;
                     self.globals.insert(name, value);
                 }
@@ -234,15 +1122,27 @@ impl Vm {
                     let value = *self
                         .globals
                         .get(&name)
-                        .ok_or(LoxError::InternalError(Internal::GlobalLookupFailure))?;
+                        .or_else(|| self.host_globals.as_ref()?.get(&name))
+                        .ok_or_else(|| LoxError::UndefinedVariable(name.clone()))?;
 
                     self.push(value)?;
                 }
                 OpCode::SetGlobal => {
                     let name = self.fetch_str_const()?;
 
-                    if !self.globals.contains_key(&name) {
-                        return Err(LoxError::InternalError(Internal::GlobalLookupFailure));
+                    // Assignment never declares a global implicitly
+                    // Assigning a host global shadows it instead of changing it
+                    let is_host_global = match &self.host_globals {
+                        Some(host_globals) => host_globals.contains_key(&name),
+                        None => false,
+                    };
+
+                    if !self.globals.contains_key(&name) && !is_host_global {
+                        return Err(LoxError::UndefinedVariable(name));
+                    }
+
+                    if self.const_globals.contains(&name) {
+                        return Err(LoxError::ConstAssignment(name));
                     }
 
                     let value = self.peek()?;
@@ -250,36 +1150,40 @@ impl Vm {
                     self.globals.insert(name, value);
                 }
                 OpCode::GetLocal => {
-                    let idx = self.fetch() as usize;
-                    let fp = self.current_frame().fp;
-                    let value = self.stack[fp + idx].ok_or(LoxError::StackOverflow)?;
+                    let slot = self.local_slot("GetLocal")?;
+                    let value = self.stack[slot]
+                        .ok_or(LoxError::InternalError(Internal::CorruptedStack))?;
                     self.push(value)?;
                 }
                 OpCode::SetLocal => {
-                    let idx = self.fetch() as usize;
+                    let slot = self.local_slot("SetLocal")?;
                     let value = self.peek()?;
-                    let fp = self.current_frame().fp;
-                    self.stack[fp + idx] = Some(value);
+                    self.stack[slot] = Some(value);
                 }
                 OpCode::JumpIfFalse => {
-                    let offset = self.fetch16() as usize;
+                    let offset = self.fetch16()? as usize;
 
                     let value = self.peek()?;
 
                     if value.is_falsey() {
-                        self.current_frame_mut().ip += offset;
+                        self.jump_forward(offset)?;
                     }
                 }
                 OpCode::Jump => {
-                    let offset = self.fetch16() as usize;
-                    self.current_frame_mut().ip += offset;
+                    let offset = self.fetch16()? as usize;
+                    self.jump_forward(offset)?;
                 }
                 OpCode::Loop => {
-                    let offset = self.fetch16() as usize;
-                    self.current_frame_mut().ip -= offset;
+                    let offset = self.fetch16()? as usize;
+                    let frame = self.current_frame_mut()?;
+
+                    frame.ip = frame
+                        .ip
+                        .checked_sub(offset)
+                        .ok_or(LoxError::InternalVmError("loop jump out of bounds"))?;
                 }
                 OpCode::Call => {
-                    let arg_count = self.fetch() as usize;
+                    let arg_count = self.fetch()? as usize;
 
                     let value =
                         self.stack[self.sp - 1 - arg_count].ok_or(LoxError::StackUnderflow)?;
@@ -287,22 +1191,31 @@ impl Vm {
                     self.call_value(value, arg_count)?;
                 }
                 OpCode::Closure => {
-                    let value = self.fetch_const();
+                    let value = self.fetch_const()?;
+                    let function = self.get_handle(&value)?;
+
+                    let upvalue_count = match self.get_obj(function)? {
+                        LoxObj::Function(function) => Ok(function.upvalue_count),
+                        _ => Err(LoxError::InternalVmError("not a function")),
+                    }?;
+
+                    // Each evaluation gets its own closure so that it captures
+                    // the variables live at that point.
+                    let value = self.alloc_value(LoxObj::Closure(Box::from(ObjClosure {
+                        function,
+                        upvalues: Vec::with_capacity(upvalue_count),
+                        is_marked: false,
+                    })))?;
                     let closure_handle = self.get_handle(&value)?;
 
                     self.push(value)?;
 
-                    let upvalue_count = match self.get_obj(closure_handle)? {
-                        LoxObj::Closure(closure) => Ok(closure.upvalue_count),
-                        _ => Err(LoxError::InternalVmError("not a closure")),
-                    }?;
-
                     for _ in 0..upvalue_count {
-                        let is_local = self.fetch() != 0;
-                        let index = self.fetch() as usize;
+                        let is_local = self.fetch()? != 0;
+                        let index = self.fetch()? as usize;
 
                         if is_local {
-                            let handle = self.capture_upvalue(index);
+                            let handle = self.capture_upvalue(index)?;
 
                             match self.get_obj_mut(closure_handle)? {
                                 LoxObj::Closure(closure) => {
@@ -323,16 +1236,15 @@ impl Vm {
                     }
                 }
                 OpCode::GetUpvalue => {
-                    let idx = self.fetch() as usize;
+                    let idx = self.fetch()? as usize;
                     let upvalue_handle = self.current_closure()?.upvalues[idx];
 
                     match self.get_obj(upvalue_handle)? {
                         LoxObj::Upvalue(upvalue) => {
-                            let value = match upvalue.value {
-                                Some(value) => value,
-                                None => {
-                                    self.stack[upvalue.location].ok_or(LoxError::StackOverflow)?
-                                }
+                            let value = match upvalue.state {
+                                UpvalueState::Open(location) => self.stack[location]
+                                    .ok_or(LoxError::InternalError(Internal::CorruptedStack))?,
+                                UpvalueState::Closed(value) => value,
                             };
 
                             self.push(value)?;
@@ -341,7 +1253,7 @@ impl Vm {
                     }
                 }
                 OpCode::SetUpvalue => {
-                    let idx = self.fetch() as usize;
+                    let idx = self.fetch()? as usize;
                     let value = self.peek()?;
 
                     let upvalue_handle = &self.current_closure()?.upvalues[idx];
@@ -351,12 +1263,12 @@ impl Vm {
                         .get_mut(upvalue_handle)
                         .ok_or(LoxError::InternalError(Internal::InvalidHandle))?
                     {
-                        LoxObj::Upvalue(upvalue) => match upvalue.value {
-                            Some(_) => {
-                                upvalue.value = Some(value);
+                        LoxObj::Upvalue(upvalue) => match upvalue.state {
+                            UpvalueState::Open(location) => {
+                                self.stack[location] = Some(value);
                             }
-                            None => {
-                                self.stack[upvalue.location] = Some(value);
+                            UpvalueState::Closed(_) => {
+                                upvalue.state = UpvalueState::Closed(value);
                             }
                         },
                         _ => return Err(LoxError::InternalVmError("handle not an upvalue")),
@@ -371,9 +1283,13 @@ impl Vm {
 
                     let lox_val = self.alloc_value(LoxObj::Class(Box::from(ObjClass {
                         name,
+                        superclass: None,
                         methods: HashMap::new(),
+                        fields: vec![],
+                        private_methods: HashSet::new(),
+                        method_cache: HashMap::new(),
                         is_marked: false,
-                    })));
+                    })))?;
 
                     self.push(lox_val)?;
                 }
@@ -430,11 +1346,21 @@ impl Vm {
                 OpCode::Method => {
                     let name = self.fetch_str_const()?;
 
-                    self.define_method(name)?;
+                    self.define_method(name, false)?;
+                }
+                OpCode::PrivateMethod => {
+                    let name = self.fetch_str_const()?;
+
+                    self.define_method(name, true)?;
+                }
+                OpCode::Field => {
+                    let name = self.fetch_str_const()?;
+
+                    self.define_field(name)?;
                 }
                 OpCode::Invoke => {
                     let name = self.fetch_str_const()?;
-                    let arg_count = self.fetch() as usize;
+                    let arg_count = self.fetch()? as usize;
                     self.invoke(name, arg_count)?;
                 }
                 OpCode::Inherit => {
@@ -444,8 +1370,10 @@ impl Vm {
                     let superclass_handle = self.get_handle(&superclass_value)?;
                     let superclass = self.get_obj(superclass_handle)?;
 
-                    let superclass_methods = match superclass {
-                        LoxObj::Class(superclass) => Ok(superclass.methods.clone()),
+                    // Methods are looked up through the superclass link, but
+                    // field defaults are copied since every instance runs them all.
+                    let superclass_fields = match superclass {
+                        LoxObj::Class(superclass) => Ok(superclass.fields.clone()),
                         _ => Err(LoxError::InvalidSuperClass),
                     }?;
 
@@ -454,7 +1382,8 @@ impl Vm {
 
                     match subclass {
                         LoxObj::Class(subclass) => {
-                            subclass.methods = superclass_methods;
+                            subclass.superclass = Some(superclass_handle);
+                            subclass.fields = superclass_fields;
                         }
                         _ => return Err(LoxError::InvalidSubClass),
                     }
@@ -475,7 +1404,7 @@ impl Vm {
                 }
                 OpCode::SuperInvoke => {
                     let name = self.fetch_str_const()?;
-                    let arg_count = self.fetch() as usize;
+                    let arg_count = self.fetch()? as usize;
                     let value = self.pop()?;
 
                     match value {
@@ -485,12 +1414,403 @@ impl Vm {
                         _ => return Err(LoxError::InvalidObject),
                     }
                 }
+                OpCode::BuildMap => {
+                    let entry_count = self.fetch()? as usize;
+
+                    // Allocate before popping so the entries stay rooted on the stack.
+                    let map_value = self.alloc_value(LoxObj::Map(Box::from(ObjMap {
+                        entries: HashMap::with_capacity(entry_count),
+                        is_marked: false,
+                    })))?;
+
+                    let mut entries = HashMap::with_capacity(entry_count);
+
+                    for i in (1..=entry_count).rev() {
+                        let key = self.stack[self.sp - 2 * i].ok_or(LoxError::StackUnderflow)?;
+                        let value =
+                            self.stack[self.sp - 2 * i + 1].ok_or(LoxError::StackUnderflow)?;
+
+                        entries.insert(self.map_key(key)?, value);
+                    }
+
+                    for _ in 0..2 * entry_count {
+                        self.pop()?;
+                    }
+
+                    match self.get_obj_mut(self.get_handle(&map_value)?)? {
+                        LoxObj::Map(map) => map.entries = entries,
+                        _ => return Err(LoxError::InternalVmError("not a map")),
+                    }
+
+                    self.push(map_value)?;
+                }
+                OpCode::BuildList => {
+                    let item_count = self.fetch()? as usize;
+
+                    // Allocate before popping so the items stay rooted on the stack.
+                    let list_value = self.alloc_value(LoxObj::List(Box::from(ObjList {
+                        items: Vec::with_capacity(item_count),
+                        is_marked: false,
+                    })))?;
+
+                    let items = self.stack[self.sp - item_count..self.sp]
+                        .iter()
+                        .map(|item| item.ok_or(LoxError::StackUnderflow))
+                        .collect::<Result<Vec<_>>>()?;
+
+                    for _ in 0..item_count {
+                        self.pop()?;
+                    }
+
+                    match self.get_obj_mut(self.get_handle(&list_value)?)? {
+                        LoxObj::List(list) => list.items = items,
+                        _ => return Err(LoxError::InternalVmError("not a list")),
+                    }
+
+                    self.push(list_value)?;
+                }
+                OpCode::Index => {
+                    let key = self.pop()?;
+                    let target = self.pop()?;
+
+                    let value = match self.get_obj(self.get_handle(&target)?)? {
+                        LoxObj::Map(map) => {
+                            let key = self.map_key(key)?;
+                            map.entries.get(&key).copied().unwrap_or(Value::Nil)
+                        }
+                        LoxObj::List(list) => list.items[list_index(key, list.items.len())?],
+                        _ => return Err(LoxError::NotIndexable),
+                    };
+
+                    self.push(value)?;
+                }
+                OpCode::SetIndex => {
+                    let value = self.pop()?;
+                    let key = self.pop()?;
+                    let target = self.pop()?;
+
+                    // Only needed (and only checked) if the target is a map
+                    let map_key = self.map_key(key);
+
+                    match self.get_obj_mut(self.get_handle(&target)?)? {
+                        LoxObj::Map(map) => {
+                            map.entries.insert(map_key?, value);
+                        }
+                        LoxObj::List(list) => {
+                            let index = list_index(key, list.items.len())?;
+                            list.items[index] = value;
+                        }
+                        _ => return Err(LoxError::NotIndexable),
+                    };
+
+                    self.push(value)?;
+                }
+                OpCode::GetIter => {
+                    let target = self.peek()?;
+                    let handle = self
+                        .get_handle(&target)
+                        .map_err(|_| LoxError::NotIterable)?;
+
+                    match self.get_obj(handle)? {
+                        LoxObj::List(_) | LoxObj::Str(_) => {
+                            // The target stays rooted on the stack while allocating.
+                            let iterator =
+                                self.alloc_value(LoxObj::Iterator(Box::from(ObjIterator {
+                                    target: handle,
+                                    index: 0,
+                                    is_marked: false,
+                                })))?;
+
+                            self.pop()?;
+                            self.push(iterator)?;
+                        }
+                        // The iterator `iter()` returns replaces the instance.
+                        LoxObj::Instance(_) => self.invoke(ITER_STRING.to_owned(), 0)?,
+                        // A generator is its own iterator
+                        LoxObj::Generator(_) => (),
+                        _ => return Err(LoxError::NotIterable),
+                    }
+                }
+                OpCode::IterDone => {
+                    let iterator = self.peek()?;
+                    let handle = self
+                        .get_handle(&iterator)
+                        .map_err(|_| LoxError::NotIterable)?;
+
+                    match self.get_obj(handle)? {
+                        LoxObj::Iterator(iterator) => {
+                            let len = match self.get_obj(iterator.target)? {
+                                LoxObj::List(list) => list.items.len(),
+                                LoxObj::Str(s) => s.value.len(),
+                                _ => return Err(LoxError::InternalVmError("not iterable")),
+                            };
+                            let done = iterator.index >= len;
+
+                            self.pop()?;
+                            self.push(Value::Bool(done))?;
+                        }
+                        LoxObj::Instance(_) => self.invoke(DONE_STRING.to_owned(), 0)?,
+                        LoxObj::Generator(_) => {
+                            let done = !self.generator_ready(handle)?;
+
+                            self.pop()?;
+                            self.push(Value::Bool(done))?;
+                        }
+                        _ => return Err(LoxError::NotIterable),
+                    }
+                }
+                OpCode::IterNext => {
+                    let iterator = self.peek()?;
+                    let handle = self
+                        .get_handle(&iterator)
+                        .map_err(|_| LoxError::NotIterable)?;
+
+                    match self.get_obj(handle)? {
+                        LoxObj::Iterator(iterator) => {
+                            let index = iterator.index;
+
+                            let (value, next_index) = match self.get_obj(iterator.target)? {
+                                LoxObj::List(list) => match list.items.get(index) {
+                                    Some(&item) => (item, index + 1),
+                                    None => {
+                                        return Err(LoxError::IndexOutOfRange(
+                                            index,
+                                            list.items.len(),
+                                        ))
+                                    }
+                                },
+                                LoxObj::Str(s) => match s.value[index..].chars().next() {
+                                    Some(c) => {
+                                        // The iterator stays rooted on the stack while allocating.
+                                        let value = self.new_string(c.to_string())?;
+                                        (value, index + c.len_utf8())
+                                    }
+                                    None => {
+                                        return Err(LoxError::IndexOutOfRange(index, s.value.len()))
+                                    }
+                                },
+                                _ => return Err(LoxError::InternalVmError("not iterable")),
+                            };
+
+                            match self.get_obj_mut(handle)? {
+                                LoxObj::Iterator(iterator) => iterator.index = next_index,
+                                _ => return Err(LoxError::InternalVmError("not an iterator")),
+                            }
+
+                            self.pop()?;
+                            self.push(value)?;
+                        }
+                        LoxObj::Instance(_) => self.invoke(NEXT_STRING.to_owned(), 0)?,
+                        LoxObj::Generator(_) => {
+                            let value = self.generator_next(handle)?;
+
+                            self.pop()?;
+                            self.push(value)?;
+                        }
+                        _ => return Err(LoxError::NotIterable),
+                    }
+                }
+                OpCode::Yield => {
+                    let value = self.pop()?;
+
+                    self.suspend_generator(value)?;
+
+                    if self.frames.len() == base_frames {
+                        return Ok(());
+                    }
+                }
             };
+
+            // Finalizers run between instructions, where the stack is consistent.
+            if !self.pending_finalizers.is_empty() {
+                self.run_finalizers()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Pushes a frame calling `finalize` for each instance awaiting
+    /// finalization, on top of whatever was executing. The oldest instance's
+    /// frame goes on top so that it runs first.
+    fn run_finalizers(&mut self) -> Result<()> {
+        while let Some(instance) = self.pending_finalizers.pop() {
+            self.call_finalizer(instance)?;
         }
 
         Ok(())
     }
 
+    fn call_finalizer(&mut self, instance: ValueHandle) -> Result<()> {
+        let class = match self.get_obj(instance)? {
+            LoxObj::Instance(instance) => instance.class,
+            _ => return Err(LoxError::NonInstance),
+        };
+
+        let closure = match self.find_method(class, FINALIZE_STRING)? {
+            Some((method, _)) => self.get_handle(&method)?,
+            None => return Err(LoxError::UndefinedMethod(FINALIZE_STRING.to_owned())),
+        };
+
+        let function = self.closure_function(closure)?;
+
+        match self.get_obj(function)? {
+            LoxObj::Function(function) if function.arity == 0 => (),
+            _ => return Err(LoxError::InvalidArguments("finalize takes no arguments")),
+        }
+
+        self.push(Value::Obj(instance))?;
+
+        self.push_frame(CallFrame {
+            closure,
+            function,
+            ip: 0,
+            opcode_ip: 0,
+            fp: self.sp - 1,
+            kind: FrameKind::Finalizer,
+        })
+    }
+
+    /// Moves unmarked finalizable instances to `pending_finalizers` and marks
+    /// them so this collection doesn't free them. They leave `finalizable` for
+    /// good: an instance that `finalize` stores somewhere (resurrects) is
+    /// collected normally the next time it becomes garbage, without running
+    /// `finalize` again.
+    fn queue_finalizers(&mut self) -> Result<()> {
+        let mut collected = vec![];
+
+        for &handle in &self.finalizable {
+            match self.get_obj(handle)? {
+                LoxObj::Instance(instance) if !instance.is_marked => collected.push(handle),
+                LoxObj::Instance(_) => (),
+                _ => return Err(LoxError::NonInstance),
+            }
+        }
+
+        if collected.is_empty() {
+            return Ok(());
+        }
+
+        self.finalizable
+            .retain(|handle| !collected.contains(handle));
+
+        for handle in &collected {
+            mark_object(&self.heap, &mut self.gray_stack, handle)?;
+        }
+
+        self.pending_finalizers.extend(collected);
+
+        // Keep everything the resurrected instances reference alive too
+        self.trace_references()
+    }
+
+    /// Reads a local's operand and returns its stack slot, which must be below
+    /// the top of the stack. Anything else means the compiler emitted a bad
+    /// index.
+    fn local_slot(&mut self, opcode: &'static str) -> Result<usize> {
+        let slot = self.current_frame()?.fp + self.fetch()? as usize;
+
+        if slot < self.sp {
+            Ok(slot)
+        } else {
+            Err(LoxError::InternalError(Internal::LocalOutOfRange(
+                opcode, slot,
+            )))
+        }
+    }
+
+    /// Lox equality: numbers, booleans and nil by value, and objects by
+    /// identity. Strings are interned, so equal strings are the same object.
+    pub fn values_equal(&self, a: Value, b: Value) -> bool {
+        match (a, b) {
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Nil, Value::Nil) => true,
+            (Value::Obj(a), Value::Obj(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    fn map_key(&self, value: Value) -> Result<MapKey> {
+        match value {
+            Value::Number(n) => Ok(MapKey::number(n)),
+            Value::Obj(handle) => match self.get_obj(handle)? {
+                LoxObj::Str(s) => Ok(MapKey::Str(s.value.clone())),
+                _ => Err(LoxError::UnhashableKey),
+            },
+            _ => Err(LoxError::UnhashableKey),
+        }
+    }
+
+    /// Calls `to_string()` on `value` if it's an instance whose class defines
+    /// it, and returns the string it returned.
+    fn call_to_string(&mut self, value: Value) -> Result<Option<String>> {
+        let class = match value {
+            Value::Obj(handle) => match self.get_obj(handle)? {
+                LoxObj::Instance(instance) => instance.class,
+                _ => return Ok(None),
+            },
+            _ => return Ok(None),
+        };
+
+        let method = match self.find_method(class, TO_STRING_STRING)? {
+            Some((method, _)) => method,
+            None => return Ok(None),
+        };
+
+        let base_frames = self.frames.len();
+
+        // The instance goes where the callee would, as the method's receiver
+        self.push(value)?;
+        self.call_value(method, 0)?;
+        self.run(base_frames)?;
+
+        let result = self.pop()?;
+
+        match self.get_str(result) {
+            Some(s) => Ok(Some(s.to_owned())),
+            None => Err(LoxError::TypeError),
+        }
+    }
+
+    /// Adds a string and an instance whose class defines `to_string()`, in
+    /// either order.
+    fn concat_to_string(&mut self, a: Value, b: Value) -> Result<Value> {
+        if self.get_str(a).is_none() && self.get_str(b).is_none() {
+            return Err(LoxError::TypeError);
+        }
+
+        // Both operands must survive collections during `to_string()`
+        self.push(a)?;
+        self.push(b)?;
+
+        let a = self.concat_operand(a)?;
+        let b = self.concat_operand(b)?;
+
+        self.pop()?;
+        self.pop()?;
+
+        let (a, b) = match (a, b) {
+            (Some(a), Some(b)) => (a, b),
+            _ => return Err(LoxError::TypeError),
+        };
+
+        let len = a.len() + b.len();
+
+        if len > self.max_string_len {
+            return Err(LoxError::StringTooLong(len));
+        }
+
+        self.new_string(a + &b)
+    }
+
+    fn concat_operand(&mut self, value: Value) -> Result<Option<String>> {
+        match self.get_str(value) {
+            Some(s) => Ok(Some(s.to_owned())),
+            None => self.call_to_string(value),
+        }
+    }
+
     fn invoke(&mut self, name: String, arg_count: usize) -> Result<()> {
         let value = self.stack[self.sp - 1 - arg_count].ok_or(LoxError::StackUnderflow)?;
 
@@ -501,6 +1821,7 @@ impl Vm {
 
         let instance = match self.get_obj(handle)? {
             LoxObj::Instance(obj) => obj,
+            LoxObj::Generator(_) => return self.invoke_generator(handle, name, arg_count),
             _ => return Err(LoxError::InvalidObject),
         };
 
@@ -515,49 +1836,153 @@ impl Vm {
         self.invoke_from_class(class_handle, name, arg_count)
     }
 
-    fn invoke_from_class(
+    /// Calls `next()` or `done()` on a generator.
+    fn invoke_generator(
         &mut self,
         handle: ValueHandle,
         name: String,
         arg_count: usize,
     ) -> Result<()> {
-        match self.get_obj(handle)? {
-            LoxObj::Class(class) => {
-                let methods = &class.methods;
+        if arg_count != 0 {
+            return Err(LoxError::InvalidArguments(
+                "generator methods take no arguments",
+            ));
+        }
 
-                match methods.get(&name) {
-                    Some(&value) => self.call_value(value, arg_count),
-                    _ => Err(LoxError::UndefinedMethod(name)),
-                }
-            }
-            _ => Err(LoxError::InvalidClass),
+        let result = if name == NEXT_STRING {
+            self.generator_next(handle)?
+        } else if name == DONE_STRING {
+            Value::Bool(!self.generator_ready(handle)?)
+        } else {
+            return Err(LoxError::UndefinedMethod(name));
+        };
+
+        // The result replaces the generator
+        self.pop()?;
+        self.push(result)
+    }
+
+    fn invoke_from_class(
+        &mut self,
+        handle: ValueHandle,
+        name: String,
+        arg_count: usize,
+    ) -> Result<()> {
+        match self.resolve_method(handle, &name)? {
+            Some(method) => self.call_value(method, arg_count),
+            None => Err(LoxError::UndefinedMethod(name)),
         }
     }
 
     fn bind_method(&mut self, handle: ValueHandle, name: String) -> Result<Value> {
-        let class = match self.get_obj(handle)? {
-            LoxObj::Class(class) => class,
-            _ => return Err(LoxError::InvalidClass),
-        };
-
-        let method = match class.methods.get(&name) {
-            Some(Value::Obj(handle)) => *handle,
+        let method = match self.resolve_method(handle, &name)? {
+            Some(Value::Obj(handle)) => handle,
             Some(_) => return Err(LoxError::InvalidObject),
             None => return Err(LoxError::UndefinedProperty(name)),
         };
 
-        let receiver = self.pop()?;
+        // The receiver stays on the stack until the bound method holding it is
+        // allocated, otherwise the GC could free it in between.
+        let receiver = self.peek()?;
 
         let bound = self.alloc_value(LoxObj::BoundMethod(Box::from(ObjBoundMethod {
             receiver,
             method,
             is_marked: false,
-        })));
+        })))?;
+
+        self.pop()?;
 
         Ok(bound)
     }
 
-    fn define_method(&mut self, name: String) -> Result<()> {
+    /// Looks up method `name` of `class`, checking that the caller may access
+    /// it if it's private.
+    fn resolve_method(&mut self, class: ValueHandle, name: &str) -> Result<Option<Value>> {
+        match self.find_method(class, name)? {
+            Some((method, Some(owner))) => {
+                self.check_private_access(owner, name)?;
+                Ok(Some(method))
+            }
+            Some((method, None)) => Ok(Some(method)),
+            None => Ok(None),
+        }
+    }
+
+    /// Finds method `name` on `class` or its nearest superclass declaring it,
+    /// returning it along with the declaring class if it's private. Results
+    /// are memoized in `class`'s cache.
+    ///
+    /// Only a class's own cache is cleared when it gains a method. That's
+    /// enough because methods are only added while a class body runs, before
+    /// any subclass (which could have cached the old lookup) can exist.
+    fn find_method(
+        &mut self,
+        class: ValueHandle,
+        name: &str,
+    ) -> Result<Option<(Value, Option<ValueHandle>)>> {
+        match self.get_obj(class)? {
+            LoxObj::Class(class) => {
+                if let Some(&cached) = class.method_cache.get(name) {
+                    return Ok(cached);
+                }
+            }
+            _ => return Err(LoxError::InvalidClass),
+        }
+
+        let mut found = None;
+        let mut current = Some(class);
+
+        while let Some(handle) = current {
+            match self.get_obj(handle)? {
+                LoxObj::Class(ancestor) => {
+                    if let Some(&method) = ancestor.methods.get(name) {
+                        let owner = if ancestor.private_methods.contains(name) {
+                            Some(handle)
+                        } else {
+                            None
+                        };
+
+                        found = Some((method, owner));
+                        break;
+                    }
+
+                    current = ancestor.superclass;
+                }
+                _ => return Err(LoxError::InvalidSuperClass),
+            }
+        }
+
+        // The cached methods stay reachable through the classes declaring them.
+        if let LoxObj::Class(class) = self.get_obj_mut(class)? {
+            class.method_cache.insert(name.to_owned(), found);
+        }
+
+        Ok(found)
+    }
+
+    /// Private methods can only be looked up from a method of `owner`, the
+    /// class declaring them: not from its subclasses or superclasses, nor
+    /// from functions nested inside its methods.
+    fn check_private_access(&self, owner: ValueHandle, name: &str) -> Result<()> {
+        let caller = self.current_frame()?.closure;
+
+        let is_method = match self.get_obj(owner)? {
+            LoxObj::Class(class) => class
+                .methods
+                .values()
+                .any(|method| matches!(method, Value::Obj(handle) if *handle == caller)),
+            _ => return Err(LoxError::InvalidClass),
+        };
+
+        if is_method {
+            Ok(())
+        } else {
+            Err(LoxError::PrivateMethod(name.to_owned()))
+        }
+    }
+
+    fn define_method(&mut self, name: String, private: bool) -> Result<()> {
         // pop closure off the stack
         let method = self.pop()?;
         // pop class off the stack and get inner class object
@@ -571,22 +1996,110 @@ impl Vm {
             _ => Err(LoxError::InvalidObject),
         }?;
 
+        // Redeclaring a method replaces its visibility too
+        if private {
+            class.private_methods.insert(name.clone());
+        } else {
+            class.private_methods.remove(&name);
+        }
+
         class.methods.insert(name, method);
+        class.method_cache.clear();
 
         // push class back on the stack for the next method (if any) or the final
         // pop instruction
         self.push(value)
     }
 
-    fn close_upvalues(&mut self, last: usize) -> Result<()> {
-        while let Some((_, handle)) = self.open_upvalues.last() {
+    fn define_field(&mut self, name: String) -> Result<()> {
+        // pop the closure computing the field's default
+        let initializer = self.pop()?;
+
+        // the class stays on the stack for the rest of the class body
+        match self.get_obj_mut(self.get_handle(&self.peek()?)?)? {
+            LoxObj::Class(class) => class.fields.push((name, initializer)),
+            _ => return Err(LoxError::InvalidClass),
+        }
+
+        Ok(())
+    }
+
+    /// Runs the field default at `index` for the instance just created in the
+    /// callee slot, or `init` once all defaults have been computed.
+    fn initialize_instance(
+        &mut self,
+        class_handle: ValueHandle,
+        index: usize,
+        arg_count: usize,
+    ) -> Result<()> {
+        let class = match self.get_obj(class_handle)? {
+            LoxObj::Class(class) => class,
+            _ => return Err(LoxError::InvalidClass),
+        };
+
+        if let Some((_, initializer)) = class.fields.get(index) {
+            let closure = self.get_handle(initializer)?;
+            let function = self.closure_function(closure)?;
+
+            return self.push_frame(CallFrame {
+                closure,
+                function,
+                ip: 0,
+                opcode_ip: 0,
+                fp: self.sp - 1 - arg_count,
+                kind: FrameKind::FieldInit(FieldInit {
+                    class: class_handle,
+                    index,
+                    arg_count,
+                }),
+            });
+        }
+
+        match self.find_method(class_handle, INIT_STRING)? {
+            Some((init, _)) => self.call_value(init, arg_count),
+            None => Ok(()),
+        }
+    }
+
+    fn finish_field_init(&mut self, fp: usize, field_init: FieldInit, value: Value) -> Result<()> {
+        let FieldInit {
+            class,
+            index,
+            arg_count,
+        } = field_init;
+
+        let name = match self.get_obj(class)? {
+            LoxObj::Class(class) => class.fields[index].0.clone(),
+            _ => return Err(LoxError::InvalidClass),
+        };
+
+        let instance = self.stack[fp].ok_or(LoxError::InternalError(Internal::CorruptedStack))?;
+
+        match self.get_obj_mut(self.get_handle(&instance)?)? {
+            LoxObj::Instance(instance) => instance.fields.insert(name, value),
+            _ => return Err(LoxError::NonInstance),
+        };
+
+        // Drop the default's temporaries, leaving the instance and the
+        // constructor arguments in place.
+        self.sp = fp + 1 + arg_count;
+
+        self.initialize_instance(class, index + 1, arg_count)
+    }
+
+    fn close_upvalues(&mut self, last: usize) -> Result<()> {
+        while let Some((_, handle)) = self.open_upvalues.last() {
             match self
                 .heap
                 .get_mut(handle)
                 .ok_or(LoxError::InternalError(Internal::InvalidHandle))?
             {
                 LoxObj::Upvalue(upvalue) => {
-                    let location = upvalue.location;
+                    let location = match upvalue.state {
+                        UpvalueState::Open(location) => location,
+                        // Closed twice
+                        UpvalueState::Closed(_) => return Err(LoxError::InvalidUpvalue),
+                    };
 
                     if location < last {
                         break;
@@ -594,105 +2107,138 @@ impl Vm {
 
                     let value = self.stack[location].ok_or(LoxError::StackUnderflow)?;
 
-                    upvalue.value = Some(value);
+                    upvalue.state = UpvalueState::Closed(value);
                     self.open_upvalues.pop();
                 }
                 _ => return Err(LoxError::InvalidUpvalue),
             }
         }
 
+        if cfg!(debug_assertions) {
+            self.check_open_upvalues(last);
+        }
+
         Ok(())
     }
 
-    fn capture_upvalue(&mut self, index: usize) -> ValueHandle {
-        let location = self.current_frame().fp + index;
+    /// Panics unless the open upvalues are sorted by location without
+    /// duplicates, still open, and all below `last` (the lowest slot that's
+    /// about to be popped), so none can be left pointing at a dead slot.
+    fn check_open_upvalues(&self, last: usize) {
+        for window in self.open_upvalues.windows(2) {
+            assert!(window[0].0 < window[1].0, "open upvalues out of order");
+        }
+
+        for (location, handle) in &self.open_upvalues {
+            assert!(*location < last, "open upvalue above the closed slots");
+
+            match self.heap.get(handle) {
+                Some(LoxObj::Upvalue(upvalue)) => match upvalue.state {
+                    UpvalueState::Open(open_location) => assert_eq!(open_location, *location),
+                    UpvalueState::Closed(_) => panic!("closed upvalue still open"),
+                },
+                _ => panic!("open upvalue isn't an upvalue"),
+            }
+        }
+    }
+
+    fn capture_upvalue(&mut self, index: usize) -> Result<ValueHandle> {
+        let location = self.current_frame()?.fp + index;
 
         match self
             .open_upvalues
             .binary_search_by_key(&location, |&(i, _)| i)
         {
-            Ok(idx) => self
-                .open_upvalues
-                .get(idx)
-                .map(|(_, handle)| *handle)
-                .unwrap(),
+            Ok(idx) => Ok(self.open_upvalues[idx].1),
             Err(idx) => {
                 let upvalue_handle = self.alloc(LoxObj::Upvalue(Box::from(ObjUpvalue {
-                    location,
-                    value: None,
+                    state: UpvalueState::Open(location),
                     is_marked: false,
-                })));
+                })))?;
 
                 self.open_upvalues.insert(idx, (location, upvalue_handle));
 
-                upvalue_handle
+                Ok(upvalue_handle)
             }
         }
     }
 
+    fn push_frame(&mut self, frame: CallFrame) -> Result<()> {
+        if self.frames.len() == FRAMES_MAX {
+            return Err(LoxError::StackOverflow(self.frames.len()));
+        }
+
+        self.frames.push(frame);
+
+        Ok(())
+    }
+
     fn call_value(&mut self, value: Value, arg_count: usize) -> Result<()> {
-        // TODO: ensure arg count matches function/method/init arity
         let handle = match value {
             Value::Obj(handle) => handle,
             _ => return Err(LoxError::ValueNotCallable),
         };
 
         match self.get_obj(handle)? {
-            LoxObj::Closure(_) => {
-                self.frames.push(CallFrame {
-                    closure: handle,
-                    ip: 0,
-                    fp: self.sp - 1 - arg_count,
-                });
+            LoxObj::Closure(closure) => {
+                let function = closure.function;
 
-                Ok(())
+                self.call_closure(handle, function, arg_count)
             }
-            LoxObj::Class(class) => {
-                let methods = &class.methods;
-
-                match methods.get(INIT_STRING) {
-                    Some(&value) => {
-                        let lox_val = self.alloc_value(LoxObj::Instance(Box::from(ObjInstance {
-                            class: handle,
-                            fields: HashMap::new(),
-                            is_marked: false,
-                        })));
+            LoxObj::Class(_) => {
+                if self.find_method(handle, INIT_STRING)?.is_none() && arg_count != 0 {
+                    return Err(LoxError::InvalidArguments(
+                        "more than zero args to class without init",
+                    ));
+                }
 
-                        self.stack[self.sp - 1 - arg_count] = Some(lox_val);
+                let finalizable = self.find_method(handle, FINALIZE_STRING)?.is_some();
 
-                        self.call_value(value, arg_count)
-                    }
-                    None => {
-                        if arg_count != 0 {
-                            return Err(LoxError::InvalidArguments(
-                                "more than zero args to class without init",
-                            ));
-                        }
+                let lox_val = self.alloc_value(LoxObj::Instance(Box::from(ObjInstance {
+                    class: handle,
+                    fields: HashMap::new(),
+                    is_marked: false,
+                })))?;
 
-                        let lox_val = self.alloc_value(LoxObj::Instance(Box::from(ObjInstance {
-                            class: handle,
-                            fields: HashMap::new(),
-                            is_marked: false,
-                        })));
+                if finalizable {
+                    self.finalizable.push(self.get_handle(&lox_val)?);
+                }
 
-                        self.stack[self.sp - 1 - arg_count] = Some(lox_val);
+                self.stack[self.sp - 1 - arg_count] = Some(lox_val);
 
-                        Ok(())
-                    }
-                }
+                self.initialize_instance(handle, 0, arg_count)
             }
             LoxObj::BoundMethod(bound_method) => {
                 let closure = bound_method.method;
 
                 self.stack[self.sp - 1 - arg_count] = Some(bound_method.receiver);
 
-                self.frames.push(CallFrame {
-                    closure,
-                    ip: 0,
-                    fp: self.sp - 1 - arg_count,
-                });
+                let function = self.closure_function(closure)?;
 
-                Ok(())
+                self.call_closure(closure, function, arg_count)
+            }
+            LoxObj::Native(native) => {
+                if arg_count != native.arity {
+                    return Err(LoxError::InvalidArguments(
+                        "wrong number of arguments to native",
+                    ));
+                }
+
+                let function = native.function;
+
+                let args = self.stack[self.sp - arg_count..self.sp]
+                    .iter()
+                    .map(|arg| arg.ok_or(LoxError::InternalError(Internal::CorruptedStack)))
+                    .collect::<Result<Vec<_>>>()?;
+
+                // The arguments stay on the stack (and so rooted) until the native returns.
+                let result = function(self, &args)?;
+
+                for _ in 0..=arg_count {
+                    self.pop()?;
+                }
+
+                self.push(result)
             }
             _ => Err(LoxError::ValueNotCallable),
         }
@@ -706,73 +2252,309 @@ impl Vm {
     }
 
     fn fetch_str_const(&mut self) -> Result<String> {
-        let value = self.fetch_const();
+        let value = self.fetch_const()?;
 
         match value {
             Value::Obj(handle) => match self.get_obj(handle)? {
                 LoxObj::Str(s) => Ok(s.value.clone()),
-                _ => Err(LoxError::UnexpectedValue(value)),
+                _ => Err(LoxError::UnexpectedValue(value.to_string())),
             },
-            value => Err(LoxError::UnexpectedValue(value)),
+            value => Err(LoxError::UnexpectedValue(value.to_string())),
         }
     }
 
-    fn fetch16(&mut self) -> u16 {
-        let hi = self.fetch();
-        let lo = self.fetch();
-        (hi as u16) << 8 | (lo as u16)
+    fn fetch16(&mut self) -> Result<u16> {
+        let hi = self.fetch()?;
+        let lo = self.fetch()?;
+        Ok((hi as u16) << 8 | (lo as u16))
     }
 
     #[inline]
-    fn current_frame(&self) -> &CallFrame {
-        let last = self.frames.len() - 1;
-        &self.frames[last]
+    fn current_frame(&self) -> Result<&CallFrame> {
+        self.frames
+            .last()
+            .ok_or(LoxError::InternalVmError("no frame"))
     }
 
     #[inline]
-    fn current_frame_mut(&mut self) -> &mut CallFrame {
-        let last = self.frames.len() - 1;
-        &mut self.frames[last]
+    fn current_frame_mut(&mut self) -> Result<&mut CallFrame> {
+        self.frames
+            .last_mut()
+            .ok_or(LoxError::InternalVmError("no frame"))
     }
 
     fn current_closure(&self) -> Result<&ObjClosure> {
-        let handle = self.current_frame().closure;
+        let handle = self.current_frame()?.closure;
         match self.get_obj(handle)? {
             LoxObj::Closure(closure) => Ok(closure),
             _ => Err(LoxError::RuntimeError),
         }
     }
 
+    /// Calls a closure whose callee (or receiver) and arguments are on top of
+    /// the stack. A generator function doesn't run yet: its call becomes a
+    /// generator object, which replaces the callee and arguments.
+    fn call_closure(
+        &mut self,
+        closure: ValueHandle,
+        function: ValueHandle,
+        arg_count: usize,
+    ) -> Result<()> {
+        let fp = self.sp - 1 - arg_count;
+
+        let is_generator = match self.get_obj(function)? {
+            LoxObj::Function(function) if function.arity != arg_count => {
+                return Err(LoxError::WrongArgumentCount(function.arity, arg_count))
+            }
+            LoxObj::Function(function) => function.is_generator,
+            _ => return Err(LoxError::InternalVmError("not a function")),
+        };
+
+        if !is_generator {
+            return self.push_frame(CallFrame {
+                closure,
+                function,
+                ip: 0,
+                opcode_ip: 0,
+                fp,
+                kind: FrameKind::Call,
+            });
+        }
+
+        let slots = self.stack[fp..self.sp]
+            .iter()
+            .map(|slot| slot.ok_or(LoxError::InternalError(Internal::CorruptedStack)))
+            .collect::<Result<Vec<_>>>()?;
+
+        // The slots stay on the stack (and so rooted) while allocating.
+        let generator = self.alloc_value(LoxObj::Generator(Box::from(ObjGenerator {
+            closure,
+            state: GeneratorState::Suspended,
+            ip: 0,
+            slots,
+            upvalues: vec![],
+            yielded: None,
+            is_marked: false,
+        })))?;
+
+        self.sp = fp;
+
+        self.push(generator)
+    }
+
+    /// Runs a suspended generator until it yields or returns, unless it
+    /// already has a yielded value waiting. Returns whether it has one now.
+    fn generator_ready(&mut self, handle: ValueHandle) -> Result<bool> {
+        let generator = match self.get_obj_mut(handle)? {
+            LoxObj::Generator(generator) => generator,
+            _ => return Err(LoxError::InternalVmError("not a generator")),
+        };
+
+        if generator.yielded.is_some() {
+            return Ok(true);
+        }
+
+        match generator.state {
+            GeneratorState::Suspended => (),
+            GeneratorState::Running => return Err(LoxError::GeneratorRunning),
+            GeneratorState::Done => return Ok(false),
+        }
+
+        generator.state = GeneratorState::Running;
+
+        let fp = self.sp;
+        let base_frames = self.frames.len();
+
+        let result = self.resume_generator(handle, base_frames);
+
+        // A generator that fails is finished; unwind what it left behind so a
+        // caller that recovers from the error can carry on.
+        if result.is_err() {
+            self.frames.truncate(base_frames);
+            self.close_upvalues(fp)?;
+            self.sp = fp;
+
+            match self.get_obj_mut(handle)? {
+                LoxObj::Generator(generator) => generator.state = GeneratorState::Done,
+                _ => return Err(LoxError::InternalVmError("not a generator")),
+            }
+        }
+
+        result
+    }
+
+    /// Puts a generator's slots back on top of the stack and runs it from
+    /// where it left off until it yields or returns.
+    fn resume_generator(&mut self, handle: ValueHandle, base_frames: usize) -> Result<bool> {
+        let generator = match self.get_obj_mut(handle)? {
+            LoxObj::Generator(generator) => generator,
+            _ => return Err(LoxError::InternalVmError("not a generator")),
+        };
+
+        let closure = generator.closure;
+        let ip = generator.ip;
+        let slots = mem::take(&mut generator.slots);
+        let upvalues = mem::take(&mut generator.upvalues);
+
+        let fp = self.sp;
+
+        for value in slots {
+            self.push(value)?;
+        }
+
+        // Reopen the upvalues on the slots' new locations. The slots are above
+        // every other open upvalue, so the list stays sorted.
+        for (offset, upvalue_handle) in upvalues {
+            let location = fp + offset;
+
+            match self.get_obj_mut(upvalue_handle)? {
+                LoxObj::Upvalue(upvalue) => match upvalue.state {
+                    UpvalueState::Closed(value) => {
+                        upvalue.state = UpvalueState::Open(location);
+                        self.stack[location] = Some(value);
+                    }
+                    UpvalueState::Open(_) => return Err(LoxError::InvalidUpvalue),
+                },
+                _ => return Err(LoxError::InvalidUpvalue),
+            }
+
+            self.open_upvalues.push((location, upvalue_handle));
+        }
+
+        let function = self.closure_function(closure)?;
+
+        self.push_frame(CallFrame {
+            closure,
+            function,
+            ip,
+            opcode_ip: ip,
+            fp,
+            kind: FrameKind::Generator(handle),
+        })?;
+
+        self.run(base_frames)?;
+
+        match self.get_obj(handle)? {
+            LoxObj::Generator(generator) => Ok(generator.yielded.is_some()),
+            _ => Err(LoxError::InternalVmError("not a generator")),
+        }
+    }
+
+    /// Takes the generator's next value, or nil once it has returned.
+    fn generator_next(&mut self, handle: ValueHandle) -> Result<Value> {
+        self.generator_ready(handle)?;
+
+        match self.get_obj_mut(handle)? {
+            LoxObj::Generator(generator) => Ok(generator.yielded.take().unwrap_or(Value::Nil)),
+            _ => Err(LoxError::InternalVmError("not a generator")),
+        }
+    }
+
+    /// Pops the current frame, which must be resuming a generator, and stores
+    /// its state in the generator along with the `value` it yields.
+    fn suspend_generator(&mut self, value: Value) -> Result<()> {
+        let frame = self
+            .frames
+            .pop()
+            .ok_or(LoxError::InternalVmError("no frame"))?;
+
+        let handle = match frame.kind {
+            FrameKind::Generator(handle) => handle,
+            _ => return Err(LoxError::InternalVmError("yield outside a generator")),
+        };
+
+        // Upvalues over the frame's slots are closed while it's suspended, and
+        // remembered so they can be reopened
+        let mut upvalues = vec![];
+
+        while let Some(&(location, upvalue_handle)) = self.open_upvalues.last() {
+            if location < frame.fp {
+                break;
+            }
+
+            let value = self.stack[location].ok_or(LoxError::StackUnderflow)?;
+
+            match self.get_obj_mut(upvalue_handle)? {
+                LoxObj::Upvalue(upvalue) => upvalue.state = UpvalueState::Closed(value),
+                _ => return Err(LoxError::InvalidUpvalue),
+            }
+
+            upvalues.push((location - frame.fp, upvalue_handle));
+            self.open_upvalues.pop();
+        }
+
+        upvalues.reverse();
+
+        let slots = self.stack[frame.fp..self.sp]
+            .iter()
+            .map(|slot| slot.ok_or(LoxError::InternalError(Internal::CorruptedStack)))
+            .collect::<Result<Vec<_>>>()?;
+
+        self.sp = frame.fp;
+
+        match self.get_obj_mut(handle)? {
+            LoxObj::Generator(generator) => {
+                generator.state = GeneratorState::Suspended;
+                generator.ip = frame.ip;
+                generator.slots = slots;
+                generator.upvalues = upvalues;
+                generator.yielded = Some(value);
+            }
+            _ => return Err(LoxError::InternalVmError("not a generator")),
+        }
+
+        Ok(())
+    }
+
+    fn closure_function(&self, handle: ValueHandle) -> Result<ValueHandle> {
+        match self.get_obj(handle)? {
+            LoxObj::Closure(closure) => Ok(closure.function),
+            _ => Err(LoxError::InternalVmError("not a closure")),
+        }
+    }
+
     #[inline]
-    fn fetch_opcode(&mut self) -> Option<&u8> {
-        let frame = self.current_frame_mut();
+    fn fetch_opcode(&mut self) -> Result<Option<u8>> {
+        let frame = self.current_frame_mut()?;
         let ip = frame.ip;
 
+        frame.opcode_ip = ip;
         frame.ip += 1;
 
-        self.chunk().unwrap().code.get(ip)
+        Ok(self.chunk()?.code.get(ip).copied())
     }
 
     #[inline]
-    fn fetch(&mut self) -> u8 {
-        let frame = self.current_frame_mut();
+    fn fetch(&mut self) -> Result<u8> {
+        let frame = self.current_frame_mut()?;
         let ip = frame.ip;
 
         frame.ip += 1;
 
-        self.chunk().unwrap().code[ip]
+        self.chunk()?
+            .code
+            .get(ip)
+            .copied()
+            .ok_or(LoxError::InternalVmError(
+                "operand past the end of the code",
+            ))
     }
 
     #[inline]
-    fn fetch_const(&mut self) -> Value {
-        let idx = self.fetch() as usize;
+    fn fetch_const(&mut self) -> Result<Value> {
+        let idx = self.fetch()? as usize;
 
-        self.chunk().unwrap().constants[idx]
+        self.chunk()?
+            .constants
+            .get(idx)
+            .copied()
+            .ok_or(LoxError::InternalVmError("constant out of range"))
     }
 
+    #[inline]
     fn push(&mut self, value: Value) -> Result<()> {
         if self.sp == self.stack.len() {
-            Err(LoxError::StackOverflow)
+            Err(LoxError::StackOverflow(self.frames.len()))
         } else {
             self.stack[self.sp] = Some(value);
             self.sp += 1;
@@ -780,6 +2562,7 @@ impl Vm {
         }
     }
 
+    #[inline]
     fn pop(&mut self) -> Result<Value> {
         if self.sp == 0 {
             return Err(LoxError::StackUnderflow);
@@ -796,15 +2579,35 @@ impl Vm {
         self.stack[self.sp - 1].ok_or(LoxError::InternalError(Internal::CorruptedStack))
     }
 
+    /// Hot path for arithmetic: a single tag check, with the error
+    /// construction kept out of line in `not_a_number`.
+    #[inline]
     fn pop_number(&mut self) -> Result<f64> {
-        let value = self.pop()?;
-
-        match value {
+        match self.pop()? {
             Value::Number(n) => Ok(n),
-            value => Err(LoxError::UnexpectedValue(value)),
+            value => Self::not_a_number(value),
         }
     }
 
+    /// Pops an operand of a bitwise operator, which must be a number with
+    /// an integer value that fits in an `i64`.
+    fn pop_integer(&mut self) -> Result<i64> {
+        let n = self.pop_number()?;
+
+        // `i64::MAX as f64` rounds up to 2^63, which is out of range
+        if n.fract() == 0.0 && n >= i64::MIN as f64 && n < i64::MAX as f64 {
+            Ok(n as i64)
+        } else {
+            Err(LoxError::NotAnInteger(n))
+        }
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn not_a_number(value: Value) -> Result<f64> {
+        Err(LoxError::UnexpectedValue(value.to_string()))
+    }
+
     #[inline]
     fn get_obj(&self, handle: ValueHandle) -> Result<&LoxObj> {
         self.heap
@@ -819,29 +2622,30 @@ impl Vm {
             .ok_or(LoxError::InternalError(Internal::InvalidHandle))
     }
 
-    fn update_bytes_allocated(&mut self) {
+    fn update_bytes_allocated(&mut self) -> Result<()> {
         self.bytes_allocated += lox_obj_size();
 
-        if self.bytes_allocated > self.next_gc {
-            self.collect_garbage().unwrap();
+        if self.stress_gc || self.bytes_allocated > self.next_gc {
+            self.collect_garbage()?;
         }
+
+        Ok(())
     }
 
-    fn alloc(&mut self, obj: LoxObj) -> ValueHandle {
-        if DEV_GC_TESTING && cfg!(debug_assertions) {
-            println!("Allocing {:?}", &obj);
-            self.collect_garbage().unwrap();
-        } else {
-            self.update_bytes_allocated();
+    fn alloc(&mut self, obj: LoxObj) -> Result<ValueHandle> {
+        if self.stress_gc {
+            dprintln!("Allocing {:?}", &obj);
         }
 
-        self.heap.insert(obj)
+        self.update_bytes_allocated()?;
+
+        Ok(self.heap.insert(obj))
     }
 
-    fn alloc_value(&mut self, obj: LoxObj) -> Value {
-        let handle = self.alloc(obj);
+    fn alloc_value(&mut self, obj: LoxObj) -> Result<Value> {
+        let handle = self.alloc(obj)?;
 
-        Value::Obj(handle)
+        Ok(Value::Obj(handle))
     }
 
     fn mark_roots(&mut self) -> Result<()> {
@@ -872,11 +2676,24 @@ impl Vm {
             mark_object(&self.heap, &mut self.gray_stack, handle)?;
         }
 
+        dprintln!("marking instances awaiting finalization");
+        for handle in &self.pending_finalizers {
+            mark_object(&self.heap, &mut self.gray_stack, handle)?;
+        }
+
         dprintln!("marking globals");
         // mark globals
         // self.mark_table()?;
         mark_table(&self.heap, &mut self.gray_stack, &self.globals)?;
 
+        if let Some(host_globals) = &self.host_globals {
+            mark_table(&self.heap, &mut self.gray_stack, host_globals)?;
+        }
+
+        if let Value::Obj(handle) = &self.result {
+            mark_object(&self.heap, &mut self.gray_stack, handle)?;
+        }
+
         dprintln!("mark roots end");
 
         Ok(())
@@ -898,8 +2715,8 @@ impl Vm {
             .ok_or(LoxError::InternalError(Internal::InvalidHandle))?;
 
         match value {
-            LoxObj::Str(_) => (),
-            LoxObj::Closure(obj) => {
+            LoxObj::Str(_) | LoxObj::Native(_) => (),
+            LoxObj::Function(obj) => {
                 if let Some(name_handle) = &obj.name {
                     mark_object(&self.heap, &mut self.gray_stack, name_handle)?;
                 }
@@ -909,34 +2726,75 @@ impl Vm {
                         mark_object(&self.heap, &mut self.gray_stack, handle)?;
                     }
                 }
+            }
+            LoxObj::Closure(obj) => {
+                mark_object(&self.heap, &mut self.gray_stack, &obj.function)?;
 
                 for upvalue_handle in &obj.upvalues {
                     mark_object(&self.heap, &mut self.gray_stack, upvalue_handle)?;
                 }
             }
-            LoxObj::Upvalue(obj) => match &obj.value {
-                Some(Value::Obj(upvalue_handle)) => {
+            LoxObj::Upvalue(obj) => {
+                // An open upvalue's slot is already marked as a stack root.
+                if let UpvalueState::Closed(Value::Obj(upvalue_handle)) = &obj.state {
                     mark_object(&self.heap, &mut self.gray_stack, upvalue_handle)?;
                 }
-                Some(_) => return Err(LoxError::InvalidUpvalue),
-                None => (),
-            },
+            }
             LoxObj::Class(obj) => {
+                if let Some(superclass) = &obj.superclass {
+                    mark_object(&self.heap, &mut self.gray_stack, superclass)?;
+                }
+
                 mark_table(&self.heap, &mut self.gray_stack, &obj.methods)?;
+
+                for (_, initializer) in &obj.fields {
+                    if let Value::Obj(handle) = initializer {
+                        mark_object(&self.heap, &mut self.gray_stack, handle)?;
+                    }
+                }
             }
             LoxObj::Instance(obj) => {
                 mark_object(&self.heap, &mut self.gray_stack, &obj.class)?;
 
                 mark_table(&self.heap, &mut self.gray_stack, &obj.fields)?;
             }
-            LoxObj::BoundMethod(obj) => {
-                mark_object(&self.heap, &mut self.gray_stack, &obj.method)?;
+            LoxObj::Map(obj) => {
+                for value in obj.entries.values() {
+                    if let Value::Obj(handle) = value {
+                        mark_object(&self.heap, &mut self.gray_stack, handle)?;
+                    }
+                }
+            }
+            LoxObj::List(obj) => {
+                for value in &obj.items {
+                    if let Value::Obj(handle) = value {
+                        mark_object(&self.heap, &mut self.gray_stack, handle)?;
+                    }
+                }
+            }
+            LoxObj::Generator(obj) => {
+                mark_object(&self.heap, &mut self.gray_stack, &obj.closure)?;
 
-                match &obj.receiver {
-                    Value::Obj(handle) => {
+                let values = obj.slots.iter().chain(&obj.yielded);
+
+                for value in values {
+                    if let Value::Obj(handle) = value {
                         mark_object(&self.heap, &mut self.gray_stack, handle)?;
                     }
-                    _ => (),
+                }
+
+                for (_, upvalue_handle) in &obj.upvalues {
+                    mark_object(&self.heap, &mut self.gray_stack, upvalue_handle)?;
+                }
+            }
+            LoxObj::Iterator(obj) => {
+                mark_object(&self.heap, &mut self.gray_stack, &obj.target)?;
+            }
+            LoxObj::BoundMethod(obj) => {
+                mark_object(&self.heap, &mut self.gray_stack, &obj.method)?;
+
+                if let Value::Obj(handle) = &obj.receiver {
+                    mark_object(&self.heap, &mut self.gray_stack, handle)?;
                 }
             }
         }
@@ -947,25 +2805,42 @@ impl Vm {
     fn sweep(&mut self) {
         let mut bytes_freed = 0;
 
-        self.heap.objects = self
-            .heap
-            .objects
-            .iter()
-            .filter(|&handle| match self.heap.get_mut(handle) {
-                Some(LoxObj::Closure(obj)) => sweep_obj!(obj, handle, bytes_freed),
-                Some(LoxObj::Str(obj)) => sweep_obj!(obj, handle, bytes_freed),
-                Some(LoxObj::Upvalue(obj)) => sweep_obj!(obj, handle, bytes_freed),
-                Some(LoxObj::Class(obj)) => sweep_obj!(obj, handle, bytes_freed),
-                Some(LoxObj::Instance(obj)) => sweep_obj!(obj, handle, bytes_freed),
-                Some(LoxObj::BoundMethod(obj)) => sweep_obj!(obj, handle, bytes_freed),
-                None => panic!(), // TODO: change this to an error instead
-            })
-            .copied()
-            .collect();
+        // Unmarked strings are about to be freed, so they leave the intern
+        // table first. Its handles are all live until then.
+        self.heap
+            .strings
+            .retain(|interned| match unsafe { &*interned.0.ptr } {
+                LoxObj::Str(obj) => obj.is_marked,
+                _ => false,
+            });
 
-        if !(DEV_GC_TESTING && cfg!(debug_assertions)) {
-            self.bytes_allocated -= bytes_freed;
-        }
+        let on_free = &mut self.heap.on_free;
+        let arena = &mut self.heap.arena;
+
+        // Every handle in `objects` is live until it's swept, so it's safe to
+        // dereference it directly here.
+        self.heap
+            .objects
+            .retain(|handle| match unsafe { &mut *handle.ptr } {
+                LoxObj::Function(obj) => sweep_obj!(obj, handle, bytes_freed, on_free, arena),
+                LoxObj::Closure(obj) => sweep_obj!(obj, handle, bytes_freed, on_free, arena),
+                LoxObj::Str(obj) => sweep_obj!(obj, handle, bytes_freed, on_free, arena),
+                LoxObj::Upvalue(obj) => sweep_obj!(obj, handle, bytes_freed, on_free, arena),
+                LoxObj::Class(obj) => sweep_obj!(obj, handle, bytes_freed, on_free, arena),
+                LoxObj::Instance(obj) => sweep_obj!(obj, handle, bytes_freed, on_free, arena),
+                LoxObj::BoundMethod(obj) => sweep_obj!(obj, handle, bytes_freed, on_free, arena),
+                LoxObj::Map(obj) => sweep_obj!(obj, handle, bytes_freed, on_free, arena),
+                LoxObj::List(obj) => sweep_obj!(obj, handle, bytes_freed, on_free, arena),
+                LoxObj::Native(obj) => sweep_obj!(obj, handle, bytes_freed, on_free, arena),
+                LoxObj::Iterator(obj) => sweep_obj!(obj, handle, bytes_freed, on_free, arena),
+                LoxObj::Generator(obj) => sweep_obj!(obj, handle, bytes_freed, on_free, arena),
+            });
+
+        debug_assert!(
+            bytes_freed <= self.bytes_allocated,
+            "freed uncounted objects"
+        );
+        self.bytes_allocated -= bytes_freed;
     }
 
     fn collect_garbage(&mut self) -> Result<()> {
@@ -975,22 +2850,3056 @@ impl Vm {
 
         self.trace_references()?;
 
+        self.queue_finalizers()?;
+
         self.sweep();
 
-        self.next_gc = self.bytes_allocated * GC_HEAP_GROW_FACTOR;
+        self.next_gc = self.bytes_allocated * self.gc_grow_factor;
 
         dprintln!("gc end");
 
         Ok(())
     }
 
+    // Jumps may land just past the last instruction, where a function without
+    // a trailing return ends.
+    fn jump_forward(&mut self, offset: usize) -> Result<()> {
+        let len = self.chunk()?.code.len();
+        let frame = self.current_frame_mut()?;
+
+        match frame.ip.checked_add(offset) {
+            Some(target) if target <= len => {
+                frame.ip = target;
+                Ok(())
+            }
+            _ => Err(LoxError::InternalVmError("jump out of bounds")),
+        }
+    }
+
     #[inline]
     fn chunk(&mut self) -> Result<&Chunk> {
-        let handle = self.current_frame().closure;
+        let handle = self.current_frame()?.function;
 
         match self.get_obj(handle) {
-            Ok(LoxObj::Closure(f)) => Ok(&f.chunk),
+            Ok(LoxObj::Function(f)) => Ok(&f.chunk),
             _ => Err(LoxError::RuntimeError),
         }
     }
 }
+
+/// Checks that `amount` is a valid number of bits to shift an `i64` by.
+fn shift_amount(amount: i64) -> Result<u32> {
+    match u32::try_from(amount) {
+        Ok(amount) if amount < 64 => Ok(amount),
+        _ => Err(LoxError::InvalidShift(amount)),
+    }
+}
+
+/// Checks that `value` is a valid index into a list of length `len`.
+fn list_index(value: Value, len: usize) -> Result<usize> {
+    match value {
+        Value::Number(n) if n >= 0.0 && n.fract() == 0.0 => {
+            let index = n as usize;
+
+            if index < len {
+                Ok(index)
+            } else {
+                Err(LoxError::IndexOutOfRange(index, len))
+            }
+        }
+        _ => Err(LoxError::InvalidIndex(value.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// A writer tests can keep a handle to after handing it to the VM.
+    #[derive(Clone, Default)]
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+    impl SharedBuf {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Counts what's written to it, remembering the largest single write.
+    #[derive(Clone, Default)]
+    struct CountingWriter(Rc<RefCell<(usize, usize)>>);
+
+    impl Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut counts = self.0.borrow_mut();
+            counts.0 += buf.len();
+            counts.1 = counts.1.max(buf.len());
+
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn run_with_output(source: &str) -> (Result<()>, String) {
+        let mut compiler = Compiler::new(source.chars(), Heap::default());
+
+        if let Err(e) = compiler.compile() {
+            return (Err(e), String::new());
+        }
+
+        let output = SharedBuf::default();
+        let mut vm = Vm::with_writer(compiler.heap, Box::new(output.clone()));
+        vm.set_stress_gc(true);
+
+        let result = vm.interpret(Box::from(compiler.function));
+
+        (result, output.contents())
+    }
+
+    fn run(source: &str) -> Result<Vm> {
+        let heap = Heap::default();
+
+        let mut compiler = Compiler::new(source.chars(), heap);
+
+        compiler.compile()?;
+
+        let mut vm = Vm::new(compiler.heap);
+        vm.set_stress_gc(true);
+        vm.interpret(Box::from(compiler.function))?;
+
+        Ok(vm)
+    }
+
+    fn global_number(vm: &Vm, name: &str) -> f64 {
+        match vm.globals.get(name) {
+            Some(Value::Number(n)) => *n,
+            value => panic!("expected number global `{}`, got {:?}", name, value),
+        }
+    }
+
+    fn global_string(vm: &Vm, name: &str) -> String {
+        match vm.globals.get(name) {
+            Some(Value::Obj(handle)) => match vm.get_obj(*handle).unwrap() {
+                LoxObj::Str(s) => s.value.clone(),
+                obj => panic!("expected string global `{}`, got {:?}", name, obj),
+            },
+            value => panic!("expected string global `{}`, got {:?}", name, value),
+        }
+    }
+
+    #[test]
+    fn test_method_call_on_returned_instance() {
+        let source = r#"
+            class Child {
+                init(name) { this.name = name; }
+                getName() { return this.name; }
+            }
+
+            class Parent {
+                init() { this.child = Child(42); }
+                getChild() { return this.child; }
+            }
+
+            var obj = Parent();
+            var result = obj.getChild().getName();
+            var viaField = obj.child.getName();
+        "#;
+
+        let vm = run(source).unwrap();
+
+        assert_eq!(global_number(&vm, "result"), 42.0);
+        assert_eq!(global_number(&vm, "viaField"), 42.0);
+    }
+
+    #[test]
+    fn test_invoke_function_field() {
+        let source = r#"
+            fun double(n) { return n * 2; }
+
+            class Counter {
+                init(start) { this.count = start; }
+                add(n) { return this.count + n; }
+            }
+
+            class Point {
+                init(x) { this.x = x; }
+            }
+
+            class Holder {}
+
+            var holder = Holder();
+            holder.f = double;
+            holder.g = Counter(10).add;
+            holder.h = Point;
+
+            var fromFunction = holder.f(21);
+            var fromBoundMethod = holder.g(5);
+            var fromClass = holder.h(7).x;
+        "#;
+
+        let vm = run(source).unwrap();
+
+        assert_eq!(global_number(&vm, "fromFunction"), 42.0);
+        // The bound method keeps its own receiver rather than `holder`
+        assert_eq!(global_number(&vm, "fromBoundMethod"), 15.0);
+        assert_eq!(global_number(&vm, "fromClass"), 7.0);
+    }
+
+    #[test]
+    fn test_call_returned_function() {
+        let source = r#"
+            fun makeObj() {
+                fun inner(n) { return n * 2; }
+                return inner;
+            }
+
+            var result = makeObj()(21);
+            var negated = -makeObj()(1) + 5;
+        "#;
+
+        let vm = run(source).unwrap();
+
+        assert_eq!(global_number(&vm, "result"), 42.0);
+        assert_eq!(global_number(&vm, "negated"), 3.0);
+    }
+
+    #[test]
+    fn test_sorted_keys() {
+        let source = r#"
+            var m = {"pear": 1, "apple": 2};
+            m["fig"] = 3;
+            m["banana"] = 4;
+            m[7] = 5;
+
+            print sorted_keys(m);
+            print sorted_keys({});
+            sorted_keys([1, 2]);
+        "#;
+
+        let (result, output) = run_with_output(source);
+
+        assert!(matches!(result, Err(LoxError::InvalidArguments(_))));
+        assert_eq!(output, "[\"apple\", \"banana\", \"fig\", \"pear\"]\n[]\n");
+    }
+
+    #[test]
+    fn test_large_string_list() {
+        let mut vm = Vm::new(Heap::default());
+        let items = (0..20_000).map(|i| format!("key{}", i)).collect();
+
+        let list = vm.new_string_list(items).unwrap();
+
+        assert_eq!(vm.sp, 0);
+        match vm.get_obj(vm.get_handle(&list).unwrap()).unwrap() {
+            LoxObj::List(list) => assert_eq!(list.items.len(), 20_000),
+            _ => panic!("expected a list"),
+        }
+    }
+
+    #[test]
+    fn test_map_number_and_string_keys() {
+        let source = r#"
+            var m = {"k": 1};
+            m[42] = "x";
+            m[-0] = "zero";
+
+            var byNumber = m[42];
+            var byString = m["k"];
+            var byZero = m[0];
+        "#;
+
+        let vm = run(source).unwrap();
+
+        assert_eq!(global_string(&vm, "byNumber"), "x");
+        assert_eq!(global_number(&vm, "byString"), 1.0);
+        assert_eq!(global_string(&vm, "byZero"), "zero");
+    }
+
+    #[test]
+    fn test_string_equality() {
+        let mut vm = Vm::with_writer(Heap::default(), Box::new(io::sink()));
+
+        // Each string is rooted on the stack before the next allocation
+        let shared = vm.new_string(String::from("lox")).unwrap();
+        vm.push(shared).unwrap();
+        let copy = vm.new_string(String::from("lox")).unwrap();
+        vm.push(copy).unwrap();
+        let other = vm.new_string(String::from("clox")).unwrap();
+
+        assert!(vm.values_equal(shared, shared));
+        assert!(vm.values_equal(shared, copy));
+        assert!(!vm.values_equal(shared, other));
+
+        let source = r#"
+            var a = "lox";
+            var b = a;
+            var sameHandle = a == b;
+            var sameContent = a == "lo" + "x";
+            var different = a == "clox";
+        "#;
+
+        let vm = run(source).unwrap();
+
+        assert!(matches!(
+            vm.globals.get("sameHandle"),
+            Some(Value::Bool(true))
+        ));
+        assert!(matches!(
+            vm.globals.get("sameContent"),
+            Some(Value::Bool(true))
+        ));
+        assert!(matches!(
+            vm.globals.get("different"),
+            Some(Value::Bool(false))
+        ));
+    }
+
+    #[test]
+    fn test_string_interning() {
+        let source = r#"
+            var literal = "lox";
+            var concatenated = "lo" + "x";
+        "#;
+
+        let mut vm = run(source).unwrap();
+
+        let literal = vm.globals.get("literal").copied().unwrap();
+        let concatenated = vm.globals.get("concatenated").copied().unwrap();
+
+        assert!(matches!((literal, concatenated), (Value::Obj(a), Value::Obj(b)) if a == b));
+
+        let created = vm.new_string(String::from("lox")).unwrap();
+        assert!(matches!((literal, created), (Value::Obj(a), Value::Obj(b)) if a == b));
+
+        // Unreachable strings leave the table when they're collected
+        vm.new_string(String::from("garbage")).unwrap();
+        assert!(vm.heap.interned("garbage").is_some());
+
+        vm.collect_garbage().unwrap();
+
+        assert!(vm.heap.interned("garbage").is_none());
+        assert!(vm.heap.interned("lox").is_some());
+    }
+
+    #[test]
+    fn test_print_class_and_instance() {
+        let source = r#"
+            class Foo {}
+            class Bar < Foo {}
+            print Foo;
+            print Foo();
+            print Bar();
+            print [Foo, Foo()];
+        "#;
+
+        let (result, output) = run_with_output(source);
+
+        assert!(result.is_ok());
+        assert_eq!(
+            output,
+            "<class Foo>\n<Foo instance>\n<Bar instance>\n[<class Foo>, <Foo instance>]\n"
+        );
+    }
+
+    #[test]
+    fn test_logical_operator_stack() {
+        let source = r#"
+            var calls = 0;
+            fun sideEffect(value) {
+                calls = calls + 1;
+                return value;
+            }
+
+            // Locals declared after each chain would read the wrong slots if
+            // it left too many or too few values behind
+            fun check(a, b, c) {
+                var allAnd = true and true and 5;
+                var shortAnd = false and sideEffect(1);
+                var allOr = nil or nil or 7;
+                var shortOr = 3 or sideEffect(1);
+                var mixed = a and b or c;
+                var nested = (a or b) and (b or c) and sideEffect(c);
+                var marker = "marker";
+                return [allAnd, shortAnd, allOr, shortOr, mixed, nested, marker];
+            }
+
+            var first = check(1, 2, 3);
+            var firstCalls = calls;
+            var second = check(1, nil, 3);
+            var third = check(false, nil, nil);
+            var results = [first, second, third];
+            var totalCalls = calls;
+
+            var expression = false or nil and sideEffect(1) or 9;
+        "#;
+
+        let vm = run(source).unwrap();
+
+        // Only the script's closure is left on the stack
+        assert_eq!(vm.sp, 1);
+
+        let results = match vm.globals.get("results") {
+            Some(&results) => format!("{}", results),
+            None => panic!("no results"),
+        };
+
+        assert_eq!(
+            results,
+            "[[5, false, 7, 3, 2, 3, \"marker\"], \
+             [5, false, 7, 3, 3, 3, \"marker\"], \
+             [5, false, 7, 3, nil, nil, \"marker\"]]"
+        );
+
+        // Only `nested` reaches its call, in the first two checks
+        assert_eq!(global_number(&vm, "firstCalls"), 1.0);
+        assert_eq!(global_number(&vm, "totalCalls"), 2.0);
+
+        assert_eq!(global_number(&vm, "expression"), 9.0);
+    }
+
+    #[test]
+    fn test_read_input() {
+        let run_with_input = |source: &str, input: &'static str| {
+            let mut compiler = Compiler::new(source.chars(), Heap::default());
+            compiler.compile().unwrap();
+
+            let output = SharedBuf::default();
+            let mut vm = Vm::with_writer(compiler.heap, Box::new(output.clone()));
+            vm.set_reader(Box::new(input.as_bytes()));
+
+            vm.interpret(Box::from(compiler.function)).unwrap();
+
+            output.contents()
+        };
+
+        let source = "print input_number(); print input_number(); print input_number();";
+
+        assert_eq!(run_with_input(source, "42\n"), "42\nnil\nnil\n");
+        assert_eq!(run_with_input(source, "abc\n -1.5 \r\n7"), "nil\n-1.5\n7\n");
+        assert_eq!(run_with_input(source, ""), "nil\nnil\nnil\n");
+
+        let source = "print readline(); print readline(); print readline();";
+
+        assert_eq!(run_with_input(source, "one\r\n\nthree"), "one\n\nthree\n");
+        assert_eq!(run_with_input(source, "one\n"), "one\nnil\nnil\n");
+    }
+
+    #[test]
+    fn test_type_predicates() {
+        let source = r#"
+            class Point {
+                sum() { return 0; }
+            }
+
+            fun f() {}
+
+            var p = Point();
+
+            var matching = is_number(1) and is_string("a") and is_bool(false)
+                and is_nil(nil) and is_instance(p) and is_class(Point);
+
+            var mismatching = is_number("1") or is_string(1) or is_bool(nil)
+                or is_nil(false) or is_instance(Point) or is_class(p);
+
+            var callable = is_callable(f) and is_callable(Point)
+                and is_callable(p.sum) and is_callable(len);
+
+            var uncallable = is_callable(1) or is_callable(p) or is_callable("f");
+        "#;
+
+        let vm = run(source).unwrap();
+
+        assert!(matches!(
+            vm.globals.get("matching"),
+            Some(Value::Bool(true))
+        ));
+        assert!(matches!(
+            vm.globals.get("mismatching"),
+            Some(Value::Bool(false))
+        ));
+        assert!(matches!(
+            vm.globals.get("callable"),
+            Some(Value::Bool(true))
+        ));
+        assert!(matches!(
+            vm.globals.get("uncallable"),
+            Some(Value::Bool(false))
+        ));
+    }
+
+    #[test]
+    fn test_globals_snapshot() {
+        let source = r#"
+            var zeta = 3;
+            var alpha = "a";
+            var mid = nil;
+        "#;
+
+        let vm = run(source).unwrap();
+
+        let snapshot: Vec<_> = vm
+            .globals_snapshot()
+            .into_iter()
+            .map(|(name, value)| (name, value.to_string()))
+            .collect();
+
+        assert_eq!(
+            snapshot,
+            vec![
+                (String::from("alpha"), String::from("a")),
+                (String::from("mid"), String::from("nil")),
+                (String::from("zeta"), String::from("3")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_to_string() {
+        let source = r#"
+            class Point {
+                init(name) {
+                    this.name = name;
+                }
+
+                to_string() {
+                    return "Point " + this.name;
+                }
+            }
+
+            class Plain {}
+
+            var p = Point("a");
+
+            print p;
+            print "at " + p;
+            print p + "!";
+            print Plain();
+        "#;
+
+        let (result, output) = run_with_output(source);
+
+        assert!(result.is_ok(), "{:?}", result);
+        assert_eq!(output, "Point a\nat Point a\nPoint a!\n<Plain instance>\n");
+    }
+
+    #[test]
+    fn test_to_string_non_string() {
+        let source = r#"
+            class Bad {
+                to_string() { return 1; }
+            }
+
+            print Bad();
+        "#;
+
+        assert!(matches!(run(source), Err(LoxError::TypeError)));
+    }
+
+    #[test]
+    fn test_clone_instance() {
+        let source = r#"
+            class Point {
+                sum() { return this.x + this.y; }
+            }
+
+            var p = Point();
+            p.x = 1;
+            p.y = 2;
+            p.tags = [];
+
+            var q = clone(p);
+            q.x = 10;
+            push(q.tags, "moved");
+
+            var px = p.x;
+            var qsum = q.sum();
+            var tags = len(p.tags);
+            var distinct = p != q;
+        "#;
+
+        let vm = run(source).unwrap();
+
+        assert_eq!(global_number(&vm, "px"), 1.0);
+        assert_eq!(global_number(&vm, "qsum"), 12.0);
+        // The list is shared between the two
+        assert_eq!(global_number(&vm, "tags"), 1.0);
+        assert!(matches!(
+            vm.globals.get("distinct"),
+            Some(Value::Bool(true))
+        ));
+
+        assert!(matches!(
+            run("clone(42);"),
+            Err(LoxError::InvalidArguments(_))
+        ));
+    }
+
+    #[test]
+    fn test_native_new_instance() {
+        fn make_point(vm: &mut Vm, args: &[Value]) -> Result<Value> {
+            // Not rooted anywhere but in the field list
+            let label = vm.new_string(String::from("made natively")).unwrap();
+
+            vm.new_instance(
+                args[0],
+                vec![
+                    (String::from("x"), args[1]),
+                    (String::from("y"), args[2]),
+                    (String::from("label"), label),
+                ],
+            )
+        }
+
+        let source = r#"
+            class Point {
+                sum() { return this.x + this.y; }
+            }
+
+            var p = make_point(Point, 3, 4);
+            print p;
+            print p.x;
+            print p.sum();
+            print p.label;
+            make_point(42, 1, 2);
+        "#;
+
+        let mut compiler = Compiler::new(source.chars(), Heap::default());
+        compiler.compile().unwrap();
+
+        let output = SharedBuf::default();
+        let mut vm = Vm::with_writer(compiler.heap, Box::new(output.clone()));
+        vm.define_native("make_point", 3, make_point);
+
+        let result = vm.interpret(Box::from(compiler.function));
+
+        assert!(matches!(result, Err(LoxError::InvalidClass)));
+        assert_eq!(output.contents(), "<Point instance>\n3\n7\nmade natively\n");
+    }
+
+    #[test]
+    fn test_generators() {
+        let source = r#"
+            fun count(from, to) {
+                for (var i = from; i <= to; i = i + 1) yield i;
+                print "count done";
+                return "ignored";
+            }
+
+            // Driven by hand
+            var gen = count(1, 2);
+            print "created";
+            print gen.done();
+            print gen.next();
+            print gen.next();
+            print gen.done();
+            print gen.next();
+            print gen.done();
+
+            // Driven by foreach, interleaved with another generator
+            var other = count(10, 11);
+            for (var n in count(1, 3)) {
+                print n;
+                print other.next();
+            }
+
+            // Closures over a generator's locals keep working across yields
+            fun counter() {
+                var total = 0;
+                fun add(n) { total = total + n; }
+                yield add;
+                yield total;
+                add(5);
+                yield total;
+            }
+
+            var c = counter();
+            var add = c.next();
+            add(2);
+            print c.next();
+            add(3);
+            print c.next();
+            print c.done();
+
+            class Tree {
+                init(items) { this.items = items; }
+                each() {
+                    for (var item in this.items) yield item + "!";
+                }
+            }
+
+            for (var item in Tree(["a", "b"]).each()) print item;
+        "#;
+
+        let (result, output) = run_with_output(source);
+
+        assert!(result.is_ok());
+        assert_eq!(
+            output,
+            "created\nfalse\n1\n2\ncount done\ntrue\nnil\ntrue\n\
+             1\n10\n2\n11\n3\ncount done\nnil\ncount done\n\
+             2\n10\ntrue\n\
+             a!\nb!\n"
+        );
+    }
+
+    #[test]
+    fn test_generator_errors() {
+        let source = r#"
+            var gen;
+            fun recurse() {
+                yield 1;
+                gen.next();
+            }
+            gen = recurse();
+            gen.next();
+            gen.next();
+        "#;
+
+        assert!(matches!(run(source), Err(LoxError::GeneratorRunning)));
+
+        let mut compiler = Compiler::new("yield 1;".chars(), Heap::default());
+        assert!(compiler.compile().is_err());
+
+        let source = "class A { init() { yield 1; } }";
+        let mut compiler = Compiler::new(source.chars(), Heap::default());
+        assert!(compiler.compile().is_err());
+    }
+
+    #[test]
+    fn test_generator_error_finishes_it() {
+        let source = r#"
+            fun gen() {
+                var local = "kept";
+                fun show() { return local; }
+                yield show;
+                nil + 1;
+                yield 2;
+            }
+
+            var g = gen();
+            var show;
+
+            fun failing() {
+                show = g.next();
+                g.next();
+            }
+
+            test("failing", failing);
+            print g.next();
+            print g.next();
+            print show();
+        "#;
+
+        let mut compiler = Compiler::new(source.chars(), Heap::default());
+        compiler.compile().unwrap();
+
+        let buf = SharedBuf::default();
+        let mut vm = Vm::with_writer(compiler.heap, Box::new(buf.clone()));
+        vm.install_test_harness();
+
+        assert!(matches!(
+            vm.interpret(Box::from(compiler.function)),
+            Err(LoxError::TestsFailed(1))
+        ));
+
+        let output = buf.contents();
+        assert!(output.starts_with("test failing ... FAILED"));
+        assert!(output.ends_with("\nnil\nnil\nkept\n0 passed, 1 failed\n"));
+    }
+
+    #[test]
+    fn test_object_identity_equality() {
+        let source = r#"
+            fun f() {}
+            fun g() {}
+            class Handler {
+                handle() {}
+            }
+
+            var handler = Handler();
+            var bound = handler.handle;
+            var sameFunction = f == f;
+            var differentFunctions = f == g;
+            var sameBound = bound == bound;
+            var reboundMethod = bound == handler.handle;
+            var sameInstance = handler == handler;
+            var differentInstances = Handler() == Handler();
+            var sameClass = Handler == Handler;
+            var classAndInstance = Handler == handler;
+            var functionAndString = f == "f";
+        "#;
+
+        let vm = run(source).unwrap();
+
+        let global = |name: &str| match vm.globals.get(name) {
+            Some(Value::Bool(b)) => *b,
+            value => panic!("expected bool global `{}`, got {:?}", name, value),
+        };
+
+        assert!(global("sameFunction"));
+        assert!(!global("differentFunctions"));
+        assert!(global("sameBound"));
+        // Every property access binds a new method object
+        assert!(!global("reboundMethod"));
+        assert!(global("sameInstance"));
+        assert!(!global("differentInstances"));
+        assert!(global("sameClass"));
+        assert!(!global("classAndInstance"));
+        assert!(!global("functionAndString"));
+    }
+
+    #[test]
+    fn test_equality_of_any_types() {
+        let (result, output) = run_with_output(
+            r#"
+            print true == true;
+            print true != false;
+            print nil == nil;
+            print (1 < 2) == true;
+            print nil == false;
+            print 1 == "1";
+            print "a" != nil;
+            print contains([nil], nil) == (nil == nil);
+        "#,
+        );
+
+        result.unwrap();
+        assert_eq!(output, "true\ntrue\ntrue\ntrue\nfalse\nfalse\ntrue\ntrue\n");
+    }
+
+    #[test]
+    fn test_map_literal_statement() {
+        let source = r#"
+            fun show(s) { print s; }
+            {"k": show("map")};
+            { print "block"; }
+            var k = "key";
+            {k: 1}[k];
+            {}
+            {
+                var x = 1;
+                print x;
+            }
+        "#;
+
+        let (result, output) = run_with_output(source);
+
+        assert!(result.is_ok());
+        assert_eq!(output, "map\nblock\n1\n");
+    }
+
+    #[test]
+    fn test_map_unhashable_key() {
+        let source = r#"
+            class Foo {}
+            var m = {};
+            m[Foo()] = 1;
+        "#;
+
+        assert!(matches!(run(source), Err(LoxError::UnhashableKey)));
+    }
+
+    #[test]
+    fn test_interpret_with_deadline() {
+        use std::time::Duration;
+
+        let source = r#"
+            var i = 0;
+            while (true) {
+                i = i + 1;
+            }
+        "#;
+
+        let mut compiler = Compiler::new(source.chars(), Heap::default());
+        compiler.compile().unwrap();
+
+        let mut vm = Vm::new(compiler.heap);
+
+        let start = Instant::now();
+        let result = vm.interpret_with_deadline(
+            Box::from(compiler.function),
+            start + Duration::from_millis(50),
+        );
+
+        assert!(matches!(result, Err(LoxError::TimedOut)));
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_resume() {
+        let source = r#"
+            var i = 0;
+            while (i < 10000) {
+                i = i + 1;
+            }
+            return i;
+        "#;
+
+        let mut compiler = Compiler::new(source.chars(), Heap::default());
+        compiler.set_top_level_return(true);
+        compiler.compile().unwrap();
+
+        let mut vm = Vm::new(compiler.heap);
+        vm.set_steps_per_resume(500);
+        vm.start(Box::from(compiler.function)).unwrap();
+
+        let mut resumes = 0;
+
+        let result = loop {
+            let step = vm.resume().unwrap();
+            resumes += 1;
+
+            assert!(vm.steps() > 0 && vm.steps() <= 500);
+
+            if let StepResult::Done(value) = step {
+                break value;
+            }
+        };
+
+        assert!(matches!(result, Value::Number(n) if n == 10000.0));
+        assert!(resumes > 10000 / 500);
+
+        // The script is unloaded once it's done
+        assert!(matches!(vm.resume(), Err(LoxError::InternalVmError(_))));
+    }
+
+    #[test]
+    fn test_super_init_sets_fields_on_same_instance() {
+        let source = r#"
+            class A {
+                init(a) { this.a = a; }
+            }
+
+            class B < A {
+                init(a, b) {
+                    super.init(a);
+                    this.b = b;
+                }
+            }
+
+            class C < B {
+                init() { super.init(1, 2); this.c = 3; }
+            }
+
+            var b = B(10, 20);
+            var ba = b.a;
+            var bb = b.b;
+
+            var c = C();
+            var sum = c.a + c.b + c.c;
+        "#;
+
+        let vm = run(source).unwrap();
+
+        assert_eq!(global_number(&vm, "ba"), 10.0);
+        assert_eq!(global_number(&vm, "bb"), 20.0);
+        assert_eq!(global_number(&vm, "sum"), 6.0);
+    }
+
+    #[test]
+    fn test_inherited_init() {
+        let source = r#"
+            class A {
+                init(a) { this.a = a; }
+            }
+
+            class B < A {}
+
+            var a = B(7).a;
+        "#;
+
+        let vm = run(source).unwrap();
+
+        assert_eq!(global_number(&vm, "a"), 7.0);
+    }
+
+    #[test]
+    fn test_super_in_nested_function() {
+        let source = r#"
+            class A {
+                greet() { return "hi " + this.name; }
+            }
+
+            class B < A {
+                init() { this.name = "b"; }
+
+                m() {
+                    fun g() {
+                        fun h() { return super.greet() + " from " + this.name; }
+                        return h();
+                    }
+                    return g();
+                }
+            }
+
+            var result = B().m();
+        "#;
+
+        let vm = run(source).unwrap();
+
+        assert_eq!(global_string(&vm, "result"), "hi b from b");
+    }
+
+    #[test]
+    fn test_capture_through_several_functions() {
+        let source = r#"
+            fun outer() {
+                var a = 1;
+                var b = 10;
+                fun middle() {
+                    fun inner() { return b + a + a; }
+                    return inner();
+                }
+                return middle();
+            }
+
+            var result = outer();
+        "#;
+
+        let vm = run(source).unwrap();
+
+        assert_eq!(global_number(&vm, "result"), 12.0);
+    }
+
+    #[test]
+    fn test_local_recursive_function() {
+        let source = r#"
+            var result;
+            {
+                fun fact(n) {
+                    if (n <= 1) return 1;
+                    return n * fact(n - 1);
+                }
+                result = fact(5);
+            }
+        "#;
+
+        let vm = run(source).unwrap();
+
+        assert_eq!(global_number(&vm, "result"), 120.0);
+    }
+
+    #[test]
+    fn test_recursive_closure_captured_as_upvalue() {
+        let source = r#"
+            fun outer() {
+                fun count(n) {
+                    if (n <= 0) return 0;
+                    return 1 + count(n - 1);
+                }
+
+                fun wrap() { return count(4); }
+
+                return wrap;
+            }
+
+            var result = outer()();
+        "#;
+
+        let vm = run(source).unwrap();
+
+        assert_eq!(global_number(&vm, "result"), 4.0);
+    }
+
+    #[test]
+    fn test_local_out_of_range() {
+        for (opcode, name) in &[
+            (OpCode::GetLocal as u8, "GetLocal"),
+            (OpCode::SetLocal as u8, "SetLocal"),
+        ] {
+            let mut function = ObjFunction {
+                arity: 0,
+                chunk: Chunk::default(),
+                name: None,
+                upvalue_count: 0,
+                is_generator: false,
+                is_marked: false,
+            };
+
+            // The script frame starts at slot 0, so slot 200 is far above the top.
+            for &byte in &[OpCode::Nil as u8, *opcode, 200, OpCode::Pop as u8] {
+                function.chunk.write(byte, 1);
+            }
+
+            let mut vm = Vm::new(Heap::default());
+
+            match vm.interpret(Box::from(function)) {
+                Err(LoxError::InternalError(Internal::LocalOutOfRange(op, 200))) => {
+                    assert_eq!(op, *name);
+                }
+                result => panic!("expected an out of range local, got {:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    fn test_jump_out_of_bounds() {
+        let cases: [(&[u8], &str); 3] = [
+            (
+                &[OpCode::Nil as u8, OpCode::Loop as u8, 0, 10],
+                "loop jump out of bounds",
+            ),
+            (&[OpCode::Jump as u8, 1, 0], "jump out of bounds"),
+            (
+                &[OpCode::False as u8, OpCode::JumpIfFalse as u8, 0, 1],
+                "jump out of bounds",
+            ),
+        ];
+
+        for (code, message) in cases {
+            let mut function = ObjFunction {
+                arity: 0,
+                chunk: Chunk::default(),
+                name: None,
+                upvalue_count: 0,
+                is_generator: false,
+                is_marked: false,
+            };
+
+            for &byte in code {
+                function.chunk.write(byte, 1);
+            }
+
+            let mut vm = Vm::new(Heap::default());
+
+            match vm.interpret(Box::from(function)) {
+                Err(LoxError::InternalVmError(m)) => assert_eq!(m, message),
+                result => panic!("expected {}, got {:?}", message, result),
+            }
+        }
+    }
+
+    #[test]
+    fn test_arithmetic_on_non_numbers() {
+        for source in &["1 - \"a\";", "\"a\" * 2;", "-nil;", "true < 1;"] {
+            assert!(
+                matches!(run(source), Err(LoxError::UnexpectedValue(_))),
+                "{}",
+                source
+            );
+        }
+
+        let vm = run("var x = (1 + 2) * 3 - 4 / 2;").unwrap();
+
+        assert_eq!(global_number(&vm, "x"), 7.0);
+    }
+
+    #[test]
+    fn test_field_defaults() {
+        let source = r#"
+            class Point {
+                x = 0;
+                y = this.x + 1;
+            }
+
+            class Named {
+                name = "anon";
+                count = 1;
+                init(count) { this.count = this.count + count; }
+            }
+
+            class Sub < Point {
+                z = this.y + 1;
+            }
+
+            var p = Point();
+            var px = p.x;
+            var py = p.y;
+
+            var n = Named(41);
+            var name = n.name;
+            var count = n.count;
+
+            var z = Sub().z;
+        "#;
+
+        let vm = run(source).unwrap();
+
+        assert_eq!(global_number(&vm, "px"), 0.0);
+        assert_eq!(global_number(&vm, "py"), 1.0);
+        assert_eq!(global_string(&vm, "name"), "anon");
+        assert_eq!(global_number(&vm, "count"), 42.0);
+        assert_eq!(global_number(&vm, "z"), 2.0);
+    }
+
+    #[test]
+    fn test_print_function() {
+        let (result, output) = run_with_output(
+            r#"
+            fun greet() {}
+
+            fun outer() {
+                var x = 1;
+                fun inner() { return x; }
+                return inner;
+            }
+
+            print greet;
+            print outer();
+        "#,
+        );
+
+        result.unwrap();
+        assert_eq!(output, "<fn greet>\n<fn inner>\n");
+    }
+
+    #[test]
+    fn test_print_bound_method_and_native() {
+        let (result, output) = run_with_output(
+            r#"
+            class Greeter {
+                hello() {}
+            }
+
+            print Greeter().hello;
+            print write;
+        "#,
+        );
+
+        result.unwrap();
+        assert_eq!(
+            output,
+            "<fn hello bound to Greeter instance>\n<native fn write>\n"
+        );
+    }
+
+    #[test]
+    fn test_write_native() {
+        let (result, output) = run_with_output(
+            r#"
+            write("a");
+            write("b");
+            print "c";
+            write(1);
+        "#,
+        );
+
+        result.unwrap();
+        assert_eq!(output, "abc\n1");
+    }
+
+    #[test]
+    fn test_print_non_finite_numbers() {
+        for (source, expected) in &[
+            ("print 1/0;", "inf\n"),
+            ("print -1/0;", "-inf\n"),
+            ("print 0/0;", "nan\n"),
+        ] {
+            let (result, output) = run_with_output(source);
+
+            result.unwrap();
+            assert_eq!(&output, expected);
+        }
+    }
+
+    #[test]
+    fn test_vm_does_not_hold_stdout() {
+        let vm = Vm::new(Heap::default());
+
+        std::thread::spawn(|| drop(io::stdout().lock()))
+            .join()
+            .unwrap();
+
+        drop(vm);
+    }
+
+    #[test]
+    fn test_print_is_buffered() {
+        /// Counts the calls made to it, keeping what's written.
+        #[derive(Clone, Default)]
+        struct CallCountingWriter {
+            writes: Rc<RefCell<usize>>,
+            flushes: Rc<RefCell<usize>>,
+            output: SharedBuf,
+        }
+
+        impl Write for CallCountingWriter {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                *self.writes.borrow_mut() += 1;
+                self.output.write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                *self.flushes.borrow_mut() += 1;
+                Ok(())
+            }
+        }
+
+        let source = r#"
+            for (var i = 0; i < 1000; i = i + 1) {
+                print i;
+            }
+        "#;
+
+        let mut compiler = Compiler::new(source.chars(), Heap::default());
+        compiler.compile().unwrap();
+
+        let writer = CallCountingWriter::default();
+        let mut vm = Vm::with_writer(compiler.heap, Box::new(writer.clone()));
+
+        vm.interpret(Box::from(compiler.function)).unwrap();
+
+        let output = writer.output.contents();
+        assert_eq!(output.lines().count(), 1000);
+        assert_eq!(output.lines().last(), Some("999"));
+
+        assert!(*writer.writes.borrow() < 10);
+        assert_eq!(*writer.flushes.borrow(), 1);
+    }
+
+    #[test]
+    fn test_finalizer_runs_once() {
+        // Relies on the stress GC collecting on every allocation
+        let source = r#"
+            var count = 0;
+            var saved;
+            var s = "s";
+
+            class Resource {
+                finalize() {
+                    count = count + 1;
+                    saved = this;
+                }
+            }
+
+            Resource();
+            var a = s + "b";
+            var resurrected = saved;
+
+            saved = nil;
+            var b = s + "d";
+            var c = s + "f";
+        "#;
+
+        let vm = run(source).unwrap();
+
+        assert_eq!(global_number(&vm, "count"), 1.0);
+        assert!(matches!(vm.globals.get("resurrected"), Some(Value::Obj(_))));
+    }
+
+    #[test]
+    fn test_finalizers_run_in_allocation_order() {
+        let (result, output) = run_with_output(
+            r#"
+            class Resource {
+                init(n) { this.n = n; }
+                finalize() { print this.n; }
+            }
+
+            var resources = [Resource(1), Resource(2), Resource(3)];
+            resources = nil;
+            [];
+        "#,
+        );
+
+        result.unwrap();
+        assert_eq!(output, "1\n2\n3\n");
+    }
+
+    #[test]
+    fn test_heap_hooks() {
+        use crate::object::ObjKind;
+
+        let source = r#"
+            class A {}
+            var i = 0;
+            while (i < 3) {
+                A();
+                i = i + 1;
+            }
+        "#;
+
+        let mut compiler = Compiler::new(source.chars(), Heap::default());
+        compiler.compile().unwrap();
+
+        let mut vm = Vm::new(compiler.heap);
+
+        let allocs = Rc::new(RefCell::new(HashMap::new()));
+        let frees = Rc::new(RefCell::new(0));
+
+        let counts = allocs.clone();
+        vm.heap.set_alloc_hook(move |kind, size| {
+            assert_eq!(size, lox_obj_size());
+            *counts.borrow_mut().entry(kind).or_insert(0) += 1;
+        });
+
+        let freed = frees.clone();
+        vm.heap.set_free_hook(move |_, _| *freed.borrow_mut() += 1);
+
+        vm.interpret(Box::from(compiler.function)).unwrap();
+
+        let allocs = allocs.borrow();
+        assert_eq!(allocs.get(&ObjKind::Instance), Some(&3));
+        assert_eq!(allocs.get(&ObjKind::Class), Some(&1));
+        assert!(*frees.borrow() <= allocs.values().sum());
+    }
+
+    #[test]
+    fn test_substring() {
+        let (result, output) = run_with_output(
+            r#"
+            print substring("hello", 1, 4);
+            print substring("hello", 2, 100);
+            print substring("hello", 3, 1) == "";
+            print substring("héllo wörld", 1, 9);
+        "#,
+        );
+
+        result.unwrap();
+        assert_eq!(output, "ell\nllo\ntrue\néllo wör\n");
+
+        for source in &[
+            "substring(\"abc\", 4, 5);",
+            "substring(\"abc\", -1, 2);",
+            "substring(\"abc\", 0.5, 2);",
+            "substring(1, 0, 1);",
+        ] {
+            assert!(
+                matches!(run(source), Err(LoxError::InvalidArguments(_))),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_collection_natives() {
+        let (result, output) = run_with_output(
+            r#"
+            var list = [1, "two", nil];
+            var map = {"a": 1, 2: "b"};
+
+            print len("héllo");
+            print len(list);
+            print len(map);
+            print len([]);
+
+            print contains(list, "two");
+            print contains(list, nil);
+            print contains(list, 3);
+            print contains(map, "a");
+            print contains(map, 2);
+            print contains(map, "b");
+            print contains(map, list);
+
+            push(list, 4);
+            print len(list);
+            print list[3];
+            print pop(list);
+            print pop(list);
+            print list;
+        "#,
+        );
+
+        result.unwrap();
+        assert_eq!(
+            output,
+            "5\n3\n2\n0\ntrue\ntrue\nfalse\ntrue\ntrue\nfalse\nfalse\n4\n4\n4\nnil\n[1, \"two\"]\n"
+        );
+
+        for source in &[
+            "len(1);",
+            "contains(\"abc\", \"a\");",
+            "push({}, 1);",
+            "append(nil, 1);",
+            "pop(nil);",
+            "pop([]);",
+        ] {
+            assert!(
+                matches!(run(source), Err(LoxError::InvalidArguments(_))),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_lists() {
+        // Runs under the stress GC, so the lists must survive every
+        // allocation made while they're only reachable through globals
+        let source = r#"
+            var list = [1, "two", [3]];
+            list[0] = list[0] + 10;
+            append(list, "four");
+            push(list, nil);
+
+            var first = list[0];
+            var second = list[1];
+            var nested = list[2][0];
+            var last = list[len(list) - 2];
+            var length = len(list);
+            var empty = len([]);
+        "#;
+
+        let vm = run(source).unwrap();
+
+        assert_eq!(global_number(&vm, "first"), 11.0);
+        assert_eq!(global_string(&vm, "second"), "two");
+        assert_eq!(global_number(&vm, "nested"), 3.0);
+        assert_eq!(global_string(&vm, "last"), "four");
+        assert_eq!(global_number(&vm, "length"), 5.0);
+        assert_eq!(global_number(&vm, "empty"), 0.0);
+    }
+
+    #[test]
+    fn test_list_index_errors() {
+        assert!(matches!(
+            run("[1, 2][2];"),
+            Err(LoxError::IndexOutOfRange(2, 2))
+        ));
+        assert!(matches!(
+            run("var l = [1]; l[0.5] = 1;"),
+            Err(LoxError::InvalidIndex(_))
+        ));
+        assert!(matches!(
+            run("[1][\"a\"];"),
+            Err(LoxError::InvalidIndex(index)) if index == "a"
+        ));
+    }
+
+    #[test]
+    fn test_compound_assignment() {
+        let source = r#"
+            var global = 10;
+            global += 5;
+            global -= 3;
+            global *= 4;
+            global /= 6;
+
+            var text = "lo";
+            text += "x";
+
+            var local;
+            var captured;
+            var property;
+            var lookups = 0;
+            {
+                var n = 2;
+                n += 1;
+                n *= 10;
+                n -= 6;
+                n /= 3;
+                local = n;
+
+                var m = 1;
+                fun bump(by) {
+                    m += by;
+                    m *= 2;
+                    return m;
+                }
+                bump(2);
+                captured = bump(1);
+            }
+
+            class Counter {
+                init() { this.count = 1; }
+                twice() { this.count *= 2; }
+            }
+
+            var counter = Counter();
+            fun get() {
+                lookups = lookups + 1;
+                return counter;
+            }
+            get().count += 4;
+            counter.twice();
+            get().count -= 1;
+            counter.count /= 3;
+            property = counter.count;
+        "#;
+
+        let vm = run(source).unwrap();
+
+        assert_eq!(global_number(&vm, "global"), 8.0);
+        assert_eq!(global_string(&vm, "text"), "lox");
+        assert_eq!(global_number(&vm, "local"), 8.0);
+        assert_eq!(global_number(&vm, "captured"), 14.0);
+        assert_eq!(global_number(&vm, "property"), 3.0);
+        assert_eq!(global_number(&vm, "lookups"), 2.0);
+    }
+
+    #[test]
+    fn test_conditional() {
+        let (result, output) = run_with_output(
+            r#"
+            print true ? "yes" : panic("unreachable");
+            print nil ? panic("unreachable") : "no";
+            print true ? 1 : true ? 2 : 3;
+            print false ? 1 : false ? 2 : 3;
+            print 1 < 2 and 2 < 1 ? "and" : "or";
+
+            fun sign(n) { return n < 0 ? -1 : n == 0 ? 0 : 1; }
+            var sum = 0;
+            for (var i = -50; i <= 100; i = i + 1) sum = sum + sign(i);
+            print sum;
+            print [sign(-3), sign(0), 1 ? sign(7) : 0];
+        "#,
+        );
+
+        result.unwrap();
+        assert_eq!(output, "yes\nno\n1\n3\nor\n50\n[-1, 0, 1]\n");
+    }
+
+    #[test]
+    fn test_bitwise_operators() {
+        let (result, output) = run_with_output(
+            r#"
+            print 12 & 10;
+            print 12 | 10;
+            print 12 ^ 10;
+            print ~5;
+            print 1 << 4;
+            print -16 >> 2;
+            print 6 & 3 == 2;
+            print 1 | 2 ^ 3 & 4 << 1;
+            print ~-1;
+            print 1 << 62 >> 61;
+        "#,
+        );
+
+        result.unwrap();
+        assert_eq!(output, "8\n14\n6\n-6\n16\n-4\ntrue\n3\n0\n2\n");
+    }
+
+    #[test]
+    fn test_bitwise_errors() {
+        assert!(matches!(
+            run("3.5 & 1;"),
+            Err(LoxError::NotAnInteger(n)) if n == 3.5
+        ));
+        assert!(matches!(run("~0.1;"), Err(LoxError::NotAnInteger(_))));
+        assert!(matches!(
+            run("1 | 10000000000000000000;"),
+            Err(LoxError::NotAnInteger(_))
+        ));
+        assert!(matches!(run("1 << 64;"), Err(LoxError::InvalidShift(64))));
+        assert!(matches!(run("1 >> -1;"), Err(LoxError::InvalidShift(-1))));
+        assert!(matches!(
+            run("\"a\" ^ 1;"),
+            Err(LoxError::UnexpectedValue(_))
+        ));
+    }
+
+    #[test]
+    fn test_lambda() {
+        let (result, output) = run_with_output(
+            r#"
+            var add = fun (a, b) { return a + b; };
+            print add(1, 2);
+            print add;
+
+            fun map(list, f) {
+                var mapped = [];
+                for (var x in list) push(mapped, f(x));
+                return mapped;
+            }
+            print map([1, 2, 3], fun (x) { return x * x; });
+
+            fun counter() {
+                var count = 0;
+                return fun () {
+                    count = count + 1;
+                    return count;
+                };
+            }
+            var next = counter();
+            next();
+            print next();
+
+            fun (message) { print message; }("called right away");
+        "#,
+        );
+
+        result.unwrap();
+        assert_eq!(output, "3\n<fn lambda>\n[1, 4, 9]\n2\ncalled right away\n");
+    }
+
+    #[test]
+    fn test_switch() {
+        let (result, output) = run_with_output(
+            r#"
+            fun describe(n) {
+                switch (n) {
+                    case 1:
+                        return "one";
+                    case 1 + 1:
+                        var name = "tw";
+                        return name + "o";
+                    default:
+                        return "many";
+                }
+            }
+            print describe(1);
+            print describe(2);
+            print describe(3);
+
+            // Without a default nothing runs when no case matches
+            switch ("c") {
+                case "a": print "a";
+                case "b": print "b";
+            }
+
+            // The subject is evaluated once, and only the matching case runs
+            var evaluations = 0;
+            fun subject() {
+                evaluations = evaluations + 1;
+                return "b";
+            }
+            switch (subject()) {
+                case "a": print "a";
+                case "b": print "b";
+                case "b": print "b again";
+                default: print "default";
+            }
+            print evaluations;
+
+            for (var i = 0; i < 5; i = i + 1) {
+                switch (i) {
+                    case 1: continue;
+                    case 3: break;
+                }
+                print i;
+            }
+        "#,
+        );
+
+        result.unwrap();
+        assert_eq!(output, "one\ntwo\nmany\nb\n1\n0\n2\n");
+    }
+
+    #[test]
+    fn test_switch_any_types() {
+        let (result, output) = run_with_output(
+            r#"
+            fun describe(value) {
+                switch (value) {
+                    case 1: return "one";
+                    case "a": return "a";
+                    case true: return "true";
+                    case nil: return "nil";
+                    default: return "other";
+                }
+            }
+            print describe(true);
+            print describe(nil);
+            print describe("a");
+            print describe("b");
+            print describe(false);
+            print describe(1);
+        "#,
+        );
+
+        result.unwrap();
+        assert_eq!(output, "true\nnil\na\nother\nother\none\n");
+    }
+
+    #[test]
+    fn test_const() {
+        let (result, output) = run_with_output(
+            r#"
+            const greeting = "hello";
+            print greeting;
+            {
+                const n = 2;
+                fun double() { return n * 2; }
+                print double();
+            }
+        "#,
+        );
+
+        result.unwrap();
+        assert_eq!(output, "hello\n4\n");
+
+        // Compiled before the declaration, so only the VM can catch it
+        let source = "fun f() { x = 2; } const x = 1; f();";
+        assert!(matches!(run(source), Err(LoxError::ConstAssignment(name)) if name == "x"));
+
+        let mut vm = Vm::new(Heap::default());
+        vm.eval("const x = 1;").unwrap();
+
+        for source in &["x = 2;", "var x = 2;", "fun x() {}"] {
+            assert!(vm.eval(source).is_err(), "{}", source);
+        }
+
+        assert!(matches!(vm.eval("x += 1;"), Err(LoxError::CompileError(_))));
+        assert!(vm.eval("var y = x + 1;").is_ok());
+        assert_eq!(global_number(&vm, "x"), 1.0);
+        assert_eq!(global_number(&vm, "y"), 2.0);
+    }
+
+    #[test]
+    fn test_logical_operators() {
+        let (result, output) = run_with_output(
+            r#"
+            print true and 1;
+            print false and panic("unreachable");
+            print nil or "default";
+            print 1 or panic("unreachable");
+            print false or nil and true;
+        "#,
+        );
+
+        result.unwrap();
+        assert_eq!(output, "1\nfalse\ndefault\n1\nnil\n");
+    }
+
+    #[test]
+    fn test_seeded_random() {
+        let source = r#"
+            for (var i = 0; i < 5; i = i + 1) {
+                var r = random();
+                if (r < 0 or r >= 1) panic(r);
+                print r;
+            }
+        "#;
+
+        let run_seeded = |seed| {
+            let mut compiler = Compiler::new(source.chars(), Heap::default());
+            compiler.compile().unwrap();
+
+            let output = SharedBuf::default();
+            let mut vm = Vm::with_writer(compiler.heap, Box::new(output.clone()));
+            vm.seed_rng(seed);
+
+            vm.interpret(Box::from(compiler.function)).unwrap();
+
+            output.contents()
+        };
+
+        assert_eq!(run_seeded(42), run_seeded(42));
+        assert_ne!(run_seeded(42), run_seeded(43));
+    }
+
+    #[test]
+    fn test_clock() {
+        let source = r#"
+            var start = clock();
+            var elapsed = clock() - start;
+        "#;
+
+        let vm = run(source).unwrap();
+
+        assert!(global_number(&vm, "start") > 0.0);
+        assert!(global_number(&vm, "elapsed") >= 0.0);
+
+        assert!(matches!(
+            run("clock(1);"),
+            Err(LoxError::InvalidArguments(_))
+        ));
+    }
+
+    #[test]
+    fn test_random_int() {
+        let (result, output) = run_with_output(
+            r#"
+            var seen = {};
+            for (var i = 0; i < 1000; i = i + 1) {
+                var n = random_int(1, 6);
+                if (!contains([1, 2, 3, 4, 5, 6], n)) panic(n);
+                seen[n] = true;
+            }
+            print len(seen);
+            print random_int(3, 3);
+        "#,
+        );
+
+        result.unwrap();
+        assert_eq!(output, "6\n3\n");
+
+        for source in &[
+            "random_int(2, 1);",
+            "random_int(0.5, 1);",
+            "random_int(\"a\", 1);",
+        ] {
+            assert!(
+                matches!(run(source), Err(LoxError::InvalidArguments(_))),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_panic_native() {
+        let (result, output) = run_with_output("print 1;\npanic(\"boom\");\nprint 2;");
+
+        match result {
+            Err(LoxError::Panic(message, line)) => {
+                assert_eq!(message, "boom");
+                assert_eq!(line, Some(2));
+            }
+            result => panic!("expected a panic, got {:?}", result),
+        }
+
+        assert_eq!(output, "1\n");
+
+        assert!(matches!(
+            run("fun f() { panic(42); } f();"),
+            Err(LoxError::Panic(message, Some(1))) if message == "42"
+        ));
+    }
+
+    #[test]
+    fn test_panic_without_debug_info() {
+        use crate::compiler::DebugInfo;
+
+        let source = "fun f() {\n  panic(\"boom\");\n}\nf();";
+
+        let mut compiler = Compiler::new(source.chars(), Heap::default());
+        compiler.set_debug_info(DebugInfo::None);
+        compiler.compile().unwrap();
+
+        let mut vm = Vm::new(compiler.heap);
+
+        assert!(matches!(
+            vm.interpret(Box::from(compiler.function)),
+            Err(LoxError::Panic(message, None)) if message == "boom"
+        ));
+    }
+
+    #[test]
+    fn test_for_loop() {
+        let source = r#"
+            var sum = 0;
+            for (var i = 1; i <= 4; i = i + 1) {
+                sum = sum + i;
+            }
+
+            var j = 0;
+            for (; j < 3;) j = j + 1;
+
+            fun forever() {
+                for (;;) {
+                    return 7;
+                }
+            }
+
+            var returned = forever();
+        "#;
+
+        let vm = run(source).unwrap();
+
+        assert_eq!(global_number(&vm, "sum"), 10.0);
+        assert_eq!(global_number(&vm, "j"), 3.0);
+        assert_eq!(global_number(&vm, "returned"), 7.0);
+
+        // The initializer's variable is local to the loop
+        let source = r#"
+            for (var i = 0; i < 1; i = i + 1) {}
+            print i;
+        "#;
+
+        assert!(matches!(
+            run(source),
+            Err(LoxError::UndefinedVariable(name)) if name == "i"
+        ));
+    }
+
+    #[test]
+    fn test_break() {
+        let source = r#"
+            var outer = 0;
+            var inner = 0;
+
+            for (var i = 0; i < 3; i = i + 1) {
+                outer = outer + 1;
+
+                var j = 0;
+                while (true) {
+                    var doubled = j * 2;
+                    if (doubled >= 4) break;
+                    j = j + 1;
+                    inner = inner + 1;
+                }
+            }
+
+            var seen = 0;
+            for (var x in [1, 2, 3, 4]) {
+                var y = x;
+                fun capture() { return y; }
+                if (x == 3) break;
+                seen = seen + capture();
+            }
+
+            var after = outer + inner + seen;
+        "#;
+
+        let vm = run(source).unwrap();
+
+        assert_eq!(global_number(&vm, "outer"), 3.0);
+        assert_eq!(global_number(&vm, "inner"), 6.0);
+        assert_eq!(global_number(&vm, "seen"), 3.0);
+        assert_eq!(global_number(&vm, "after"), 12.0);
+    }
+
+    #[test]
+    fn test_continue() {
+        let source = r#"
+            var odd_sum = 0;
+            var even = false;
+            for (var i = 0; i < 10; i = i + 1) {
+                var skip = !even;
+                even = skip;
+                if (skip) continue;
+                odd_sum = odd_sum + i;
+            }
+
+            var n = 0;
+            var odd_count = 0;
+            while (n < 10) {
+                n = n + 1;
+                var skip = n == 2 or n == 4 or n == 6 or n == 8 or n == 10;
+                if (skip) continue;
+                odd_count = odd_count + 1;
+            }
+
+            var items = 0;
+            for (var x in [1, 2, 3, 4]) {
+                if (x > 2) continue;
+                items = items + x;
+            }
+        "#;
+
+        let vm = run(source).unwrap();
+
+        assert_eq!(global_number(&vm, "odd_sum"), 25.0);
+        assert_eq!(global_number(&vm, "odd_count"), 5.0);
+        assert_eq!(global_number(&vm, "items"), 3.0);
+
+        // A changed per-iteration binding is written back before continuing
+        let source = r#"
+            var count = 0;
+            for (var i = 0; i < 10; i = i + 1) {
+                count = count + 1;
+                i = i + 1;
+                if (true) continue;
+            }
+        "#;
+
+        let mut compiler = Compiler::new(source.chars(), Heap::default());
+        compiler.set_fresh_loop_bindings(true);
+        compiler.compile().unwrap();
+
+        let mut vm = Vm::new(compiler.heap);
+        vm.interpret(Box::from(compiler.function)).unwrap();
+
+        assert_eq!(global_number(&vm, "count"), 5.0);
+    }
+
+    #[test]
+    fn test_for_loop_closure_capture() {
+        let source = r#"
+            var fns = {};
+            for (var i = 0; i < 3; i = i + 1) {
+                fun f() { return i; }
+                fns[i] = f;
+            }
+            print fns[0]();
+            print fns[1]();
+            print fns[2]();
+        "#;
+
+        let (result, output) = run_with_output(source);
+        result.unwrap();
+        assert_eq!(output, "3\n3\n3\n");
+
+        let mut compiler = Compiler::new(source.chars(), Heap::default());
+        compiler.set_fresh_loop_bindings(true);
+        compiler.compile().unwrap();
+
+        let output = SharedBuf::default();
+        let mut vm = Vm::with_writer(compiler.heap, Box::new(output.clone()));
+        vm.interpret(Box::from(compiler.function)).unwrap();
+
+        assert_eq!(output.contents(), "0\n1\n2\n");
+    }
+
+    #[test]
+    fn test_fresh_loop_binding_writes_back() {
+        let source = r#"
+            var count = 0;
+            for (var i = 0; i < 10; i = i + 1) {
+                i = i + 1;
+                count = count + 1;
+            }
+        "#;
+
+        let mut compiler = Compiler::new(source.chars(), Heap::default());
+        compiler.set_fresh_loop_bindings(true);
+        compiler.compile().unwrap();
+
+        let mut vm = Vm::new(compiler.heap);
+        vm.interpret(Box::from(compiler.function)).unwrap();
+
+        assert_eq!(global_number(&vm, "count"), 5.0);
+    }
+
+    #[test]
+    fn test_foreach_iterator_protocol() {
+        let (result, output) = run_with_output(
+            r#"
+            class RangeIterator {
+                init(start, end) {
+                    this.current = start;
+                    this.end = end;
+                }
+
+                done() {
+                    return this.current >= this.end;
+                }
+
+                next() {
+                    var value = this.current;
+                    this.current = this.current + 1;
+                    return value;
+                }
+            }
+
+            class Range {
+                init(start, end) {
+                    this.start = start;
+                    this.end = end;
+                }
+
+                iter() {
+                    return RangeIterator(this.start, this.end);
+                }
+            }
+
+            var closures = [];
+
+            for (x in Range(1, 4)) {
+                print x;
+                fun capture() { return x; }
+                push(closures, capture);
+            }
+
+            for (var x in Range(5, 5)) print "empty";
+
+            for (var c in closures) print c();
+        "#,
+        );
+
+        result.unwrap();
+        assert_eq!(output, "1\n2\n3\n1\n2\n3\n");
+    }
+
+    #[test]
+    fn test_foreach_builtins() {
+        let (result, output) = run_with_output(
+            r#"
+            var list = [1, "two", nil];
+            for (var item in list) print item;
+            for (c in "hé!") print c;
+
+            var total = 0;
+            for (var i = 1; i < 3; i = i + 1) total = total + i;
+            var j;
+            for (j = 10; j < 12; j = j + 1) total = total + j;
+            print total;
+        "#,
+        );
+
+        result.unwrap();
+        assert_eq!(output, "1\ntwo\nnil\nh\né\n!\n24\n");
+    }
+
+    #[test]
+    fn test_foreach_protocol_errors() {
+        let missing = |source: &str, method: &str| {
+            assert!(matches!(
+                run(source),
+                Err(LoxError::UndefinedMethod(name)) if name == method
+            ));
+        };
+
+        missing("class A {} for (x in A()) {}", "iter");
+        missing(
+            "class I {} class A { iter() { return I(); } } for (x in A()) {}",
+            "done",
+        );
+        missing(
+            "class I { done() { return false; } } class A { iter() { return I(); } } for (x in A()) {}",
+            "next",
+        );
+
+        assert!(matches!(run("for (x in 1) {}"), Err(LoxError::NotIterable)));
+        assert!(matches!(
+            run("for (x in {}) {}"),
+            Err(LoxError::NotIterable)
+        ));
+    }
+
+    #[test]
+    fn test_empty_programs() {
+        for source in [
+            "",
+            "  \n\t\r\n",
+            "// nothing to see here",
+            "\n  // a comment\n\n",
+        ] {
+            let (result, output) = run_with_output(source);
+
+            assert!(result.is_ok(), "{:?} failed to run", source);
+            assert_eq!(output, "");
+        }
+    }
+
+    #[test]
+    fn test_call_frame_limit() {
+        let source = r#"
+            fun forever(n) {
+                return forever(n + 1);
+            }
+
+            forever(0);
+        "#;
+
+        assert!(matches!(
+            run(source),
+            Err(LoxError::StackOverflow(depth)) if depth == FRAMES_MAX
+        ));
+
+        // Up to the limit is fine, counting the script's own frame
+        let source = format!(
+            "fun down(n) {{ if (n > 0) down(n - 1); }} down({});",
+            FRAMES_MAX - 2
+        );
+
+        assert!(run(&source).is_ok());
+    }
+
+    #[test]
+    fn test_stress_gc() {
+        let source = r#"
+            var s = "a";
+            for (var i = 0; i < 200; i = i + 1) {
+                s = s + "b";
+            }
+        "#;
+
+        let garbage_after = |stress_gc: bool| {
+            let mut compiler = Compiler::new(source.chars(), Heap::default());
+            compiler.compile().unwrap();
+
+            let mut vm = Vm::new(compiler.heap);
+            vm.set_stress_gc(stress_gc);
+            vm.interpret(Box::from(compiler.function)).unwrap();
+
+            let allocated = vm.bytes_allocated;
+            let objects = vm.heap.objects.len();
+
+            vm.collect_garbage().unwrap();
+
+            // Collecting frees exactly what it stops counting
+            let freed = objects - vm.heap.objects.len();
+            assert_eq!(allocated - vm.bytes_allocated, freed * lox_obj_size());
+
+            freed
+        };
+
+        // The concatenations are only collected along the way under stress
+        assert!(garbage_after(false) >= 100);
+        assert!(garbage_after(true) < 10);
+    }
+
+    #[test]
+    fn test_gc_counts_compiled_objects() {
+        let source = r#"
+            var a = "one";
+            var b = "two";
+            fun f() { return "three"; }
+            a = nil;
+            b = nil;
+            f = nil;
+        "#;
+
+        let mut compiler = Compiler::new(source.chars(), Heap::default());
+        compiler.compile().unwrap();
+
+        let mut vm = Vm::new(compiler.heap);
+        vm.interpret(Box::from(compiler.function)).unwrap();
+        vm.collect_garbage().unwrap();
+
+        assert_eq!(vm.bytes_allocated, vm.heap.objects.len() * lox_obj_size());
+    }
+
+    #[test]
+    fn test_gc_config() {
+        let source = r#"
+            var s = "a";
+            for (var i = 0; i < 1000; i = i + 1) {
+                s = s + "b";
+            }
+        "#;
+
+        let objects_after = |config: GcConfig| {
+            let mut compiler = Compiler::new(source.chars(), Heap::default());
+            compiler.compile().unwrap();
+
+            let mut vm = Vm::with_gc_config(compiler.heap, config);
+            vm.interpret(Box::from(compiler.function)).unwrap();
+
+            vm.heap.objects.len()
+        };
+
+        // Nowhere near the default threshold, so nothing is collected
+        assert!(objects_after(GcConfig::default()) > 1000);
+
+        let tiny = GcConfig {
+            initial_threshold: 8 * lox_obj_size(),
+            grow_factor: 2,
+        };
+
+        assert!(objects_after(tiny) < 100);
+    }
+
+    #[test]
+    fn test_argument_count() {
+        let source = r#"
+            fun add(a, b) { return a + b; }
+
+            class Point {
+                init(x, y) {}
+                scale(factor) {}
+            }
+        "#;
+
+        for (call, expected, got) in [
+            ("add();", 2, 0),
+            ("add(1, 2, 3);", 2, 3),
+            ("Point(1);", 2, 1),
+            ("Point(1, 2, 3);", 2, 3),
+            ("Point(1, 2).scale();", 1, 0),
+            ("var scale = Point(1, 2).scale; scale(1, 2);", 1, 2),
+        ] {
+            let result = run(&format!("{}{}", source, call));
+
+            assert!(
+                matches!(result, Err(LoxError::WrongArgumentCount(e, g)) if e == expected && g == got),
+                "{}",
+                call
+            );
+        }
+
+        assert!(run(&format!("{}add(1, 2); Point(1, 2).scale(3);", source)).is_ok());
+    }
+
+    #[test]
+    fn test_runtime_error_line() {
+        let error_line = |source: &str| {
+            let mut compiler = Compiler::new(source.chars(), Heap::default());
+            compiler.compile().unwrap();
+
+            let mut vm = Vm::with_writer(compiler.heap, Box::new(io::sink()));
+            assert!(vm.interpret(Box::from(compiler.function)).is_err());
+
+            vm.error_line()
+        };
+
+        // The operator is on line 3, its operands on lines 2 and 4
+        assert_eq!(error_line("var a = 1;\nprint a\n  +\n  nil;"), Some(3));
+        assert_eq!(error_line("var a = 1;\nprint a\n  <\n  nil;"), Some(3));
+        assert_eq!(error_line("print\n  -\n  \"one\";"), Some(2));
+
+        // An error in a callee reports the callee's line
+        assert_eq!(
+            error_line("fun f() {\n  return nil * 2;\n}\n\nf();"),
+            Some(2)
+        );
+        assert_eq!(
+            error_line("print\n  1 +\n  2;\nprint substring(\"a\", 5, 6);"),
+            Some(4)
+        );
+
+        // Reading an undefined global reports the line of the read
+        assert_eq!(error_line("var a = 1;\n\nprint a + b;"), Some(3));
+        assert_eq!(error_line("fun f() {\n  return\n    g;\n}\nf();"), Some(3));
+    }
+
+    #[test]
+    fn test_report_errors() {
+        let source = "fun inner(x) {\n  return x + nil;\n}\n\nfun outer() {\n  return inner(1);\n}\n\nprint \"before\";\nouter();";
+
+        let mut compiler = Compiler::new(source.chars(), Heap::default());
+        compiler.compile().unwrap();
+
+        let output = SharedBuf::default();
+        let errors = SharedBuf::default();
+        let mut vm = Vm::with_writer(compiler.heap, Box::new(output.clone()));
+        vm.set_report_errors(true);
+        vm.set_error_writer(Box::new(errors.clone()));
+
+        assert!(vm.interpret(Box::from(compiler.function)).is_err());
+
+        assert_eq!(output.contents(), "before\n");
+        assert_eq!(
+            errors.contents(),
+            "Runtime error: InvalidTypeForAddition\n\
+             [line 2] in inner()\n\
+             [line 6] in outer()\n\
+             [line 10] in script\n"
+        );
+    }
+
+    #[test]
+    fn test_private_methods() {
+        let class = r#"
+            class Account {
+                init(balance) {
+                    this.balance = balance;
+                }
+
+                private audit() {
+                    return "audited " + this.describe();
+                }
+
+                private describe() {
+                    return "account";
+                }
+
+                report() {
+                    return this.audit();
+                }
+
+                auditor() {
+                    return this.audit;
+                }
+            }
+
+            class Savings < Account {
+                check() {
+                    return this.audit();
+                }
+            }
+
+            var account = Account(10);
+        "#;
+
+        // Inherited public methods still reach the private ones they call
+        let (result, output) = run_with_output(&format!(
+            "{} print account.report(); print account.auditor()(); print Savings(1).report();",
+            class
+        ));
+
+        result.unwrap();
+        assert_eq!(
+            output,
+            "audited account\naudited account\naudited account\n"
+        );
+
+        // Private methods aren't accessible to subclasses' methods
+        for source in &[
+            "account.audit();",
+            "var f = account.audit;",
+            "Savings(1).audit();",
+            "Savings(1).check();",
+        ] {
+            assert!(matches!(
+                run(&format!("{} {}", class, source)),
+                Err(LoxError::PrivateMethod(name)) if name == "audit"
+            ));
+        }
+    }
+
+    #[test]
+    fn test_private_access_through_hierarchy() {
+        let classes = r#"
+            class Base {
+                private secret() { return "base"; }
+                reveal() { return this.secret(); }
+                peek() { return this.hidden(); }
+            }
+
+            class Derived < Base {
+                private hidden() { return "derived"; }
+                show() { return this.hidden(); }
+                viaSuper() { return super.secret(); }
+            }
+        "#;
+
+        let (result, output) = run_with_output(&format!(
+            "{} print Derived().reveal(); print Derived().show();",
+            classes
+        ));
+
+        result.unwrap();
+        assert_eq!(output, "base\nderived\n");
+
+        // Neither a superclass's methods nor a subclass's may reach the other's
+        // private methods
+        for (source, method) in &[
+            ("Derived().peek();", "hidden"),
+            ("Derived().viaSuper();", "secret"),
+        ] {
+            assert!(matches!(
+                run(&format!("{} {}", classes, source)),
+                Err(LoxError::PrivateMethod(name)) if name == *method
+            ));
+        }
+    }
+
+    #[test]
+    fn test_undefined_global() {
+        let undefined = |source: &str| match run(source) {
+            Err(LoxError::UndefinedVariable(name)) => name,
+            _ => panic!("expected an undefined variable error"),
+        };
+
+        assert_eq!(undefined("print x;"), "x");
+        assert_eq!(undefined("x = 5;"), "x");
+        assert_eq!(undefined("fun f() { y = 1; } var y; f(); z = y;"), "z");
+        assert_eq!(undefined("fun f() { return w; } f(); var w = 1;"), "w");
+    }
+
+    #[test]
+    fn test_opcode_decode() {
+        // `decode` transmutes, so every byte up to `LAST` must be an opcode
+        for byte in 0..=OpCode::LAST {
+            assert_eq!(OpCode::decode(byte) as u8, byte);
+            assert_eq!(OpCode::decode(byte), OpCode::try_from(byte).unwrap());
+        }
+
+        assert!(OpCode::try_from(OpCode::LAST + 1).is_err());
+
+        let mut function = ObjFunction {
+            arity: 0,
+            chunk: Chunk::default(),
+            name: None,
+            upvalue_count: 0,
+            is_generator: false,
+            is_marked: false,
+        };
+        function.chunk.write(OpCode::Nil as u8, 1);
+        function.chunk.write(0xFF, 1);
+
+        let mut vm = Vm::new(Heap::default());
+
+        assert!(matches!(
+            vm.interpret(Box::from(function)),
+            Err(LoxError::InternalVmError("unknown opcode"))
+        ));
+
+        let (result, output) = run_with_output(
+            r#"
+            fun fib(n) {
+                if (n < 2) return n;
+                return fib(n - 1) + fib(n - 2);
+            }
+
+            print fib(15);
+        "#,
+        );
+
+        result.unwrap();
+        assert_eq!(output, "610\n");
+    }
+
+    #[test]
+    fn test_closure_captures_this() {
+        // Lox has no function expressions, so the closure is a named local
+        let source = r#"
+            class Counter {
+                init() { this.n = 0; }
+
+                make() {
+                    fun inc() {
+                        this.n = this.n + 1;
+                        return this.n;
+                    }
+
+                    return inc;
+                }
+            }
+
+            var inc = Counter().make();
+            var first = inc();
+            var second = inc();
+        "#;
+
+        let vm = run(source).unwrap();
+
+        assert_eq!(global_number(&vm, "first"), 1.0);
+        assert_eq!(global_number(&vm, "second"), 2.0);
+    }
+
+    #[test]
+    fn test_script_result() {
+        let run_config = |source: &str| {
+            let mut compiler = Compiler::new(source.chars(), Heap::default());
+            compiler.set_top_level_return(true);
+            compiler.compile().unwrap();
+
+            let mut vm = Vm::new(compiler.heap);
+            vm.interpret(Box::from(compiler.function)).unwrap();
+            vm
+        };
+
+        let vm = run_config("var width = 80; return width * 2;");
+        assert!(matches!(vm.result(), Value::Number(n) if n == 160.0));
+
+        let source = r#"
+            class Config {
+                init(name) { this.name = name; }
+            }
+
+            if (true) return Config("release");
+            print "unreachable";
+        "#;
+
+        let vm = run_config(source);
+
+        match vm.result() {
+            Value::Obj(handle) => match vm.get_obj(handle).unwrap() {
+                LoxObj::Instance(instance) => {
+                    let name = instance.fields["name"];
+                    assert_eq!(vm.get_str(name), Some("release"));
+                }
+                obj => panic!("expected an instance, got {:?}", obj),
+            },
+            value => panic!("expected an instance, got {:?}", value),
+        }
+
+        let vm = run_config("var x = 1;");
+        assert!(matches!(vm.result(), Value::Nil));
+    }
+
+    #[test]
+    fn test_assert_statement() {
+        let source = "var x = 4;\nassert x > 0 and x < 10;\nassert   x ==  5 ;";
+
+        let mut compiler = Compiler::new(source.chars(), Heap::default());
+        compiler.compile().unwrap();
+
+        let mut vm = Vm::new(compiler.heap);
+
+        match vm.interpret(Box::from(compiler.function)) {
+            Err(LoxError::AssertionFailed(message)) => {
+                assert_eq!(message, "assertion failed: x ==  5");
+            }
+            result => panic!("expected a failed assertion, got {:?}", result),
+        }
+
+        assert_eq!(vm.error_line(), Some(3));
+    }
+
+    #[test]
+    fn test_max_string_len() {
+        let source = r#"
+            var s = "ab";
+            var doublings = 0;
+
+            while (true) {
+                s = s + s;
+                doublings = doublings + 1;
+            }
+        "#;
+
+        let mut compiler = Compiler::new(source.chars(), Heap::default());
+        compiler.compile().unwrap();
+
+        let mut vm = Vm::new(compiler.heap);
+        vm.set_max_string_len(1000);
+
+        let result = vm.interpret(Box::from(compiler.function));
+
+        assert!(matches!(result, Err(LoxError::StringTooLong(1024))));
+        assert_eq!(global_number(&vm, "doublings"), 8.0);
+
+        let mut compiler = Compiler::new("var s = \"ab\" + \"cd\";".chars(), Heap::default());
+        compiler.compile().unwrap();
+
+        let mut vm = Vm::new(compiler.heap);
+        vm.set_max_string_len(4);
+        vm.interpret(Box::from(compiler.function)).unwrap();
+
+        assert_eq!(global_string(&vm, "s"), "abcd");
+    }
+
+    #[test]
+    fn test_global_redefinition() {
+        let run_strict = |source: &str, strict: bool| {
+            let mut compiler = Compiler::new(source.chars(), Heap::default());
+            compiler.compile().unwrap();
+
+            let mut vm = Vm::new(compiler.heap);
+            vm.set_error_on_global_redefinition(strict);
+
+            let result = vm.interpret(Box::from(compiler.function));
+            (result, vm)
+        };
+
+        let (result, vm) = run_strict("var x = 1; var x = 2;", false);
+        result.unwrap();
+        assert_eq!(global_number(&vm, "x"), 2.0);
+
+        for source in &[
+            "var x = 1; var x = 2;",
+            "var x = 1; fun x() {}",
+            "class x {} var x;",
+        ] {
+            match run_strict(source, true).0 {
+                Err(LoxError::GlobalAlreadyDefined(name)) => assert_eq!(name, "x"),
+                result => panic!(
+                    "{}: expected a redefinition error, got {:?}",
+                    source, result
+                ),
+            }
+        }
+
+        // Assignment isn't definition, and locals are unaffected
+        let source = "var x = 1; var y = 2; x = 3; fun f() { var x = 4; } f();";
+        let (result, vm) = run_strict(source, true);
+        result.unwrap();
+        assert_eq!(global_number(&vm, "x"), 3.0);
+        assert_eq!(global_number(&vm, "y"), 2.0);
+    }
+
+    #[test]
+    fn test_print_large_list() {
+        // Nothing is allocated in the loop, which keeps the stress GC quick
+        let source = format!(
+            r#"
+            var item = "{}";
+            var items = [{{"k": [item]}}];
+
+            for (var i = 0; i < 1000; i = i + 1) push(items, item);
+
+            print items;
+        "#,
+            "x".repeat(100)
+        );
+
+        let mut compiler = Compiler::new(source.chars(), Heap::default());
+        compiler.compile().unwrap();
+
+        let output = CountingWriter::default();
+        let mut vm = Vm::with_writer(compiler.heap, Box::new(output.clone()));
+        vm.interpret(Box::from(compiler.function)).unwrap();
+
+        let item = format!("\"{}\"", "x".repeat(100));
+        let expected =
+            format!("[{{\"k\": [{}]}}", item) + &format!(", {}", item).repeat(1000) + "]\n";
+
+        let (total, largest) = *output.0.borrow();
+
+        assert_eq!(total, expected.len());
+        // Only ever as much as the VM's output buffer holds at once
+        assert!(largest <= 8192, "largest write was {} bytes", largest);
+    }
+
+    #[test]
+    fn test_print_cyclic_collections() {
+        let source = r#"
+            var list = [1];
+            push(list, list);
+            print list;
+
+            var map = {};
+            map["self"] = map;
+            print [map];
+        "#;
+
+        let (result, output) = run_with_output(source);
+
+        result.unwrap();
+        assert_eq!(output, "[1, [...]]\n[{\"self\": {...}}]\n");
+    }
+
+    #[test]
+    fn test_arena_heap() {
+        let source = r#"
+            class Cell {
+                init(n) { this.n = n; }
+            }
+
+            var kept = [];
+            var countdown = 0;
+
+            for (var i = 0; i < 3000; i = i + 1) {
+                var cell = Cell(i);
+                var label = "cell";
+
+                if (countdown == 0) {
+                    push(kept, cell);
+                    countdown = 100;
+                }
+
+                countdown = countdown - 1;
+            }
+
+            var sum = 0;
+            for (cell in kept) sum = sum + cell.n;
+        "#;
+
+        let mut compiler = Compiler::new(source.chars(), Heap::with_arena());
+        compiler.compile().unwrap();
+
+        let mut vm = Vm::new(compiler.heap);
+        vm.interpret(Box::from(compiler.function)).unwrap();
+
+        // Every 100th cell survived however many collections ran
+        assert_eq!(
+            global_number(&vm, "sum"),
+            (0..30).map(|i| i as f64 * 100.0).sum()
+        );
+    }
+
+    #[test]
+    fn test_open_and_closed_upvalues() {
+        let source = r#"
+            var get;
+            var set;
+
+            fun make() {
+                var x = "initial";
+                fun getX() { return x; }
+                fun setX(value) { x = value; }
+                get = getX;
+                set = setX;
+
+                // Open: the closures and the frame share x's stack slot
+                setX("set while open");
+                print x;
+                x = "assigned while open";
+                print getX();
+            }
+
+            make();
+
+            // Closed: the value moved into the upvalue
+            print get();
+            set("set while closed");
+            print get();
+
+            // Reusing make's stack slots mustn't reach the closed upvalue
+            fun clobber() {
+                var a = "clobbered";
+                var b = "clobbered";
+                var c = "clobbered";
+                return get();
+            }
+
+            print clobber();
+        "#;
+
+        let (result, output) = run_with_output(source);
+
+        assert!(result.is_ok());
+        assert_eq!(
+            output,
+            "set while open\nassigned while open\nassigned while open\nset while closed\nset while closed\n"
+        );
+    }
+
+    #[test]
+    fn test_upvalue_closing() {
+        let source = r#"
+            var getters = [];
+            var setters = [];
+
+            for (var i = 0; i < 3; i = i + 1) {
+                var doubled = i * 2;
+                fun get() { return doubled; }
+                fun set(value) { doubled = value; }
+                push(getters, get);
+                push(setters, set);
+            }
+
+            setters[1](10);
+            for (var get in getters) print get();
+
+            fun counter(start) {
+                var count = start;
+                var unused = "closed with count";
+                fun increment() {
+                    count = count + 1;
+                    return count;
+                }
+                return increment;
+            }
+
+            var a = counter(0);
+            var b = counter(100);
+            a();
+            print a();
+            print b();
+
+            fun outer() {
+                var x = "before";
+                fun inner() { return x; }
+                {
+                    var y = "scoped";
+                    fun inner_y() { return y + " " + x; }
+                    x = "after";
+                    return [inner, inner_y];
+                }
+            }
+
+            var pair = outer();
+            print pair[0]();
+            print pair[1]();
+        "#;
+
+        let mut compiler = Compiler::new(source.chars(), Heap::default());
+        compiler.compile().unwrap();
+
+        let buf = SharedBuf::default();
+        let mut vm = Vm::with_writer(compiler.heap, Box::new(buf.clone()));
+        vm.interpret(Box::from(compiler.function)).unwrap();
+
+        assert_eq!(buf.contents(), "0\n10\n4\n2\n101\nafter\nscoped after\n");
+        assert!(vm.open_upvalues.is_empty());
+    }
+
+    #[test]
+    fn test_eval_isolated() {
+        let mut vm = run("var greeting = \"hello\"; var count = 1;").unwrap();
+
+        // Reads host globals (and natives)
+        let value = vm
+            .eval_isolated("return greeting + \" world \" + substring(\"abc\", 0, len(\"ab\"));")
+            .unwrap();
+        assert_eq!(vm.get_str(value).unwrap(), "hello world ab");
+
+        // Definitions and assignments stay inside the snippet
+        let value = vm
+            .eval_isolated("var secret = 42; count = count + 1; return count;")
+            .unwrap();
+        assert!(matches!(value, Value::Number(n) if n == 2.0));
+        assert!(!vm.globals.contains_key("secret"));
+        assert_eq!(global_number(&vm, "count"), 1.0);
+
+        // A later snippet doesn't see the earlier one's globals
+        assert!(matches!(
+            vm.eval_isolated("return secret;"),
+            Err(LoxError::UndefinedVariable(name)) if name == "secret"
+        ));
+
+        // No return gives nil, and a failed snippet leaves the host intact
+        assert!(matches!(vm.eval_isolated("var a = 1;"), Ok(Value::Nil)));
+        assert!(vm.eval_isolated("return;\n").is_ok());
+        assert!(vm.eval_isolated("fun f() { return -nil; } f();").is_err());
+        assert!(matches!(vm.eval_isolated("return count;"), Ok(Value::Number(n)) if n == 1.0));
+        assert_eq!(global_string(&vm, "greeting"), "hello");
+    }
+
+    #[test]
+    fn test_test_harness() {
+        let source = r#"
+            fun passing() {
+                assert_eq(1 + 1, 2);
+                assert_eq("ab", "a" + "b");
+                assert_true(contains([1, 2], 2));
+            }
+
+            fun failing() {
+                assert_eq("one", "two");
+                print "unreachable";
+            }
+
+            test("passing", passing);
+            test("failing", failing);
+            print "after tests";
+        "#;
+
+        let mut compiler = Compiler::new(source.chars(), Heap::default());
+        compiler.compile().unwrap();
+
+        let buf = SharedBuf::default();
+        let mut vm = Vm::with_writer(compiler.heap, Box::new(buf.clone()));
+        vm.install_test_harness();
+
+        assert!(matches!(
+            vm.interpret(Box::from(compiler.function)),
+            Err(LoxError::TestsFailed(1))
+        ));
+        assert_eq!(
+            buf.contents(),
+            "test passing ... ok\n\
+             test failing ... FAILED: assert_eq failed: one != two\n\
+             after tests\n\
+             1 passed, 1 failed\n"
+        );
+    }
+
+    #[test]
+    fn test_no_test_summary_without_harness() {
+        let buf = SharedBuf::default();
+        let mut vm = Vm::with_writer(Heap::default(), Box::new(buf.clone()));
+
+        vm.eval("print 1;").unwrap();
+        vm.eval("print 2;").unwrap();
+
+        assert_eq!(buf.contents(), "1\n2\n");
+    }
+
+    #[test]
+    fn test_method_lookup_through_superclasses() {
+        let (result, output) = run_with_output(
+            r#"
+            class A {
+                root() { return "A.root"; }
+                name() { return "A"; }
+                describe() { return this.name() + " via " + this.root(); }
+            }
+
+            class B < A {}
+
+            class C < B {
+                name() { return "C"; }
+            }
+
+            class D < C {
+                name() { return "D<" + super.name() + ">"; }
+            }
+
+            var d = D();
+            print d.root();
+            print d.describe();
+            print C().describe();
+            print B().describe();
+
+            var root = d.root;
+            print root();
+        "#,
+        );
+
+        result.unwrap();
+        assert_eq!(
+            output,
+            "A.root\nD<C> via A.root\nC via A.root\nA via A.root\nA.root\n"
+        );
+
+        let vm = run("class A { f() {} g() {} } class B < A {} class C < B { h() {} } C().f();")
+            .unwrap();
+
+        let class = |name| match vm.globals.get(name) {
+            Some(Value::Obj(handle)) => match vm.get_obj(*handle).unwrap() {
+                LoxObj::Class(class) => class,
+                obj => panic!("expected a class, got {:?}", obj),
+            },
+            value => panic!("expected a class, got {:?}", value),
+        };
+
+        // Subclasses don't copy inherited methods, but remember lookups
+        assert_eq!(class("B").methods.len(), 0);
+        assert_eq!(class("C").methods.len(), 1);
+        assert!(class("C").method_cache.contains_key("f"));
+    }
+}
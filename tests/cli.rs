@@ -0,0 +1,100 @@
+use std::env;
+use std::fs;
+use std::process::Command;
+
+fn rslox(args: &[&str]) -> std::process::Output {
+    Command::new(env!("CARGO_BIN_EXE_rslox"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_eval() {
+    let output = rslox(&["--eval", "print 1 + 2;"]);
+
+    assert_eq!(output.status.code(), Some(0));
+
+    // Debug builds trace the compiler and VM to stdout ahead of the output
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.lines().last(), Some("3"));
+
+    if !cfg!(debug_assertions) {
+        assert_eq!(stdout, "3\n");
+    }
+}
+
+#[test]
+fn test_eval_error() {
+    let output = rslox(&["--eval", "print nil + 2;"]);
+
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "Runtime error: InvalidTypeForAddition\n[line 1] in script\n"
+    );
+
+    // The operand's object is freed along with the VM before it's reported
+    let output = rslox(&["--eval", "print 1 - \"a\";"]);
+
+    assert_eq!(output.status.code(), Some(70));
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .starts_with("Runtime error: UnexpectedValue(\"a\")"));
+
+    let output = rslox(&["--eval", "fun f() {\n  return nil + 2;\n}\nf();"]);
+
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "Runtime error: InvalidTypeForAddition\n[line 2] in f()\n[line 4] in script\n"
+    );
+
+    // Compile errors have no line to report them with
+    let output = rslox(&["--eval", "print;"]);
+
+    assert_eq!(output.status.code(), Some(70));
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("error: "));
+}
+
+#[test]
+fn test_eval_with_file() {
+    let output = rslox(&["--eval", "print 1;", "test.lox"]);
+
+    assert_eq!(output.status.code(), Some(64));
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("can't be combined with a file"));
+
+    let output = rslox(&["--eval"]);
+
+    assert_eq!(output.status.code(), Some(64));
+}
+
+#[test]
+fn test_check() {
+    let source = "var limit = 10;\nvar i = 0;\nwhile (i < limit) i = i + 1;\nprint 1 < 2 < 3;\n";
+
+    let path = env::temp_dir().join(format!("rslox-check-{}.lox", std::process::id()));
+    fs::write(&path, source).unwrap();
+
+    let output = rslox(&["--check", path.to_str().unwrap()]);
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(output.status.code(), Some(65));
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let diagnostics: Vec<_> = stderr.lines().collect();
+
+    assert_eq!(diagnostics.len(), 2, "{}", stderr);
+    assert!(diagnostics[0].starts_with("3:12: warning: global `limit`"));
+    assert!(diagnostics[1].starts_with("4:11: error: CompileError(\"chained comparison"));
+
+    // Nothing runs, so a clean program exits 0 without output
+    let output = rslox(&["--check", "--eval", "print 1;"]);
+
+    assert_eq!(output.status.code(), Some(0));
+    assert!(output.stderr.is_empty());
+
+    if !cfg!(debug_assertions) {
+        assert!(output.stdout.is_empty());
+    }
+}
@@ -0,0 +1,41 @@
+use rslox::vm::Vm;
+use rslox::{interpret_with, LoxError, Value};
+
+fn host_add(_vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    match (args[0], args[1]) {
+        (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a + b)),
+        _ => Err(LoxError::InvalidArguments("host_add expects numbers")),
+    }
+}
+
+fn host_greet(vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+    let name = vm.get_str(args[0]).unwrap_or("stranger").to_owned();
+
+    vm.new_string(format!("hello, {}", name))
+}
+
+fn run(source: &str) -> Result<(), LoxError> {
+    interpret_with(source.to_owned(), |vm| {
+        vm.define_native("host_add", 2, host_add);
+        vm.define_native("host_greet", 1, host_greet);
+    })
+}
+
+#[test]
+fn test_host_natives() {
+    let source = r#"
+        if (host_add(1, 2) != 3) panic("bad sum");
+        if (host_greet("lox") != "hello, lox") panic("bad greeting");
+    "#;
+
+    run(source).unwrap();
+
+    assert!(matches!(
+        run("host_add(1, nil);"),
+        Err(LoxError::InvalidArguments("host_add expects numbers"))
+    ));
+    assert!(matches!(
+        run("host_add(1);"),
+        Err(LoxError::InvalidArguments(_))
+    ));
+}